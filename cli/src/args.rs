@@ -127,6 +127,9 @@ pub fn get_args() -> Result<CliHandler> {
         .arg(Arg::with_name("no-process-group")
                  .help("Do not use a process group when running the command")
                  .long("no-process-group"))
+        .arg(Arg::with_name("stdin-quit")
+                 .help("Exit when stdin closes")
+                 .long("stdin-quit"))
         .arg(Arg::with_name("once").short("1").hidden(true))
         .arg(Arg::with_name("watch-when-idle")
                  .help("Deprecated alias for --on-busy-update=do-nothing, which will become the default in 2.0.")
@@ -269,6 +272,7 @@ pub fn get_args() -> Result<CliHandler> {
     builder.no_ignore(args.is_present("no-ignore"));
     builder.poll(args.occurrences_of("poll") > 0);
     builder.use_process_group(!args.is_present("no-process-group"));
+    builder.stdin_quit(args.is_present("stdin-quit"));
 
     let mut config = builder.build()?;
     if args.is_present("once") {
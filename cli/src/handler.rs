@@ -4,8 +4,9 @@ use notify_rust::Notification;
 use watchexec::{
     config::Config,
     error::Result,
-    pathop::PathOp,
+    events::Event,
     run::{ExecHandler, Handler},
+    watcher::Injector,
 };
 
 pub struct CliHandler {
@@ -29,13 +30,17 @@ impl Handler for CliHandler {
         self.inner.args()
     }
 
-    fn on_manual(&self) -> Result<bool> {
-        self.inner.on_manual()
+    fn on_start(&self, injector: Injector) {
+        self.inner.on_start(injector);
     }
 
-    fn on_update(&self, ops: &[PathOp]) -> Result<bool> {
-        self.inner.on_update(ops).map(|o| {
-            if self.notify {
+    fn on_stop(&self) {
+        self.inner.on_stop();
+    }
+
+    fn on_event(&self, events: &[Event]) -> Result<bool> {
+        self.inner.on_event(events).map(|o| {
+            if self.notify && events.iter().any(|e| e.pathop().is_some()) {
                 Notification::new()
                     .summary("Watchexec observed a change")
                     .body("Watchexec has seen a change, the command may have restarted.")
@@ -0,0 +1,2191 @@
+//! Action execution: process supervision in response to batches.
+//!
+//! This is the second half of [`watch`][crate::run::watch]. The
+//! [`ExecHandler`] runs the configured command according to the busy-update
+//! policy, and [`consume`] drives any [`Handler`] from a channel of batches,
+//! such as one obtained from [`Events::spawn`][crate::events::Events::spawn].
+//! [`run_once`] runs the command a single time, as the handler would.
+//!
+//! With [`OnBusyUpdate::Queue`], batches received while busy are kept as per
+//! the [`QueuePolicy`]. Under `watch`, the handler keeps handling events while
+//! they wait, and a thread checks for a free slot, then wakes the loop to
+//! start the next queued run. Driven in any other way, which doesn't provide
+//! an [`Injector`], the handler instead blocks until a slot is free.
+
+#[cfg(unix)]
+use command_group::UnixChildExt;
+use command_group::{CommandGroup, GroupChild};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::{debug, info, warn};
+
+#[cfg(unix)]
+use std::process::Command;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    env, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    iter,
+    net::TcpStream,
+    path::PathBuf,
+    process::{Child, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::Receiver,
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::audit::AuditLog;
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::events::{Event, Origin};
+use crate::pathop::{self, PathOp};
+use crate::resources;
+use crate::run::{
+    fs_changes, run_hook, tagged, Handler, OnBusyUpdate, QueuePolicy, Readiness, ResourceLimits,
+};
+use crate::signal::{self, ChildSignal, Signal, SignalGuard};
+use crate::systemd;
+use crate::watcher::Injector;
+
+/// How often to check for a free slot while runs are queued.
+const QUEUE_POLL: Duration = Duration::from_millis(50);
+
+/// How often to check whether a command has exited when restarting.
+const EXIT_POLL: Duration = Duration::from_millis(10);
+
+/// How often to try connecting to a command for its readiness.
+const READY_POLL: Duration = Duration::from_millis(100);
+
+/// How far file modification times may lag behind the clock, as filesystems
+/// often use a coarse one.
+const MTIME_SLACK: Duration = Duration::from_millis(10);
+
+/// Call a handler with every batch received, blocking until done.
+///
+/// Like [`watch`][crate::run::watch], this first sends a `Manual` event if the
+/// config says to run initially, and stops when the handler asks for it. It
+/// also stops when the sending side of the channel is gone.
+pub fn consume<H>(handler: &H, batches: Receiver<Vec<PathOp>>) -> Result<()>
+where
+    H: Handler,
+{
+    let args = handler.args();
+    if args.run_initially && !handler.on_event(&tagged(Origin::Start, Event::Manual))? {
+        return Ok(());
+    }
+
+    for paths in batches {
+        info!("Paths updated: {:?}", paths);
+        if !handler.on_event(&fs_changes(paths))? {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Run the command once for those changes, without watching, and wait for it.
+///
+/// The command is started as an [`ExecHandler`] starts each run, with the
+/// same shell, environment variables, process group, audit log, and so on,
+/// e.g. for a "build now" button. The `container` and `commands` are ignored.
+pub fn run_once(args: Config, ops: &[PathOp]) -> Result<ExitStatus> {
+    if args.cmd.is_empty() {
+        return Err(Error::Generic("cmd must not be empty".into()));
+    }
+
+    let handler = ExecHandler::new(Config {
+        container: None,
+        commands: Vec::new(),
+        ..args
+    })?;
+
+    let mut children = Vec::with_capacity(1);
+    handler.spawn(&mut children, ops)?;
+    let mut run = children
+        .pop()
+        .ok_or_else(|| Error::Generic("the command was not started".into()))?;
+
+    handler
+        .wait(&mut run)?
+        .ok_or_else(|| Error::Generic("the command has no exit status".into()))
+}
+
+#[derive(Debug)]
+pub enum ChildProcess {
+    None,
+    Grouped(GroupChild),
+    Ungrouped(Child),
+}
+
+impl Default for ChildProcess {
+    fn default() -> Self {
+        ChildProcess::None
+    }
+}
+
+impl ChildProcess {
+    #[cfg(unix)]
+    #[allow(clippy::cast_possible_wrap)]
+    fn signal(&mut self, sig: ChildSignal) -> Result<()> {
+        match (self, sig) {
+            (Self::None, _) => Ok(()),
+            (Self::Grouped(c), ChildSignal::Standard(sig)) => {
+                debug!("Sending signal {} to process group id={}", sig, c.id());
+                c.signal(sig)
+            }
+            (Self::Ungrouped(c), ChildSignal::Standard(sig)) => {
+                debug!("Sending signal {} to process id={}", sig, c.id());
+                c.signal(sig)
+            }
+            (Self::Grouped(c), ChildSignal::Number(sig)) => {
+                debug!("Sending signal {} to process group id={}", sig, c.id());
+                signal::send_raw(-(c.id() as i32), sig)
+            }
+            (Self::Ungrouped(c), ChildSignal::Number(sig)) => {
+                debug!("Sending signal {} to process id={}", sig, c.id());
+                signal::send_raw(c.id() as i32, sig)
+            }
+        }
+        .map_err(|e| e.into())
+    }
+
+    fn kill(&mut self) -> Result<()> {
+        match self {
+            Self::None => Ok(()),
+            Self::Grouped(c) => {
+                debug!("Killing process group id={}", c.id());
+                c.kill()
+            }
+            Self::Ungrouped(c) => {
+                debug!("Killing process id={}", c.id());
+                c.kill()
+            }
+        }
+        .map_err(|e| e.into())
+    }
+
+    fn id(&self) -> Option<u32> {
+        match self {
+            Self::None => None,
+            Self::Grouped(c) => Some(c.id()),
+            Self::Ungrouped(c) => Some(c.id()),
+        }
+    }
+
+    /// The exit status if the command has exited, without blocking.
+    ///
+    /// `None` is never running, and has no exit status either.
+    fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        match self {
+            Self::None => Ok(None),
+            Self::Grouped(c) => c.try_wait(),
+            Self::Ungrouped(c) => c.try_wait(),
+        }
+        .map_err(|e| e.into())
+    }
+
+    fn wait(&mut self) -> Result<Option<ExitStatus>> {
+        match self {
+            Self::None => Ok(None),
+            Self::Grouped(c) => c.wait().map(Some),
+            Self::Ungrouped(c) => c.wait().map(Some),
+        }
+        .map_err(|e| e.into())
+    }
+
+    /// The process itself, or the leader of the group.
+    fn child(&mut self) -> Option<&mut Child> {
+        match self {
+            Self::None => None,
+            Self::Grouped(c) => Some(c.inner()),
+            Self::Ungrouped(c) => Some(c),
+        }
+    }
+}
+
+/// A run of the command, as started by an [`ExecHandler`].
+#[derive(Debug)]
+struct Run {
+    number: u64,
+    process: ChildProcess,
+    state: Arc<RunState>,
+}
+
+/// What threads looking after a run know about it.
+#[derive(Debug, Default)]
+struct RunState {
+    ready: AtomicBool,
+    exited: AtomicBool,
+
+    /// to deliver the `ProcessExit` event, under `watch`
+    injector: Option<Injector>,
+}
+
+/// Batches waiting for a free slot, kept according to a [`QueuePolicy`].
+#[derive(Debug, Default)]
+struct Queue {
+    policy: QueuePolicy,
+    group_by_directory: bool,
+    batches: VecDeque<Vec<PathOp>>,
+}
+
+impl Queue {
+    fn push(&mut self, ops: Vec<PathOp>) {
+        match self.policy {
+            QueuePolicy::Unbounded => self.batches.push_back(ops),
+            QueuePolicy::Collapse => match self.batches.back_mut() {
+                Some(batch) => {
+                    batch.extend(ops);
+                    *batch = pathop::normalise_batch(batch.split_off(0), self.group_by_directory);
+                }
+                None => self.batches.push_back(ops),
+            },
+            QueuePolicy::Cap(max) => {
+                if self.batches.len() < max {
+                    self.batches.push_back(ops);
+                } else {
+                    debug!("Queue is full, dropping batch");
+                }
+            }
+            QueuePolicy::DropOldest(max) => {
+                while self.batches.len() >= max {
+                    debug!("Queue is full, dropping the oldest batch");
+                    self.batches.pop_front();
+                }
+                self.batches.push_back(ops);
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<Vec<PathOp>> {
+        self.batches.pop_front()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.batches.is_empty()
+    }
+}
+
+pub struct ExecHandler {
+    args: Config,
+    signal: Option<ChildSignal>,
+    children: Arc<Mutex<Vec<Run>>>,
+    runs: AtomicU64,
+    audit: Option<Arc<AuditLog>>,
+    on_busy_rules: GlobSet,
+    queue: Mutex<Queue>,
+    injector: Mutex<Option<Injector>>,
+    waiting: Arc<AtomicBool>,
+    last_spawn: Mutex<Option<Instant>>,
+    started_at: Mutex<Option<SystemTime>>,
+    output_paths: Vec<PathBuf>,
+    last_exit: Arc<Mutex<Option<Instant>>>,
+    exited_at: Arc<Mutex<Option<SystemTime>>>,
+    cooling: Mutex<Vec<PathOp>>,
+    cooling_wake: Arc<AtomicBool>,
+
+    /// When the changes being handled, kept for the cooldown, or queued were
+    /// received, see `note_received`.
+    received: Mutex<HashMap<PathOp, Instant>>,
+    name: Option<String>,
+    others: Vec<ExecHandler>,
+
+    /// Reaps runs on `SIGCHLD` while watching, see `on_start`.
+    reaper: Mutex<Option<SignalGuard>>,
+}
+
+impl ExecHandler {
+    pub fn new(args: Config) -> Result<Self> {
+        Self::build(args, None)
+    }
+
+    fn build(args: Config, name: Option<String>) -> Result<Self> {
+        if args.cmd.is_empty() && args.container.is_none() && args.commands.is_empty() {
+            return Err(Error::Generic("cmd must not be empty".into()));
+        }
+
+        let mut others = Vec::with_capacity(args.commands.len());
+        for (i, spec) in args.commands.iter().enumerate() {
+            if spec.cmd.is_empty() {
+                return Err(Error::Generic(format!(
+                    "command {:?} must not be empty",
+                    spec.name
+                )));
+            }
+
+            let mut config = args.clone();
+            config.cmd = spec.cmd.clone();
+            config.on_busy_update = spec.on_busy_update;
+            config.on_busy_rules = Vec::new();
+            config.container = None;
+            config.commands = Vec::new();
+            config.clear_screen = args.clear_screen && i == 0 && !Self::runs_own(&args);
+
+            others.push(Self::build(config, Some(spec.name.clone()))?);
+        }
+
+        // Convert signal string to the corresponding integer
+        let signal = signal::new(args.signal.clone())?;
+
+        let audit = match (&args.audit_log, &name) {
+            (Some(path), Some(name)) => Some(Arc::new(AuditLog::open(path)?.with_name(name))),
+            (Some(path), None) => Some(Arc::new(AuditLog::open(path)?)),
+            (None, _) => None,
+        };
+
+        let mut on_busy_rules = GlobSetBuilder::new();
+        for (glob, _) in &args.on_busy_rules {
+            on_busy_rules.add(Glob::new(glob)?);
+        }
+
+        // Changes come in canonical, or as given with `original_paths`
+        let mut output_paths = Vec::new();
+        for path in &args.output_paths {
+            let path = env::current_dir()?.join(path);
+            if let Ok(canonical) = path.canonicalize() {
+                if canonical != path {
+                    output_paths.push(canonical);
+                }
+            }
+            output_paths.push(path);
+        }
+
+        let queue = Queue {
+            policy: args.queue_policy,
+            group_by_directory: args.group_by_directory,
+            ..Queue::default()
+        };
+
+        Ok(Self {
+            args,
+            signal,
+            children: Arc::default(),
+            runs: AtomicU64::new(0),
+            audit,
+            on_busy_rules: on_busy_rules.build()?,
+            queue: Mutex::new(queue),
+            injector: Mutex::default(),
+            waiting: Arc::default(),
+            last_spawn: Mutex::default(),
+            started_at: Mutex::default(),
+            output_paths,
+            last_exit: Arc::default(),
+            exited_at: Arc::default(),
+            cooling: Mutex::default(),
+            cooling_wake: Arc::default(),
+            received: Mutex::default(),
+            name,
+            others,
+            reaper: Mutex::default(),
+        })
+    }
+
+    /// Whether the config has a command (or container) of its own, rather
+    /// than only `commands`.
+    fn runs_own(args: &Config) -> bool {
+        !args.cmd.is_empty() || args.container.is_some()
+    }
+
+    /// The handlers doing something on runs: this one if it has its own
+    /// command, and those for `commands`.
+    fn handlers(&self) -> impl Iterator<Item = &Self> {
+        let own = if Self::runs_own(&self.args) {
+            Some(self)
+        } else {
+            None
+        };
+
+        own.into_iter().chain(&self.others)
+    }
+
+    /// Call every handler in turn, all of them even if one asks to stop.
+    fn each<F>(&self, f: F) -> Result<bool>
+    where
+        F: Fn(&Self) -> Result<bool>,
+    {
+        let mut keep_going = true;
+        for handler in self.handlers() {
+            keep_going &= f(handler)?;
+        }
+
+        Ok(keep_going)
+    }
+
+    /// Start the command in a new slot.
+    ///
+    /// Callers are responsible for making room first.
+    fn spawn(&self, children: &mut Vec<Run>, ops: &[PathOp]) -> Result<()> {
+        if self.args.clear_screen {
+            clearscreen::clear()?;
+        }
+
+        if self.args.cmd.is_empty() {
+            let number = self.runs.fetch_add(1, Ordering::SeqCst) + 1;
+            systemd::status(&format!("Started run {}", number));
+            self.act_on_container();
+            return Ok(());
+        }
+
+        let mut command = self.args.shell.to_command(&self.args.cmd);
+        debug!("Assembled command: {:?}", command);
+
+        let mut env = Vec::new();
+        if !self.args.no_environment {
+            env.extend(crate::paths::collect_path_env_vars(
+                ops,
+                self.args.path_separator,
+            ));
+        }
+
+        if self.args.stdin_quit {
+            command.stdin(Stdio::null());
+        } else if self.args.paths_on_stdin {
+            command.stdin(Stdio::piped());
+        }
+
+        if let Some(Readiness::Line(_)) = self.args.readiness {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+
+        *self.last_spawn.lock().expect("poisoned lock in spawn") = Some(self.args.clock.now());
+        let started_at = SystemTime::now();
+        *self.started_at.lock().expect("poisoned lock in spawn") = Some(started_at);
+        let number = self.runs.fetch_add(1, Ordering::SeqCst) + 1;
+        env.extend(run_env_vars(number, self.triggered_at(ops, started_at)));
+        for (name, val) in &env {
+            debug!("Command environment: {}={:?}", name, val);
+            command.env(name, val);
+        }
+
+        #[cfg(unix)]
+        if self.args.exec_final && self.is_final(number) {
+            return Err(self.exec(command, number, &env));
+        }
+
+        debug!(
+            "Launching command {}",
+            self.name.as_deref().unwrap_or_default()
+        );
+        systemd::status(&format!("Started run {}", number));
+        let mut process = if self.args.use_process_group {
+            ChildProcess::Grouped(command.group_spawn()?)
+        } else {
+            ChildProcess::Ungrouped(command.spawn()?)
+        };
+
+        if let Some(ref audit) = self.audit {
+            let pid = process.id().unwrap_or_default();
+            audit.spawn(number, pid, &self.args.cmd, &env);
+        }
+
+        if let Some(mut stdin) = process.child().and_then(|c| c.stdin.take()) {
+            // In a thread, as the command may not read it all right away
+            let list = crate::paths::path_list(ops, self.args.path_separator);
+            thread::spawn(move || {
+                stdin
+                    .write_all(&list)
+                    .unwrap_or_else(|err| debug!("Could not write paths to stdin: {}", err));
+            });
+        }
+
+        let injector = self
+            .injector
+            .lock()
+            .expect("poisoned lock in spawn")
+            .clone();
+        let state = Arc::new(RunState {
+            injector,
+            ..RunState::default()
+        });
+        if let Some(limits) = self.args.resource_limits {
+            self.monitor(limits, &process, &state);
+        }
+
+        if self.args.cooldown.is_some() || !self.output_paths.is_empty() || state.injector.is_some()
+        {
+            self.note_exit(&state);
+        }
+
+        match self.args.readiness {
+            Some(ref readiness) => self.detect_readiness(readiness, &mut process, number, &state),
+            None => state.ready.store(true, Ordering::SeqCst),
+        }
+
+        let mut run = Run {
+            number,
+            process,
+            state,
+        };
+        if self.args.container.is_some() {
+            // The container is only acted upon once the command succeeded
+            if matches!(self.wait(&mut run)?, Some(status) if status.success()) {
+                self.act_on_container();
+            }
+        } else {
+            children.push(run);
+        }
+
+        Ok(())
+    }
+
+    /// Check the run's resource usage from a thread, and have the loop restart
+    /// it through an `Origin::ResourceLimit` event when it's over the limits.
+    ///
+    /// Usage is polled in real time, as that's what the command's CPU time is
+    /// measured against.
+    fn monitor(&self, limits: ResourceLimits, process: &ChildProcess, state: &Arc<RunState>) {
+        let pid = match process.id() {
+            Some(pid) => pid,
+            None => return,
+        };
+        let group = matches!(process, ChildProcess::Grouped(_));
+        let injector = self
+            .injector
+            .lock()
+            .expect("poisoned lock in monitor")
+            .clone();
+        let args = self.args.clone();
+        let state = Arc::clone(state);
+        thread::spawn(move || {
+            let mut previous = resources::usage(pid, group);
+            loop {
+                thread::sleep(args.resource_poll);
+                if state.exited.load(Ordering::SeqCst) {
+                    return;
+                }
+
+                let usage = match resources::usage(pid, group) {
+                    Some(usage) => usage,
+                    None => return,
+                };
+
+                let over =
+                    resources::exceeded(&limits, &usage, previous.as_ref(), args.resource_poll);
+                if let Some(reason) = over {
+                    warn!("Command (pid {}) is {}, restarting it", pid, reason);
+                    run_hook(&args, &args.limit_cmd, "limit")
+                        .unwrap_or_else(|err| warn!("{}", err));
+                    if let Some(injector) = injector {
+                        injector.run(Origin::ResourceLimit(pid)).ok();
+                    }
+
+                    return;
+                }
+
+                previous = Some(usage);
+            }
+        });
+    }
+
+    /// Whether that run is the last one of `max_runs`.
+    #[cfg(unix)]
+    fn is_final(&self, number: u64) -> bool {
+        self.args.max_runs == Some(number)
+    }
+
+    /// Replace this process with the command, see `Config.exec_final`.
+    ///
+    /// This only returns if that failed.
+    #[cfg(unix)]
+    fn exec(&self, mut command: Command, number: u64, env: &[(String, String)]) -> Error {
+        use std::os::unix::process::CommandExt;
+
+        debug!("Replacing watchexec with the command for run {}", number);
+        systemd::status(&format!("Started final run {}", number));
+        if let Some(ref audit) = self.audit {
+            audit.spawn(number, std::process::id(), &self.args.cmd, env);
+        }
+
+        command.exec().into()
+    }
+
+    /// Record when the run exits from a thread, for the cooldown, to tell
+    /// which changes the command made, and to deliver the `ProcessExit` event
+    /// as soon as it does.
+    fn note_exit(&self, state: &Arc<RunState>) {
+        let children = Arc::clone(&self.children);
+        let audit = self.audit.clone();
+        let last_exit = Arc::clone(&self.last_exit);
+        let exited_at = Arc::clone(&self.exited_at);
+        let clock = self.args.clock.clone();
+        let state = Arc::clone(state);
+        thread::spawn(move || loop {
+            thread::sleep(QUEUE_POLL);
+            if !state.exited.load(Ordering::SeqCst) {
+                reap(
+                    &mut children.lock().expect("poisoned lock in note_exit"),
+                    audit.as_deref(),
+                )
+                .ok();
+            }
+
+            if state.exited.load(Ordering::SeqCst) {
+                *last_exit.lock().expect("poisoned lock in note_exit") = Some(clock.now());
+                *exited_at.lock().expect("poisoned lock in note_exit") = Some(SystemTime::now());
+                return;
+            }
+        });
+    }
+
+    /// When the latest of the changes was received, by the wall clock, or
+    /// `now` if there are none, as for manual runs.
+    fn triggered_at(&self, ops: &[PathOp], now: SystemTime) -> SystemTime {
+        ops.iter()
+            .filter_map(|op| self.received(op))
+            .max()
+            .and_then(|received| now.checked_sub(self.args.clock.since(received)))
+            .unwrap_or(now)
+    }
+
+    /// Note when new changes were received, by path, or now for those
+    /// without a time, e.g. from `on_update`.
+    ///
+    /// Only the times of changes still waiting, for the cooldown or in the
+    /// queue, are kept from before.
+    fn note_received(&self, ops: &[PathOp], times: &[(PathBuf, Instant)]) {
+        let cooling = self.cooling.lock().expect("poisoned lock in note_received");
+        let queue = self.queue.lock().expect("poisoned lock in note_received");
+        let mut received = self
+            .received
+            .lock()
+            .expect("poisoned lock in note_received");
+
+        let waiting: HashSet<&PathOp> = cooling
+            .iter()
+            .chain(queue.batches.iter().flatten())
+            .chain(ops)
+            .collect();
+        received.retain(|op, _| waiting.contains(op));
+
+        let now = self.args.clock.now();
+        let times: HashMap<&PathBuf, Instant> =
+            times.iter().map(|(path, at)| (path, *at)).collect();
+        for op in ops {
+            let time = times.get(&op.path).copied().unwrap_or(now);
+            received
+                .entry(op.clone())
+                .and_modify(|at| *at = time.max(*at))
+                .or_insert(time);
+        }
+    }
+
+    /// When a change was received, if it's being handled.
+    fn received(&self, op: &PathOp) -> Option<Instant> {
+        self.received
+            .lock()
+            .expect("poisoned lock in received")
+            .get(op)
+            .copied()
+    }
+
+    /// Leave out changes received too soon after the command started, see
+    /// `Config.post_spawn_ignore`.
+    fn without_spawn_noise(&self, ops: &[PathOp]) -> Vec<PathOp> {
+        let spawned = *self
+            .last_spawn
+            .lock()
+            .expect("poisoned lock in without_spawn_noise");
+        let spawned = match spawned {
+            Some(spawned) if self.args.post_spawn_ignore > Duration::from_secs(0) => spawned,
+            _ => return ops.to_vec(),
+        };
+
+        ops.iter()
+            .filter(|op| {
+                let received = self.received(op).unwrap_or_else(|| self.args.clock.now());
+                let noise = received >= spawned && received < spawned + self.args.post_spawn_ignore;
+                if noise {
+                    debug!(
+                        "Ignoring {:?}, changed right after the command started",
+                        op.path
+                    );
+                }
+
+                !noise
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Leave out changes the command made itself, as far as can be told.
+    ///
+    /// Only changes under `output_paths` are looked at: they're the command's
+    /// if it's still `running`, or if the file was modified while the last
+    /// run was going.
+    fn without_own_changes(&self, ops: &[PathOp], running: bool) -> Vec<PathOp> {
+        if self.output_paths.is_empty() {
+            return ops.to_vec();
+        }
+
+        let started_at = *self
+            .started_at
+            .lock()
+            .expect("poisoned lock in without_own_changes");
+        let started_at = match started_at {
+            Some(started_at) => started_at,
+            None => return ops.to_vec(),
+        };
+
+        // Until the exit of the last run is seen, it may still be writing
+        let exited_at = *self
+            .exited_at
+            .lock()
+            .expect("poisoned lock in without_own_changes");
+        let during_run = |modified: SystemTime| {
+            modified + MTIME_SLACK >= started_at
+                && exited_at
+                    .filter(|exited_at| *exited_at >= started_at)
+                    .map_or(true, |exited_at| modified <= exited_at + MTIME_SLACK)
+        };
+
+        ops.iter()
+            .filter(|op| {
+                let own = self.output_paths.iter().any(|out| op.path.starts_with(out))
+                    && (running
+                        || fs::metadata(&op.path)
+                            .and_then(|meta| meta.modified())
+                            .map_or(false, during_run));
+                if own {
+                    debug!("Not triggering on {:?}, made by the command", op.path);
+                }
+
+                !own
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Keep changes seen during the cooldown, or merge the kept ones into
+    /// this batch once it's over.
+    ///
+    /// Returns `None` if the changes were kept.
+    fn after_cooldown(&self, ops: &[PathOp]) -> Option<Vec<PathOp>> {
+        let mut cooling = self
+            .cooling
+            .lock()
+            .expect("poisoned lock in after_cooldown");
+        let last_exit = *self
+            .last_exit
+            .lock()
+            .expect("poisoned lock in after_cooldown");
+        if let (Some(cooldown), Some(last_exit)) = (self.args.cooldown, last_exit) {
+            let elapsed = self.args.clock.since(last_exit);
+            if elapsed < cooldown {
+                debug!("Cooling down after the last run, keeping changes for later");
+                cooling.extend_from_slice(ops);
+                self.wake_after_cooldown(cooldown - elapsed);
+                return None;
+            }
+        }
+
+        if cooling.is_empty() {
+            return Some(ops.to_vec());
+        }
+
+        cooling.extend_from_slice(ops);
+        Some(pathop::normalise_batch(
+            std::mem::take(&mut *cooling),
+            self.args.group_by_directory,
+        ))
+    }
+
+    /// Have the loop run the changes kept during the cooldown through an
+    /// `Origin::Cooldown` event, once it's over.
+    fn wake_after_cooldown(&self, remaining: Duration) {
+        let injector = match *self
+            .injector
+            .lock()
+            .expect("poisoned lock in wake_after_cooldown")
+        {
+            Some(ref injector) => injector.clone(),
+            None => return,
+        };
+
+        if self.cooling_wake.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let clock = self.args.clock.clone();
+        let cooling_wake = Arc::clone(&self.cooling_wake);
+        thread::spawn(move || {
+            clock.sleep(remaining);
+            cooling_wake.store(false, Ordering::SeqCst);
+            injector.run(Origin::Cooldown).ok();
+        });
+    }
+
+    /// Run the changes kept during the cooldown, if it's over.
+    fn end_cooldown(&self) -> Result<bool> {
+        let kept =
+            std::mem::take(&mut *self.cooling.lock().expect("poisoned lock in end_cooldown"));
+        if kept.is_empty() {
+            return Ok(true);
+        }
+
+        self.update(&kept, &[])
+    }
+
+    /// Restart the run with that process id, if it's one of ours and still
+    /// going, as for [`OnBusyUpdate::Restart`].
+    fn restart_over_limit(&self, pid: u32) -> Result<bool> {
+        let mut children = self.children.lock().expect("poisoned lock in on_event");
+        self.reap(&mut children)?;
+
+        let index = match children
+            .iter()
+            .position(|run| run.process.id() == Some(pid))
+        {
+            Some(index) => index,
+            None => return Ok(true),
+        };
+
+        let signal = self
+            .signal
+            .unwrap_or(ChildSignal::Standard(Signal::SIGTERM));
+        let mut run = children.remove(index);
+        signal_process(&mut run.process, signal)?;
+        self.wait_or_kill(&mut run, self.args.restart_timeout)?;
+        self.spawn(&mut children, &[])?;
+        Ok(!self.reached_max_runs(&mut children)?)
+    }
+
+    /// Mark the run as ready once it is, from other threads.
+    fn detect_readiness(
+        &self,
+        readiness: &Readiness,
+        process: &mut ChildProcess,
+        number: u64,
+        state: &Arc<RunState>,
+    ) {
+        let args = self.args.clone();
+        let name = self.name.clone();
+        let state_ = Arc::clone(state);
+        let ready = Arc::new(move || {
+            if state_.ready.swap(true, Ordering::SeqCst) {
+                return;
+            }
+
+            match name {
+                Some(ref name) => info!("Run {} of {} is ready", number, name),
+                None => info!("Run {} is ready", number),
+            }
+            systemd::status(&format!("Run {} is ready", number));
+
+            let args = args.clone();
+            thread::spawn(move || {
+                run_hook(&args, &args.ready_cmd, "ready").unwrap_or_else(|err| warn!("{}", err));
+            });
+        });
+
+        match readiness {
+            Readiness::Line(marker) => {
+                if let Some(child) = process.child() {
+                    if let Some(stdout) = child.stdout.take() {
+                        let (marker, ready) = (marker.clone(), Arc::clone(&ready));
+                        thread::spawn(move || pass_through(stdout, io::stdout(), &marker, &*ready));
+                    }
+                    if let Some(stderr) = child.stderr.take() {
+                        let (marker, ready) = (marker.clone(), Arc::clone(&ready));
+                        thread::spawn(move || pass_through(stderr, io::stderr(), &marker, &*ready));
+                    }
+                }
+            }
+            Readiness::Port(addr) => {
+                let (addr, state) = (addr.clone(), Arc::clone(state));
+                thread::spawn(move || {
+                    while !state.exited.load(Ordering::SeqCst) {
+                        if TcpStream::connect(&addr).is_ok() {
+                            ready();
+                            return;
+                        }
+
+                        thread::sleep(READY_POLL);
+                    }
+                });
+            }
+        }
+    }
+
+    /// Restart or signal the configured container, if any.
+    ///
+    /// Errors are only logged, as the container may well come back.
+    fn act_on_container(&self) {
+        #[cfg(unix)]
+        if let Some(ref container) = self.args.container {
+            info!("Container {}: {:?}", container, self.args.container_action);
+            crate::docker::Docker::from_env()
+                .act(container, &self.args.container_action)
+                .unwrap_or_else(|err| warn!("Could not act on container: {}", err));
+        }
+    }
+
+    /// What to do with that batch if busy, according to the rules.
+    fn on_busy_update(&self, ops: &[PathOp]) -> OnBusyUpdate {
+        ops.iter()
+            .flat_map(|op| self.on_busy_rules.matches(&op.path))
+            .min()
+            .map_or(self.args.on_busy_update, |i| {
+                debug!("On-busy rule {:?} applies", self.args.on_busy_rules[i].0);
+                self.args.on_busy_rules[i].1
+            })
+    }
+
+    /// Wait for a run to finish.
+    fn wait(&self, run: &mut Run) -> Result<Option<ExitStatus>> {
+        let status = run.process.wait()?;
+        exited(self.audit.as_deref(), run, status);
+        Ok(status)
+    }
+
+    /// Wait for a run to finish, killing it if it takes longer than `timeout`.
+    ///
+    /// This is in real time, not by the config's clock, as the command can't
+    /// be told about a mock one.
+    fn wait_or_kill(&self, run: &mut Run, timeout: Duration) -> Result<Option<ExitStatus>> {
+        let start = Instant::now();
+        loop {
+            let status = run.process.try_wait()?;
+            if status.is_some() || run.process.id().is_none() {
+                exited(self.audit.as_deref(), run, status);
+                return Ok(status);
+            }
+
+            if start.elapsed() >= timeout {
+                warn!(
+                    "Command still running {:?} after being signalled, killing it",
+                    timeout
+                );
+                run.process.kill()?;
+                return self.wait(run);
+            }
+
+            thread::sleep(EXIT_POLL);
+        }
+    }
+
+    /// Drop the runs which have finished, returning how many are still going.
+    fn reap(&self, children: &mut Vec<Run>) -> Result<usize> {
+        reap(children, self.audit.as_deref())
+    }
+
+    /// Drop queued changes received before the latest run started.
+    fn drop_stale(&self, queue: &mut Queue) {
+        let spawned = match *self.last_spawn.lock().expect("poisoned lock in drop_stale") {
+            Some(spawned) => spawned,
+            None => return,
+        };
+
+        let mut dropped = 0;
+        for batch in &mut queue.batches {
+            let before = batch.len();
+            batch.retain(|op| {
+                self.received(op)
+                    .map_or(true, |received| received >= spawned)
+            });
+            dropped += before - batch.len();
+        }
+        queue.batches.retain(|batch| !batch.is_empty());
+
+        if dropped > 0 {
+            debug!(
+                "Dropped {} queued changes from before the latest run",
+                dropped
+            );
+        }
+    }
+
+    /// Start queued runs while there are free slots.
+    ///
+    /// If some are left, the loop is woken up once a slot is free.
+    fn start_queued(&self, children: &mut Vec<Run>) -> Result<()> {
+        let mut queue = self.queue.lock().expect("poisoned lock in start_queued");
+        while !queue.is_empty() && self.reap(children)? < self.args.max_concurrent {
+            if self.args.drop_stale {
+                self.drop_stale(&mut queue);
+            }
+
+            if let Some(ops) = queue.pop() {
+                self.spawn(children, &ops)?;
+            }
+        }
+
+        if self.args.drop_stale {
+            self.drop_stale(&mut queue);
+        }
+
+        if !queue.is_empty() {
+            self.wake_when_free();
+        }
+
+        Ok(())
+    }
+
+    /// Check for a free slot from a thread, then have the loop start the
+    /// queued runs through an `Origin::Queue` event.
+    fn wake_when_free(&self) {
+        let injector = match *self
+            .injector
+            .lock()
+            .expect("poisoned lock in wake_when_free")
+        {
+            Some(ref injector) => injector.clone(),
+            None => return,
+        };
+
+        if self.waiting.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let children = Arc::clone(&self.children);
+        let audit = self.audit.clone();
+        let waiting = Arc::clone(&self.waiting);
+        let max_concurrent = self.args.max_concurrent;
+        thread::spawn(move || loop {
+            thread::sleep(QUEUE_POLL);
+            let running = reap(
+                &mut children.lock().expect("poisoned lock in wake_when_free"),
+                audit.as_deref(),
+            );
+
+            if !matches!(running, Ok(running) if running >= max_concurrent) {
+                waiting.store(false, Ordering::SeqCst);
+                injector.run(Origin::Queue).ok();
+                return;
+            }
+        });
+    }
+
+    /// Pass a signal received by watchexec on to every running command.
+    fn forward_signal(&self, sig: Signal) {
+        let mut children = self
+            .children
+            .lock()
+            .expect("poisoned lock in forward_signal");
+
+        for run in children.iter_mut() {
+            #[cfg(unix)]
+            run.process
+                .signal(sig.into())
+                .unwrap_or_else(|err| warn!("Could not pass on signal to command: {}", err));
+
+            #[cfg(not(unix))]
+            run.process
+                .kill()
+                .unwrap_or_else(|err| warn!("Could not pass on termination to command: {}", err));
+        }
+    }
+
+    /// Wait for every run still going, killing those which don't finish
+    /// within the restart timeout.
+    fn stop(&self) {
+        let mut children = self.children.lock().expect("poisoned lock in stop");
+        for mut run in children.drain(..) {
+            self.wait_or_kill(&mut run, self.args.restart_timeout)
+                .map(drop)
+                .unwrap_or_else(|err| warn!("Could not stop command: {}", err));
+        }
+    }
+
+    /// Whether the command has been run `max_runs` times.
+    ///
+    /// If so, this waits for the last runs to finish before returning.
+    fn reached_max_runs(&self, children: &mut [Run]) -> Result<bool> {
+        match self.args.max_runs {
+            Some(max) if self.runs.load(Ordering::SeqCst) >= max => {
+                debug!("Ran the command {} times, stopping", max);
+                for run in children.iter_mut() {
+                    self.wait(run)?;
+                }
+
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Whether the latest run of every command is ready, see
+    /// [`Config.readiness`][crate::config::Config].
+    ///
+    /// Without readiness detection, runs are ready as soon as they start.
+    pub fn is_ready(&self) -> bool {
+        self.handlers().all(|handler| {
+            handler
+                .children
+                .lock()
+                .expect("poisoned lock in is_ready")
+                .last()
+                .map_or(false, |run| run.state.ready.load(Ordering::SeqCst))
+        })
+    }
+
+    pub fn has_running_process(&self) -> Result<bool> {
+        Ok(self.running_processes()? > 0)
+    }
+
+    /// How many commands are currently running, including `commands`.
+    pub fn running_processes(&self) -> Result<usize> {
+        let mut running = 0;
+        for handler in iter::once(self).chain(&self.others) {
+            let mut children = handler
+                .children
+                .lock()
+                .expect("poisoned lock in running_processes");
+            running += handler.reap(&mut children)?;
+        }
+
+        Ok(running)
+    }
+}
+
+/// What happens for the command of a handler, ignoring `commands`.
+impl ExecHandler {
+    // Only returns Err() on lock poisoning.
+    fn manual(&self) -> Result<bool> {
+        if self.args.once {
+            return Ok(true);
+        }
+
+        let mut children = self.children.lock()?;
+        if self.reap(&mut children)? >= self.args.max_concurrent {
+            // Make room by killing the oldest command
+            let mut oldest = children.remove(0);
+            oldest.process.kill().ok();
+            self.wait(&mut oldest).ok();
+        }
+
+        self.spawn(&mut children, &[])?;
+        Ok(!self.reached_max_runs(&mut children)?)
+    }
+
+    fn event(&self, events: &[Event]) -> Result<bool> {
+        if let Some(Event::Source(Origin::Queue)) = events.first() {
+            let mut children = self.children.lock().expect("poisoned lock in on_event");
+            self.start_queued(&mut children)?;
+            return Ok(!self.reached_max_runs(&mut children)?);
+        }
+
+        if let Some(Event::Source(Origin::ResourceLimit(pid))) = events.first() {
+            return self.restart_over_limit(*pid);
+        }
+
+        if let Some(Event::Source(Origin::Cooldown)) = events.first() {
+            return self.end_cooldown();
+        }
+
+        for event in events {
+            if let Event::Signal(sig) = event {
+                self.forward_signal(*sig);
+            }
+        }
+
+        // As `dispatch` does, but for this command only
+        let mut keep_going = true;
+        let mut ops = Vec::new();
+        let mut received: &[(PathBuf, Instant)] = &[];
+        let mut shed = false;
+        for event in events {
+            match event {
+                Event::FsChange(op) => ops.push(op.clone()),
+                Event::Received(times) => received = times,
+                Event::BulkChange(_) => shed = true,
+                Event::Manual | Event::Tick => keep_going &= self.manual()?,
+                _ => {}
+            }
+        }
+
+        if !ops.is_empty() {
+            keep_going &= self.update(&ops, received)?;
+        } else if shed {
+            keep_going &= self.manual()?;
+        }
+
+        Ok(keep_going)
+    }
+
+    fn update(&self, ops: &[PathOp], received: &[(PathBuf, Instant)]) -> Result<bool> {
+        log::debug!("ON UPDATE: called");
+        self.note_received(ops, received);
+
+        let ops = self.without_spawn_noise(ops);
+        if ops.is_empty() {
+            return Ok(true);
+        }
+
+        let ops = match self.after_cooldown(&ops) {
+            Some(ops) => ops,
+            None => return Ok(true),
+        };
+        let ops = ops.as_slice();
+
+        let signal = self
+            .signal
+            .unwrap_or(ChildSignal::Standard(Signal::SIGTERM));
+        let mut children = self.children.lock().expect("poisoned lock in on_update");
+        let running = self.reap(&mut children)?;
+        let ops = self.without_own_changes(ops, running > 0);
+        if ops.is_empty() {
+            return Ok(true);
+        }
+
+        let ops = ops.as_slice();
+        let on_busy_update = self.on_busy_update(ops);
+
+        log::debug!(
+            "ON UPDATE: running processes: {}/{} --- on_busy_update: {:?}",
+            running,
+            self.args.max_concurrent,
+            on_busy_update
+        );
+
+        // When all slots are busy, the policy applies to the oldest command
+        match (running >= self.args.max_concurrent, on_busy_update) {
+            // If a slot is free, start the command, after any queued ones
+            (false, OnBusyUpdate::Queue) => {
+                self.queue
+                    .lock()
+                    .expect("poisoned lock in on_update")
+                    .push(ops.to_vec());
+                self.start_queued(&mut children)?;
+            }
+            (false, _) => {
+                self.spawn(&mut children, ops)?;
+            }
+
+            // Just send a signal to the command, do nothing more
+            (true, OnBusyUpdate::Signal) => signal_process(&mut children[0].process, signal)?,
+
+            // Send a signal to the command, wait for it to exit (or kill it),
+            // then run the command again
+            (true, OnBusyUpdate::Restart) => {
+                let mut oldest = children.remove(0);
+                signal_process(&mut oldest.process, signal)?;
+                self.wait_or_kill(&mut oldest, self.args.restart_timeout)?;
+                self.spawn(&mut children, ops)?;
+            }
+
+            // Keep the changes for when a slot is free, or wait for one if
+            // nothing can wake us up then
+            (true, OnBusyUpdate::Queue) => {
+                self.queue
+                    .lock()
+                    .expect("poisoned lock in on_update")
+                    .push(ops.to_vec());
+                if self
+                    .injector
+                    .lock()
+                    .expect("poisoned lock in on_update")
+                    .is_none()
+                {
+                    self.wait(&mut children.remove(0))?;
+                }
+                self.start_queued(&mut children)?;
+            }
+
+            (true, OnBusyUpdate::DoNothing) => {}
+        }
+
+        // Handle once option for integration testing
+        if self.args.once {
+            for run in children.iter_mut() {
+                if let Some(signal) = self.signal {
+                    signal_process(&mut run.process, signal)?;
+                }
+
+                self.wait(run)?;
+            }
+
+            return Ok(false);
+        }
+
+        Ok(!self.reached_max_runs(&mut children)?)
+    }
+}
+
+impl Handler for ExecHandler {
+    fn args(&self) -> Config {
+        self.args.clone()
+    }
+
+    fn on_start(&self, injector: Injector) {
+        let mut runs = Vec::with_capacity(self.others.len() + 1);
+        for handler in iter::once(self).chain(&self.others) {
+            *handler.injector.lock().expect("poisoned lock in on_start") = Some(injector.clone());
+            runs.push((Arc::downgrade(&handler.children), handler.audit.clone()));
+        }
+
+        // Runs which exit while nothing else looks at them are reaped right
+        // away, not left as zombies. Those locked are being looked after.
+        let reaper = self
+            .args
+            .signal_source
+            .install(Box::new(move |sig: Signal| {
+                if !matches!(sig, Signal::SIGCHLD) {
+                    return;
+                }
+
+                for (children, audit) in &runs {
+                    if let Some(children) = children.upgrade() {
+                        if let Ok(mut children) = children.try_lock() {
+                            reap(&mut children, audit.as_deref()).ok();
+                        }
+                    }
+                }
+            }));
+        *self.reaper.lock().expect("poisoned lock in on_start") = Some(reaper);
+    }
+
+    fn on_stop(&self) {
+        for handler in self.handlers() {
+            handler.stop();
+        }
+    }
+
+    fn on_manual(&self) -> Result<bool> {
+        self.each(Self::manual)
+    }
+
+    fn on_event(&self, events: &[Event]) -> Result<bool> {
+        self.each(|handler| handler.event(events))
+    }
+
+    fn on_update(&self, ops: &[PathOp]) -> Result<bool> {
+        self.each(|handler| handler.update(ops, &[]))
+    }
+}
+
+/// Copy output through, calling `ready` on the first line containing `marker`.
+fn pass_through<R, W>(output: R, mut to: W, marker: &str, ready: &dyn Fn())
+where
+    R: Read,
+    W: Write,
+{
+    let mut output = BufReader::new(output);
+    let mut line = Vec::new();
+    let mut found = false;
+    loop {
+        line.clear();
+        match output.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+
+        to.write_all(&line).and_then(|_| to.flush()).ok();
+        if !found && String::from_utf8_lossy(&line).contains(marker) {
+            found = true;
+            ready();
+        }
+    }
+}
+
+fn exited(audit: Option<&AuditLog>, run: &Run, status: Option<ExitStatus>) {
+    if run.state.exited.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    if let (Some(audit), Some(status)) = (audit, status) {
+        audit.exit(run.number, status);
+    }
+
+    if let (Some(injector), Some(status), Some(pid)) =
+        (&run.state.injector, status, run.process.id())
+    {
+        injector
+            .deliver(Origin::Exit(pid), Event::ProcessExit(status))
+            .ok();
+    }
+}
+
+/// Drop the runs which have finished, returning how many are still going.
+fn reap(children: &mut Vec<Run>, audit: Option<&AuditLog>) -> Result<usize> {
+    let mut i = 0;
+    while i < children.len() {
+        let run = &mut children[i];
+        match run.process.try_wait()? {
+            None if run.process.id().is_some() => i += 1,
+            status => {
+                exited(audit, run, status);
+                children.remove(i);
+            }
+        }
+    }
+
+    Ok(children.len())
+}
+
+/// Metadata about a run, so that commands can correlate their output.
+///
+/// `WATCHEXEC_RUN_NUMBER` counts from 1, `WATCHEXEC_RUN_ID` is a random UUID,
+/// and `WATCHEXEC_TRIGGERED_AT` is an RFC 3339 timestamp in UTC of when the
+/// changes triggering the run were received.
+fn run_env_vars(number: u64, triggered_at: SystemTime) -> Vec<(String, String)> {
+    vec![
+        ("WATCHEXEC_RUN_ID".into(), uuid::Uuid::new_v4().to_string()),
+        ("WATCHEXEC_RUN_NUMBER".into(), number.to_string()),
+        (
+            "WATCHEXEC_TRIGGERED_AT".into(),
+            humantime::format_rfc3339_millis(triggered_at).to_string(),
+        ),
+    ]
+}
+
+fn signal_process(child: &mut ChildProcess, signal: ChildSignal) -> Result<()> {
+    #[cfg(unix)]
+    child.signal(signal)?;
+
+    #[cfg(not(unix))]
+    if matches!(
+        signal,
+        ChildSignal::Standard(Signal::SIGTERM) | ChildSignal::Standard(Signal::SIGKILL)
+    ) {
+        child.kill()?;
+    } else {
+        debug!("Ignoring signal to send to process");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{consume, pass_through, run_env_vars, run_once, ExecHandler, Queue};
+    use crate::clock::Clock;
+    use crate::config::{Config, ConfigBuilder};
+    use crate::error::Result;
+    use crate::events::Origin;
+    use crate::pathop::PathOp;
+    use crate::run::{
+        tagged, CommandSpec, Handler, OnBusyUpdate, PathSeparator, QueuePolicy, Readiness,
+        ResourceLimits,
+    };
+    #[cfg(unix)]
+    use crate::signal::Signal;
+    use crate::testing::MockClock;
+    use crate::watcher::Injector;
+    use std::{
+        cell::Cell,
+        collections::HashMap,
+        fs,
+        path::{Path, PathBuf},
+        sync::{atomic::Ordering, mpsc::channel},
+        time::{Duration, Instant, UNIX_EPOCH},
+    };
+
+    struct Counter(Config, Cell<usize>);
+
+    impl Handler for Counter {
+        fn args(&self) -> Config {
+            self.0.clone()
+        }
+
+        fn on_manual(&self) -> Result<bool> {
+            Ok(true)
+        }
+
+        fn on_update(&self, _ops: &[PathOp]) -> Result<bool> {
+            self.1.set(self.1.get() + 1);
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn consume_until_channel_closes() {
+        let config = ConfigBuilder::default()
+            .cmd(vec!["true".into()])
+            .paths(vec![".".into()])
+            .build()
+            .expect("valid config");
+        let handler = Counter(config, Cell::new(0));
+
+        let (tx, rx) = channel();
+        tx.send(vec![PathOp::new(Path::new("/a"), None, None)])
+            .expect("send");
+        tx.send(vec![PathOp::new(Path::new("/b"), None, None)])
+            .expect("send");
+        drop(tx);
+
+        consume(&handler, rx).expect("consumed");
+        assert_eq!(handler.1.get(), 2);
+    }
+
+    #[test]
+    fn triggered_when_received() {
+        let clock = MockClock::new();
+        let config = ConfigBuilder::default()
+            .cmd(vec!["true".into()])
+            .paths(vec![".".into()])
+            .clock(clock.clone())
+            .build()
+            .expect("valid config");
+        let handler = ExecHandler::new(config).expect("valid handler");
+        let a = PathOp::new(Path::new("/a"), None, None);
+        let b = PathOp::new(Path::new("/b"), None, None);
+
+        let a_at = clock.now();
+        clock.advance(Duration::from_secs(1));
+        let b_at = clock.now();
+        clock.advance(Duration::from_secs(2));
+        handler.note_received(
+            &[a.clone(), b.clone()],
+            &[(a.path.clone(), a_at), (b.path.clone(), b_at)],
+        );
+
+        let now = UNIX_EPOCH + Duration::from_secs(10);
+        assert_eq!(
+            handler.triggered_at(&[b, a], now),
+            UNIX_EPOCH + Duration::from_secs(8)
+        );
+        assert_eq!(handler.triggered_at(&[], now), now);
+    }
+
+    #[test]
+    fn run_metadata() {
+        let at = UNIX_EPOCH + Duration::from_millis(1_500);
+        let vars: HashMap<_, _> = run_env_vars(3, at).into_iter().collect();
+
+        assert_eq!(vars["WATCHEXEC_RUN_NUMBER"], "3");
+        assert_eq!(vars["WATCHEXEC_TRIGGERED_AT"], "1970-01-01T00:00:01.500Z");
+        assert!(uuid::Uuid::parse_str(&vars["WATCHEXEC_RUN_ID"]).is_ok());
+        assert_ne!(
+            vars["WATCHEXEC_RUN_ID"],
+            run_env_vars(3, at)[0].1,
+            "run ids are unique"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stops_after_max_runs() {
+        let config = ConfigBuilder::default()
+            .cmd(vec!["true".into()])
+            .paths(vec![".".into()])
+            .max_runs(2_u64)
+            .on_busy_update(OnBusyUpdate::Queue)
+            .build()
+            .expect("valid config");
+        let handler = ExecHandler::new(config).expect("valid handler");
+        let ops = [PathOp::new(Path::new("/a"), None, None)];
+
+        assert!(handler.on_manual().expect("manual"));
+        assert!(!handler.on_update(&ops).expect("update"));
+        assert!(!handler.has_running_process().expect("running"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runs_up_to_max_concurrent() {
+        let config = ConfigBuilder::default()
+            .cmd(vec!["sleep 10".into()])
+            .paths(vec![".".into()])
+            .max_concurrent(2_usize)
+            .build()
+            .expect("valid config");
+        let handler = ExecHandler::new(config).expect("valid handler");
+        let ops = [PathOp::new(Path::new("/a"), None, None)];
+
+        for _ in 0..3 {
+            handler.on_update(&ops).expect("update");
+        }
+        assert_eq!(handler.running_processes().expect("running"), 2);
+
+        handler.forward_signal(Signal::SIGKILL);
+        for run in handler.children.lock().expect("lock").iter_mut() {
+            run.process.wait().expect("wait");
+        }
+        assert_eq!(handler.running_processes().expect("running"), 0);
+    }
+
+    fn batch(paths: &[&str]) -> Vec<PathOp> {
+        paths
+            .iter()
+            .map(|path| PathOp::new(Path::new(path), None, None))
+            .collect()
+    }
+
+    fn queued(policy: QueuePolicy) -> Vec<Vec<PathOp>> {
+        let mut queue = Queue {
+            policy,
+            ..Queue::default()
+        };
+        queue.push(batch(&["/a"]));
+        queue.push(batch(&["/b", "/a"]));
+        queue.push(batch(&["/c"]));
+
+        let mut batches = Vec::new();
+        while let Some(ops) = queue.pop() {
+            batches.push(ops);
+        }
+        batches
+    }
+
+    #[test]
+    fn queue_policies() {
+        assert_eq!(
+            queued(QueuePolicy::Unbounded),
+            vec![batch(&["/a"]), batch(&["/b", "/a"]), batch(&["/c"])]
+        );
+        assert_eq!(
+            queued(QueuePolicy::Collapse),
+            vec![batch(&["/a", "/b", "/c"])]
+        );
+        assert_eq!(
+            queued(QueuePolicy::Cap(2)),
+            vec![batch(&["/a"]), batch(&["/b", "/a"])]
+        );
+        assert_eq!(
+            queued(QueuePolicy::DropOldest(2)),
+            vec![batch(&["/b", "/a"]), batch(&["/c"])]
+        );
+    }
+
+    /// The next event delivered to the loop, other than for a command exiting.
+    #[cfg(unix)]
+    fn next_request(
+        requests: &std::sync::mpsc::Receiver<(Origin, crate::events::Event)>,
+        what: &str,
+    ) -> (Origin, crate::events::Event) {
+        loop {
+            match requests.recv_timeout(Duration::from_secs(5)).expect(what) {
+                (Origin::Exit(_), _) => continue,
+                request => return request,
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn delivers_process_exit() {
+        let config = ConfigBuilder::default()
+            .cmd(vec!["exit 3".into()])
+            .paths(vec![".".into()])
+            .build()
+            .expect("valid config");
+        let handler = ExecHandler::new(config).expect("valid handler");
+        let (tx, _rx) = channel();
+        let (requests_tx, requests) = channel();
+        handler.on_start(Injector::new(tx, requests_tx));
+
+        handler.on_manual().expect("run");
+        let (origin, event) = requests
+            .recv_timeout(Duration::from_secs(5))
+            .expect("exited");
+        assert!(matches!(origin, Origin::Exit(_)));
+        assert!(matches!(
+            event,
+            crate::events::Event::ProcessExit(status) if status.code() == Some(3)
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stop_kills_remaining_runs() {
+        let config = ConfigBuilder::default()
+            .cmd(vec!["sleep 10".into()])
+            .paths(vec![".".into()])
+            .restart_timeout(Duration::from_millis(200))
+            .build()
+            .expect("valid config");
+        let handler = ExecHandler::new(config).expect("valid handler");
+
+        handler.on_manual().expect("run");
+        let start = Instant::now();
+        handler.on_stop();
+
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(handler.children.lock().expect("lock").is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn queued_runs_start_when_free() {
+        let config = ConfigBuilder::default()
+            .cmd(vec!["sleep 0.2".into()])
+            .paths(vec![".".into()])
+            .on_busy_update(OnBusyUpdate::Queue)
+            .queue_policy(QueuePolicy::Collapse)
+            .build()
+            .expect("valid config");
+        let handler = ExecHandler::new(config).expect("valid handler");
+        let (tx, _rx) = channel();
+        let (requests_tx, requests) = channel();
+        handler.on_start(Injector::new(tx, requests_tx));
+
+        // The first update runs, the others wait without blocking
+        for path in &["/a", "/b", "/c"] {
+            handler.on_update(&batch(&[path])).expect("update");
+        }
+        assert_eq!(handler.runs.load(Ordering::SeqCst), 1);
+
+        let (origin, _) = next_request(&requests, "woken up");
+        assert_eq!(origin, Origin::Queue);
+        handler
+            .on_event(&tagged(origin, crate::events::Event::Manual))
+            .expect("queued run");
+        assert_eq!(handler.runs.load(Ordering::SeqCst), 2);
+        assert!(handler.queue.lock().expect("lock").is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn drops_stale_queued_changes() {
+        let clock = MockClock::new();
+        let config = ConfigBuilder::default()
+            .cmd(vec!["sleep 0.2".into()])
+            .paths(vec![".".into()])
+            .on_busy_update(OnBusyUpdate::Queue)
+            .queue_policy(QueuePolicy::Unbounded)
+            .drop_stale(true)
+            .clock(clock.clone())
+            .build()
+            .expect("valid config");
+        let handler = ExecHandler::new(config).expect("valid handler");
+        let (tx, _rx) = channel();
+        let (requests_tx, requests) = channel();
+        handler.on_start(Injector::new(tx, requests_tx));
+
+        // Received before the first run, but only delivered once it started
+        let early = clock.now();
+        clock.advance(Duration::from_secs(1));
+        handler.on_update(&batch(&["/a"])).expect("first run");
+        clock.advance(Duration::from_secs(1));
+        handler
+            .update(&batch(&["/early"]), &[(PathBuf::from("/early"), early)])
+            .expect("queued");
+        for path in &["/b", "/c"] {
+            clock.advance(Duration::from_secs(1));
+            handler.on_update(&batch(&[path])).expect("queued");
+        }
+        assert_eq!(handler.runs.load(Ordering::SeqCst), 1);
+
+        // The run for /b starts after /c was received, so it sees /c already
+        clock.advance(Duration::from_secs(1));
+        let (origin, _) = next_request(&requests, "woken up");
+        handler
+            .on_event(&tagged(origin, crate::events::Event::Manual))
+            .expect("queued run");
+        assert_eq!(handler.runs.load(Ordering::SeqCst), 2);
+        assert!(handler.queue.lock().expect("lock").is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn restarts_over_resource_limits() {
+        let hook = std::env::temp_dir().join(format!("watchexec-limit-{}", std::process::id()));
+        let config = ConfigBuilder::default()
+            .cmd(vec!["sleep 10".into()])
+            .paths(vec![".".into()])
+            .resource_limits(ResourceLimits {
+                memory: Some(1),
+                cpu: None,
+            })
+            .resource_poll(Duration::from_millis(10))
+            .limit_cmd(vec![format!("touch {}", hook.display())])
+            .build()
+            .expect("valid config");
+        let handler = ExecHandler::new(config).expect("valid handler");
+        let (tx, _rx) = channel();
+        let (requests_tx, requests) = channel();
+        handler.on_start(Injector::new(tx, requests_tx));
+
+        handler.on_manual().expect("first run");
+        let (origin, event) = next_request(&requests, "over the limits");
+        assert!(matches!(origin, Origin::ResourceLimit(_)));
+        assert!(hook.exists());
+
+        handler.on_event(&tagged(origin, event)).expect("restart");
+        assert_eq!(handler.runs.load(Ordering::SeqCst), 2);
+        assert_eq!(handler.running_processes().expect("count"), 1);
+
+        handler.forward_signal(Signal::SIGKILL);
+        fs::remove_file(&hook).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ignores_changes_after_spawning() {
+        let out = std::env::temp_dir().join(format!("watchexec-noise-{}", std::process::id()));
+        let clock = MockClock::new();
+        let config = ConfigBuilder::default()
+            .cmd(vec![format!(
+                "echo \"$WATCHEXEC_WRITTEN_PATH\" >> {}",
+                out.display()
+            )])
+            .paths(vec![".".into()])
+            .on_busy_update(OnBusyUpdate::Restart)
+            .post_spawn_ignore(Duration::from_secs(1))
+            .clock(clock.clone())
+            .build()
+            .expect("valid config");
+        let handler = ExecHandler::new(config).expect("valid handler");
+        let written = |path| [PathOp::new(Path::new(path), Some(notify::op::WRITE), None)];
+
+        let lines = |count| {
+            let mut seen = String::new();
+            for _ in 0..100 {
+                seen = fs::read_to_string(&out).unwrap_or_default();
+                if seen.lines().count() == count {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            seen
+        };
+
+        handler.on_update(&written("/a")).expect("first run");
+        handler.on_update(&written("/a.lock")).expect("ignored");
+        assert_eq!(handler.runs.load(Ordering::SeqCst), 1);
+
+        // Let the first run finish before restarting
+        assert_eq!(lines(1), "/a\n");
+
+        // Only the changes received within the window are left out
+        clock.advance(Duration::from_millis(500));
+        let lock = clock.now();
+        clock.advance(Duration::from_secs(1));
+        let ops = [written("/a.lock"), written("/b")].concat();
+        handler
+            .update(
+                &ops,
+                &[
+                    (PathBuf::from("/a.lock"), lock),
+                    (PathBuf::from("/b"), clock.now()),
+                ],
+            )
+            .expect("second run");
+        assert_eq!(handler.runs.load(Ordering::SeqCst), 2);
+
+        let seen = lines(2);
+        fs::remove_file(&out).ok();
+        assert_eq!(seen, "/a\n/b\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ignores_own_changes() {
+        let dir = std::env::temp_dir().join(format!("watchexec-own-{}", std::process::id()));
+        let out = dir.join("out");
+        fs::create_dir_all(&out).expect("create dir");
+        let config = ConfigBuilder::default()
+            .cmd(vec![format!("touch {}", out.join("built").display())])
+            .paths(vec![dir.clone()])
+            .output_paths(vec![out.clone()])
+            .build()
+            .expect("valid config");
+        let handler = ExecHandler::new(config).expect("valid handler");
+        let written = |path: &Path| [PathOp::new(path, Some(notify::op::WRITE), None)];
+
+        handler
+            .on_update(&written(&dir.join("src")))
+            .expect("first run");
+        handler
+            .wait(&mut handler.children.lock().expect("lock").remove(0))
+            .expect("exit");
+
+        handler
+            .on_update(&written(&out.join("built")))
+            .expect("own change");
+        assert_eq!(handler.runs.load(Ordering::SeqCst), 1);
+
+        handler
+            .on_update(&written(&out.join("other")))
+            .expect("not modified");
+        assert_eq!(handler.runs.load(Ordering::SeqCst), 2);
+        handler
+            .wait(&mut handler.children.lock().expect("lock").remove(0))
+            .expect("exit");
+
+        // Edits made once the run is over are someone else's
+        for _ in 0..100 {
+            if handler.exited_at.lock().expect("lock").is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+        fs::write(out.join("built"), "edited").expect("edit");
+        handler
+            .on_update(&written(&out.join("built")))
+            .expect("edited after the run");
+        assert_eq!(handler.runs.load(Ordering::SeqCst), 3);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_changes_while_cooling_down() {
+        let out = std::env::temp_dir().join(format!("watchexec-cooldown-{}", std::process::id()));
+        let clock = MockClock::new();
+        let config = ConfigBuilder::default()
+            .cmd(vec![format!(
+                "echo \"$WATCHEXEC_WRITTEN_PATH\" >> {}",
+                out.display()
+            )])
+            .paths(vec![".".into()])
+            .cooldown(Duration::from_secs(10))
+            .clock(clock.clone())
+            .build()
+            .expect("valid config");
+        let handler = ExecHandler::new(config).expect("valid handler");
+        let written = |path| [PathOp::new(Path::new(path), Some(notify::op::WRITE), None)];
+
+        let wait_exit = |previous| {
+            for _ in 0..100 {
+                if *handler.last_exit.lock().expect("lock") != previous {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            *handler.last_exit.lock().expect("lock")
+        };
+
+        handler.on_update(&written("/a")).expect("first run");
+        let exited = wait_exit(None);
+        assert!(exited.is_some());
+
+        handler.on_update(&written("/b")).expect("cooling down");
+        assert_eq!(handler.runs.load(Ordering::SeqCst), 1);
+
+        clock.advance(Duration::from_secs(10));
+        handler.on_update(&written("/c")).expect("second run");
+        assert_eq!(handler.runs.load(Ordering::SeqCst), 2);
+        wait_exit(exited);
+
+        let out_ = fs::read_to_string(&out).expect("output");
+        fs::remove_file(&out).ok();
+        assert_eq!(out_, "/a\nb:c\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runs_kept_changes_after_cooldown() {
+        let config = ConfigBuilder::default()
+            .cmd(vec!["true".into()])
+            .paths(vec![".".into()])
+            .cooldown(Duration::from_millis(200))
+            .build()
+            .expect("valid config");
+        let handler = ExecHandler::new(config).expect("valid handler");
+        let (tx, _rx) = channel();
+        let (requests_tx, requests) = channel();
+        handler.on_start(Injector::new(tx, requests_tx));
+        let written = |path| [PathOp::new(Path::new(path), Some(notify::op::WRITE), None)];
+
+        handler.on_update(&written("/a")).expect("first run");
+        for _ in 0..100 {
+            if handler.last_exit.lock().expect("lock").is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        handler.on_update(&written("/b")).expect("cooling down");
+        assert_eq!(handler.runs.load(Ordering::SeqCst), 1);
+
+        let (origin, event) = next_request(&requests, "cooldown over");
+        assert_eq!(origin, Origin::Cooldown);
+        handler
+            .on_event(&tagged(origin, event))
+            .expect("second run");
+        assert_eq!(handler.runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restart_kills_stubborn_commands() {
+        let config = ConfigBuilder::default()
+            .cmd(vec!["trap '' TERM; sleep 10".into()])
+            .paths(vec![".".into()])
+            .on_busy_update(OnBusyUpdate::Restart)
+            .restart_timeout(Duration::from_millis(200))
+            .build()
+            .expect("valid config");
+        let handler = ExecHandler::new(config).expect("valid handler");
+        let ops = [PathOp::new(Path::new("/a"), None, None)];
+
+        handler.on_update(&ops).expect("first run");
+        // Give the shell time to set its trap up
+        std::thread::sleep(Duration::from_millis(100));
+
+        let start = std::time::Instant::now();
+        handler.on_update(&ops).expect("restart");
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(handler.runs.load(Ordering::SeqCst), 2);
+
+        handler.forward_signal(Signal::SIGKILL);
+    }
+
+    #[test]
+    fn on_busy_rules() {
+        let config = ConfigBuilder::default()
+            .cmd(vec!["true".into()])
+            .paths(vec![".".into()])
+            .on_busy_update(OnBusyUpdate::DoNothing)
+            .on_busy_rules(vec![
+                ("**/src/**".into(), OnBusyUpdate::Restart),
+                ("**/assets/**".into(), OnBusyUpdate::Queue),
+            ])
+            .build()
+            .expect("valid config");
+        let handler = ExecHandler::new(config).expect("valid handler");
+
+        assert!(matches!(
+            handler.on_busy_update(&batch(&["/app/assets/logo.png", "/app/src/main.rs"])),
+            OnBusyUpdate::Restart
+        ));
+        assert!(matches!(
+            handler.on_busy_update(&batch(&["/app/assets/logo.png"])),
+            OnBusyUpdate::Queue
+        ));
+        assert!(matches!(
+            handler.on_busy_update(&batch(&["/app/README.md"])),
+            OnBusyUpdate::DoNothing
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runs_several_commands() {
+        let config = ConfigBuilder::default()
+            .paths(vec![".".into()])
+            .commands(vec![
+                CommandSpec {
+                    name: "server".into(),
+                    cmd: vec!["sleep 10".into()],
+                    on_busy_update: OnBusyUpdate::Restart,
+                },
+                CommandSpec {
+                    name: "check".into(),
+                    cmd: vec!["sleep 10".into()],
+                    on_busy_update: OnBusyUpdate::DoNothing,
+                },
+            ])
+            .restart_timeout(Duration::from_millis(200))
+            .build()
+            .expect("valid config");
+        let handler = ExecHandler::new(config).expect("valid handler");
+        let ops = [PathOp::new(Path::new("/a"), None, None)];
+
+        handler.on_update(&ops).expect("first runs");
+        assert_eq!(handler.running_processes().expect("running"), 2);
+
+        handler.on_update(&ops).expect("second runs");
+        let runs: Vec<u64> = handler
+            .others
+            .iter()
+            .map(|other| other.runs.load(Ordering::SeqCst))
+            .collect();
+        assert_eq!(runs, vec![2, 1]);
+        assert_eq!(handler.runs.load(Ordering::SeqCst), 0);
+
+        handler
+            .on_event(&[crate::events::Event::Signal(Signal::SIGKILL)])
+            .expect("signal");
+        for other in &handler.others {
+            for run in other.children.lock().expect("lock").iter_mut() {
+                run.process.wait().expect("wait");
+            }
+        }
+        assert_eq!(handler.running_processes().expect("running"), 0);
+    }
+
+    #[test]
+    fn finds_ready_line() {
+        let output = "starting\nlistening on :8080\nlistening on :8081\n";
+        let mut copy = Vec::new();
+        let ready = Cell::new(0);
+
+        pass_through(output.as_bytes(), &mut copy, "listening", &|| {
+            ready.set(ready.get() + 1)
+        });
+        assert_eq!(copy, output.as_bytes());
+        assert_eq!(ready.get(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ready_once_port_opens() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        drop(listener);
+
+        let hook = std::env::temp_dir().join(format!("watchexec-ready-{}", std::process::id()));
+        std::fs::remove_file(&hook).ok();
+
+        let config = ConfigBuilder::default()
+            .cmd(vec!["sleep 10".into()])
+            .paths(vec![".".into()])
+            .readiness(Readiness::Port(addr.to_string()))
+            .ready_cmd(vec![format!("touch {}", hook.display())])
+            .build()
+            .expect("valid config");
+        let handler = ExecHandler::new(config).expect("valid handler");
+        assert!(!handler.is_ready());
+
+        handler
+            .on_update(&[PathOp::new(Path::new("/a"), None, None)])
+            .expect("update");
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(!handler.is_ready());
+
+        let _listener = std::net::TcpListener::bind(addr).expect("bind again");
+        for _ in 0..50 {
+            if handler.is_ready() && hook.exists() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert!(handler.is_ready());
+        assert!(hook.exists());
+
+        std::fs::remove_file(&hook).ok();
+        handler.forward_signal(Signal::SIGKILL);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_paths_to_stdin() {
+        let out = std::env::temp_dir().join(format!("watchexec-stdin-{}", std::process::id()));
+        let config = ConfigBuilder::default()
+            .cmd(vec![format!("cat > {}", out.display())])
+            .paths(vec![".".into()])
+            .paths_on_stdin(true)
+            .path_separator(PathSeparator::Nul)
+            .build()
+            .expect("valid config");
+
+        let status = run_once(
+            config,
+            &[
+                PathOp::new(Path::new("/a b"), Some(notify::op::WRITE), None),
+                PathOp::new(Path::new("/c"), Some(notify::op::CREATE), None),
+            ],
+        )
+        .expect("ran");
+        assert!(status.success());
+        assert_eq!(fs::read(&out).expect("output"), b"/a b\0/c\0");
+        fs::remove_file(&out).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runs_once() {
+        let config = ConfigBuilder::default()
+            .cmd(vec![
+                "test \"$WATCHEXEC_WRITTEN_PATH\" = /a && exit 3".into()
+            ])
+            .paths(vec![".".into()])
+            .build()
+            .expect("valid config");
+
+        let status = run_once(
+            config,
+            &[PathOp::new(Path::new("/a"), Some(notify::op::WRITE), None)],
+        )
+        .expect("ran");
+        assert_eq!(status.code(), Some(3));
+    }
+}
@@ -0,0 +1,210 @@
+//! Audit log of what happened while watching.
+//!
+//! When [`Config.audit_log`][crate::config::Config] is set, a line is appended
+//! to that file for every batch of changes accepted by the watch loop, and,
+//! with an [`ExecHandler`][crate::run::ExecHandler], for every run of the
+//! command started and every exit noticed. This answers questions like "what
+//! triggered that deploy at 3am" after the fact.
+//!
+//! Each line is a JSON object with a `time` (RFC 3339, UTC) and a `kind`:
+//!
+//! - `batch`: `paths`, a list of objects with the `path` and its `op`.
+//! - `spawn`: the `run` number, `pid`, `command`, and `env`, the metadata
+//!   variables set for the run, along with `path_vars`, the names of the
+//!   `WATCHEXEC_*_PATH` variables set.
+//! - `exit`: the `run` number, and the exit `code`, or `signal` on Unix. Exits
+//!   are only noticed on the next event or run, so their time is approximate.
+//!
+//! Records for one of the [`Config.commands`][crate::config::Config] also have
+//! its `name`, as each command counts its runs separately.
+
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    process::ExitStatus,
+    time::SystemTime,
+};
+
+use log::warn;
+
+use crate::error::Result;
+use crate::pathop::PathOp;
+
+/// Appends records to an audit log file.
+#[derive(Debug)]
+pub struct AuditLog {
+    file: File,
+    name: Option<String>,
+}
+
+impl AuditLog {
+    /// Open the audit log at `path`, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file, name: None })
+    }
+
+    /// Add the name of the command to every record.
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Record a batch of changes accepted by the watch loop.
+    pub fn batch(&self, ops: &[PathOp]) {
+        let paths: Vec<String> = ops
+            .iter()
+            .map(|op| {
+                format!(
+                    r#"{{"path":{},"op":{}}}"#,
+                    string(&op.path.to_string_lossy()),
+                    op.op
+                        .map_or_else(|| "null".into(), |o| string(&format!("{:?}", o)))
+                )
+            })
+            .collect();
+
+        self.write("batch", &format!(r#""paths":[{}]"#, paths.join(",")));
+    }
+
+    /// Record the start of a run of the command.
+    ///
+    /// `env` is all that was added to the command's environment.
+    pub fn spawn(&self, run: u64, pid: u32, command: &[String], env: &[(String, String)]) {
+        let command: Vec<String> = command.iter().map(|arg| string(arg)).collect();
+        let (path_vars, meta_vars): (Vec<_>, Vec<_>) =
+            env.iter().partition(|(name, _)| name.ends_with("_PATH"));
+        let meta_vars: Vec<String> = meta_vars
+            .iter()
+            .map(|(name, val)| format!("{}:{}", string(name), string(val)))
+            .collect();
+        let path_vars: Vec<String> = path_vars.iter().map(|(name, _)| string(name)).collect();
+
+        self.write(
+            "spawn",
+            &format!(
+                r#""run":{},"pid":{},"command":[{}],"env":{{{}}},"path_vars":[{}]"#,
+                run,
+                pid,
+                command.join(","),
+                meta_vars.join(","),
+                path_vars.join(",")
+            ),
+        );
+    }
+
+    /// Record the exit of a run of the command.
+    pub fn exit(&self, run: u64, status: ExitStatus) {
+        #[cfg(unix)]
+        let signal = std::os::unix::process::ExitStatusExt::signal(&status);
+        #[cfg(not(unix))]
+        let signal: Option<i32> = None;
+
+        let outcome = match (status.code(), signal) {
+            (Some(code), _) => format!(r#""code":{}"#, code),
+            (None, Some(signal)) => format!(r#""signal":{}"#, signal),
+            (None, None) => r#""code":null"#.into(),
+        };
+
+        self.write("exit", &format!(r#""run":{},{}"#, run, outcome));
+    }
+
+    /// Append a record, logging rather than returning errors.
+    ///
+    /// The line is written in one go, so that records from several handles on
+    /// the same file don't get mixed up.
+    fn write(&self, kind: &str, fields: &str) {
+        let name = self
+            .name
+            .as_ref()
+            .map_or_else(String::new, |name| format!("\"name\":{},", string(name)));
+        let line = format!(
+            "{{\"time\":{},\"kind\":{},{}{}}}\n",
+            string(&humantime::format_rfc3339_millis(SystemTime::now()).to_string()),
+            string(kind),
+            name,
+            fields
+        );
+
+        (&self.file)
+            .write_all(line.as_bytes())
+            .unwrap_or_else(|err| warn!("Could not write to audit log: {}", err));
+    }
+}
+
+/// Quote and escape a string for JSON.
+fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{string, AuditLog};
+    use crate::pathop::PathOp;
+    use std::{env, fs, path::Path, process};
+
+    #[test]
+    fn escapes_strings() {
+        assert_eq!(string("plain"), r#""plain""#);
+        assert_eq!(string("a \"b\"\\c\nd\u{1}"), r#""a \"b\"\\c\nd\u0001""#);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn records_lines() {
+        let path = env::temp_dir().join(format!("watchexec-audit-{}.jsonl", process::id()));
+        fs::remove_file(&path).ok();
+
+        let log = AuditLog::open(&path).expect("open");
+        log.batch(&[PathOp::new(
+            Path::new("/a\"b"),
+            Some(notify::op::WRITE),
+            None,
+        )]);
+        log.spawn(
+            1,
+            42,
+            &["echo hi".into()],
+            &[
+                ("WATCHEXEC_WRITTEN_PATH".into(), "/a".into()),
+                ("WATCHEXEC_RUN_NUMBER".into(), "1".into()),
+            ],
+        );
+        let status = process::Command::new("false").status().expect("status");
+        log.exit(1, status);
+
+        AuditLog::open(&path)
+            .expect("open")
+            .with_name("check")
+            .exit(1, status);
+
+        let contents = fs::read_to_string(&path).expect("read");
+        fs::remove_file(&path).ok();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].ends_with(r#","kind":"batch","paths":[{"path":"/a\"b","op":"WRITE"}]}"#));
+        assert!(lines[1].ends_with(
+            r#","kind":"spawn","run":1,"pid":42,"command":["echo hi"],"env":{"WATCHEXEC_RUN_NUMBER":"1"},"path_vars":["WATCHEXEC_WRITTEN_PATH"]}"#
+        ));
+        assert!(lines[2].ends_with(r#","kind":"exit","run":1,"code":1}"#));
+        assert!(lines[3].ends_with(r#","kind":"exit","name":"check","run":1,"code":1}"#));
+    }
+}
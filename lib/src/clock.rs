@@ -0,0 +1,63 @@
+//! Abstraction over time.
+//!
+//! Everything in the watch loop which waits or measures durations does so via
+//! the [`Clock`] in [`Config.clock`][crate::config::Config]. This is the
+//! [`SystemClock`] by default; tests can use a
+//! [`MockClock`][crate::testing::MockClock] instead, so that debouncing and
+//! timeouts play out without real sleeps.
+
+use std::{
+    fmt, thread,
+    time::{Duration, Instant},
+};
+
+/// A source of time.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// The current instant.
+    fn now(&self) -> Instant;
+
+    /// Block (or pretend to) for the given duration.
+    fn sleep(&self, duration: Duration);
+
+    /// Time elapsed since an instant obtained from this clock.
+    fn since(&self, earlier: Instant) -> Duration {
+        self.now()
+            .checked_duration_since(earlier)
+            .unwrap_or_default()
+    }
+}
+
+/// The real clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// How often [`wait`] looks at the clock again.
+const WAIT_STEP: Duration = Duration::from_millis(100);
+
+/// Block until `duration` has passed by the clock, for threads which only
+/// wait, like timers.
+///
+/// Unlike [`Clock::sleep`], this waits for real, in short steps, so that a
+/// [`MockClock`][crate::testing::MockClock] standing still doesn't make such a
+/// thread spin, while one moved forward is noticed soon after.
+pub(crate) fn wait(clock: &dyn Clock, duration: Duration) {
+    let start = clock.now();
+    loop {
+        let elapsed = clock.since(start);
+        if elapsed >= duration {
+            break;
+        }
+
+        thread::sleep((duration - elapsed).min(WAIT_STEP));
+    }
+}
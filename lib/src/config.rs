@@ -14,9 +14,23 @@
 //! ```
 
 use derive_builder::Builder;
-use std::{path::PathBuf, time::Duration};
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
-use crate::run::OnBusyUpdate;
+use crate::clock::{Clock, SystemClock};
+use crate::error::{Error, Result as WResult};
+use crate::remote::RemoteAgent;
+use crate::run::{
+    CommandSpec, ContainerAction, IgnoreSearch, OnBusyUpdate, PathSeparator, QueuePolicy,
+    Readiness, ResourceLimits,
+};
+use crate::signal::{ProcessSignals, SignalSource};
+use crate::trigger::TriggerListener;
 use crate::Shell;
 
 /// Arguments to the watcher
@@ -32,8 +46,53 @@ pub struct Config {
     /// joined together with a single space and passed to the shell. More
     /// control can then be obtained by providing a 1-element vec, and doing
     /// your own joining and/or escaping there.
+    ///
+    /// May only be left empty if the config has a `container` or `commands`,
+    /// or with `no_command`.
+    #[builder(default)]
     pub cmd: Vec<String>,
 
+    /// Allow the config to have no command at all.
+    ///
+    /// This is for embedders whose own [`Handler`][crate::run::Handler] acts
+    /// on changes instead, like [`Watchexec`][crate::Watchexec] with only an
+    /// update callback. An [`ExecHandler`][crate::run::ExecHandler] still
+    /// needs a command.
+    #[builder(default)]
+    pub no_command: bool,
+
+    /// Command to run once before watching starts, if not empty.
+    ///
+    /// This is interpreted like `cmd`, and run to completion: if it fails,
+    /// [`watch`][crate::run::watch] returns an error without watching.
+    #[builder(default)]
+    pub setup_cmd: Vec<String>,
+
+    /// Command to run once watching stops, if not empty.
+    ///
+    /// This is interpreted like `cmd`, and run to completion whatever the
+    /// reason for stopping, including errors and signals (unless the process
+    /// is forcibly terminated).
+    #[builder(default)]
+    pub teardown_cmd: Vec<String>,
+
+    /// If Some, how to tell that a run of the command is ready.
+    ///
+    /// Until then, [`ExecHandler::is_ready`][crate::run::ExecHandler::is_ready]
+    /// is false for it, and once it is, `ready_cmd` runs. To look for a line,
+    /// the command's stdout and stderr are piped through watchexec, so the
+    /// command doesn't write to a terminal anymore.
+    #[builder(default)]
+    pub readiness: Option<Readiness>,
+
+    /// Command to run every time a run of the command becomes ready, if not
+    /// empty.
+    ///
+    /// This is interpreted like `cmd`, from another thread. Failures are
+    /// only logged.
+    #[builder(default)]
+    pub ready_cmd: Vec<String>,
+
     /// List of paths to watch for changes.
     pub paths: Vec<PathBuf>,
 
@@ -53,14 +112,186 @@ pub struct Config {
     #[builder(default)]
     pub signal: Option<String>,
 
+    /// If Some, restart or signal that Docker container on every run (Unix only).
+    ///
+    /// See the [`docker`][crate::docker] module for details.
+    #[builder(default)]
+    pub container: Option<String>,
+
+    /// What to do with the `container`.
+    #[builder(default)]
+    pub container_action: ContainerAction,
+
     /// Specify what to do when receiving updates while the command is running.
     #[builder(default)]
     pub on_busy_update: OnBusyUpdate,
 
+    /// More commands to run in parallel on each trigger.
+    ///
+    /// An [`ExecHandler`][crate::run::ExecHandler] runs each of them alongside
+    /// `cmd` (which may then be empty), with its own processes, on-busy
+    /// behaviour, queue, and run count for `max_runs`. Everything else is
+    /// shared, except `on_busy_rules` and `container`, which only apply to
+    /// `cmd`. When `cmd` is empty, only the first one clears the screen.
+    #[builder(default)]
+    pub commands: Vec<CommandSpec>,
+
+    /// Globs choosing a different `on_busy_update` for some changes.
+    ///
+    /// The first rule whose glob matches any path of a batch applies to it,
+    /// e.g. to restart a server for source changes, but only queue a run for
+    /// asset changes. Globs match full paths, as for `filters`. Batches which
+    /// match no rule use `on_busy_update`.
+    #[builder(default)]
+    pub on_busy_rules: Vec<(String, OnBusyUpdate)>,
+
+    /// What to keep of the updates received while busy, with
+    /// `on_busy_update` set to `Queue`.
+    ///
+    /// Queued runs start as soon as a slot is free, with the changes of their
+    /// batch (or of all the batches merged into one, when collapsing). By
+    /// default, they're all collapsed into a single follow-up run.
+    #[builder(default)]
+    pub queue_policy: QueuePolicy,
+
+    /// Drop queued changes received before the latest run started.
+    ///
+    /// That run already sees those changes, so starting another run for them
+    /// once it finishes would be redundant. This goes by when the watcher
+    /// delivered each change, not when its batch was handed over.
+    #[builder(default)]
+    pub drop_stale: bool,
+
+    /// How long to wait for the command to exit after signalling it, when
+    /// restarting, before killing it.
+    ///
+    /// This keeps a command which ignores the signal from blocking the loop.
+    #[builder(default = "Duration::from_secs(10)")]
+    pub restart_timeout: Duration,
+
+    /// If Some, restart the command when it goes over these limits (Linux
+    /// only).
+    ///
+    /// Usage is checked every `resource_poll`. When a limit is exceeded, the
+    /// `limit_cmd` is run, then the command is restarted as with
+    /// [`OnBusyUpdate::Restart`]. Restarting happens through the watch loop,
+    /// so only the hook runs otherwise.
+    #[builder(default)]
+    pub resource_limits: Option<ResourceLimits>,
+
+    /// How often to check the command's resource usage.
+    #[builder(default = "Duration::from_secs(1)")]
+    pub resource_poll: Duration,
+
+    /// Command to run when the command goes over its `resource_limits`.
+    ///
+    /// This is interpreted like `cmd`, and run to completion before the
+    /// command is restarted. If it fails, a warning is logged.
+    #[builder(default)]
+    pub limit_cmd: Vec<String>,
+
+    /// If Some, how long to cool down for after a run finishes.
+    ///
+    /// Changes seen in that time don't start a run right away: they are
+    /// kept, and run once it's over, merged with any batch of changes which
+    /// comes first. This stops commands which touch the watched files when
+    /// they finish from triggering themselves back to back.
+    #[builder(default)]
+    pub cooldown: Option<Duration>,
+
+    /// How long after starting the command to ignore changes for.
+    ///
+    /// Changes right after a run starts are often made by the command itself,
+    /// e.g. lockfiles and caches, so this drops them instead of triggering
+    /// again. This goes by when each change was received, so other changes
+    /// in the same batch still count. Zero (the default) ignores nothing.
+    #[builder(default)]
+    pub post_spawn_ignore: Duration,
+
+    /// Paths the command writes to, e.g. a build directory.
+    ///
+    /// Changes under these are taken to be made by the command, and don't
+    /// trigger a run, if the command is running when they're handled or the
+    /// file was modified since the last run started. Changes made there
+    /// otherwise, e.g. cleaning up between runs, still count.
+    #[builder(default)]
+    pub output_paths: Vec<PathBuf>,
+
+    /// How many instances of the command may run at once.
+    ///
+    /// Further changes start new instances until this many are running. Then,
+    /// `on_busy_update` applies to the oldest one, whose slot is reused if it
+    /// is restarted or waited on. Must be at least 1.
+    #[builder(default = "1")]
+    pub max_concurrent: usize,
+
+    /// If Some, stop after the command has been run that many times.
+    ///
+    /// The loop stops once the last run has finished. Must be at least 1.
+    #[builder(default)]
+    pub max_runs: Option<u64>,
+
+    /// Replace watchexec with the command for the final run, instead of
+    /// starting it as a child (Unix only).
+    ///
+    /// The final run is the last of `max_runs`. It then has watchexec's
+    /// process id, gets its signals directly, and its exit status is that of
+    /// the whole process. Nothing of watchexec is left to clean up after it,
+    /// so `teardown_cmd` doesn't run, and earlier runs still going are left
+    /// to it. This can't be used with `commands`, `readiness`, or
+    /// `paths_on_stdin`.
+    #[builder(default)]
+    pub exec_final: bool,
+
+    /// If Some, stop watching after that long.
+    ///
+    /// The handler is then given a `SIGTERM` signal event, so that commands
+    /// are stopped gracefully, see [`watch`][crate::run::watch].
+    #[builder(default)]
+    pub max_runtime: Option<Duration>,
+
+    /// Stop watching when stdin is closed.
+    ///
+    /// This is how supervising programs commonly tell a helper that they're
+    /// gone. Stopping is graceful, as for `max_runtime`. The command's own
+    /// stdin is then set to null, as watchexec is reading it.
+    #[builder(default)]
+    pub stdin_quit: bool,
+
+    /// Fork into the background before watching (Unix only).
+    ///
+    /// See the [`daemon`][crate::daemon] module for details.
+    #[builder(default)]
+    pub daemonize: bool,
+
+    /// If Some, write the PID of the watching process to that file (Unix only).
+    ///
+    /// The file is removed when watching stops, and the process can be
+    /// signalled through it with [`daemon::signal`][crate::daemon::signal].
+    #[builder(default)]
+    pub pid_file: Option<PathBuf>,
+
+    /// If Some, append output to that file when daemonized, rather than
+    /// discarding it. Requires `daemonize`.
+    #[builder(default)]
+    pub log_file: Option<PathBuf>,
+
     /// Interval to debounce the changes.
+    ///
+    /// A zero duration disables debouncing entirely: the first matching event
+    /// triggers immediately, and any further events are handled according to
+    /// `on_busy_update` as if they were a new batch.
     #[builder(default = "Duration::from_millis(100)")]
     pub debounce: Duration,
 
+    /// If Some, tell the handler how many changes are pending at most that
+    /// often while a batch is still accumulating.
+    ///
+    /// See [`Handler::on_pending`][crate::run::Handler::on_pending]. This is
+    /// for UIs to show that changes were seen during long operations.
+    #[builder(default)]
+    pub progress_interval: Option<Duration>,
+
     /// Run the commands right after starting.
     #[builder(default = "true")]
     pub run_initially: bool,
@@ -69,14 +300,52 @@ pub struct Config {
     #[builder(default)]
     pub shell: Shell,
 
+    /// Sort the paths given to handlers by directory, rather than by full path.
+    ///
+    /// Either way, batches are deduplicated and sorted before being handed off.
+    #[builder(default)]
+    pub group_by_directory: bool,
+
     /// Ignore metadata changes.
     #[builder(default)]
     pub no_meta: bool,
 
+    /// Report paths under the roots as given in `paths`, rather than as
+    /// canonicalized for watching.
+    ///
+    /// Watching resolves symlinks (and e.g. `/var` to `/private/var` on
+    /// macOS), so by default that's what handlers and the `WATCHEXEC_*`
+    /// variables see. Filtering still happens on the canonical paths.
+    #[builder(default)]
+    pub original_paths: bool,
+
+    /// If Some, shed events coming in faster than that many per second.
+    ///
+    /// Once a batch goes over the rate, the rest of its events which pass the
+    /// filters are only counted, and delivered as a single
+    /// [`BulkChange`][crate::events::Event::BulkChange] after the changes
+    /// which were kept. This bounds the work done for mass operations like
+    /// switching branches. Must be at least 1.
+    #[builder(default)]
+    pub max_event_rate: Option<u32>,
+
     /// Do not set WATCHEXEC_*_PATH environment variables for the process.
     #[builder(default)]
     pub no_environment: bool,
 
+    /// How to separate paths in the WATCHEXEC_*_PATH environment variables,
+    /// and on the command's stdin with `paths_on_stdin`.
+    #[builder(default)]
+    pub path_separator: PathSeparator,
+
+    /// Write the changed paths to the command's stdin, in full, each followed
+    /// by the `path_separator`, then close it.
+    ///
+    /// With [`PathSeparator::Nul`], this is what `xargs -0` expects. Can't
+    /// be used with `stdin_quit`.
+    #[builder(default)]
+    pub paths_on_stdin: bool,
+
     /// Skip auto-loading .gitignore files
     #[builder(default)]
     pub no_vcs_ignore: bool,
@@ -85,6 +354,15 @@ pub struct Config {
     #[builder(default)]
     pub no_ignore: bool,
 
+    /// How far above the watched paths to load ignore files from.
+    ///
+    /// By default, `.ignore` files are loaded all the way up to the
+    /// filesystem root. `.gitignore` files are never loaded from above the
+    /// repository, as git does, but with [`IgnoreSearch::None`] only those
+    /// in the watched paths are.
+    #[builder(default)]
+    pub ignore_search: IgnoreSearch,
+
     /// For testing only, always set to false.
     #[builder(setter(skip), default)]
     #[doc(hidden)]
@@ -101,11 +379,72 @@ pub struct Config {
     /// Whether to use a process group to run the command.
     #[builder(default = "true")]
     pub use_process_group: bool,
+
+    /// If Some, also get changes from a remote agent.
+    ///
+    /// See the [`remote`][crate::remote] module for details.
+    #[builder(default)]
+    pub remote_agent: Option<RemoteAgent>,
+
+    /// If Some, any change to that file triggers a run on its own.
+    ///
+    /// This is checked before filters and ignores, and the change is not
+    /// batched with others: it's delivered to the handler as a `Manual`
+    /// event, right away. Changes to it within `debounce` of the last one, as
+    /// a single `touch` makes, only run once. The file has to be within one of
+    /// the `paths`, but doesn't need to exist beforehand, so that e.g.
+    /// `touch .trigger` works.
+    #[builder(default)]
+    pub trigger_file: Option<PathBuf>,
+
+    /// If Some, also run periodically, that long after the last timed run.
+    ///
+    /// Timed runs are delivered as `Tick` events from
+    /// [`Origin::Timer`][crate::events::Origin::Timer], which handlers run
+    /// the command for by default.
+    #[builder(default)]
+    pub run_interval: Option<Duration>,
+
+    /// If Some, listen there for messages triggering runs.
+    ///
+    /// See the [`trigger`][crate::trigger] module for the protocol.
+    #[builder(default)]
+    pub trigger_listener: Option<TriggerListener>,
+
+    /// If Some, record every received event to that file.
+    ///
+    /// See the [`record`][crate::record] module for the format and replaying.
+    #[builder(default)]
+    pub record_events: Option<PathBuf>,
+
+    /// If Some, append a JSON line to that file for every batch, run, and exit.
+    ///
+    /// See the [`audit`][crate::audit] module for the format.
+    #[builder(default)]
+    pub audit_log: Option<PathBuf>,
+
+    /// Where signals come from, see [`SignalSource`].
+    ///
+    /// By default, `watch` installs a process-wide handler. Use
+    /// [`NoSignals`][crate::NoSignals] to leave signals to the host
+    /// application, or a custom source to pass on only some of them.
+    #[builder(setter(custom), default = "Arc::new(ProcessSignals)")]
+    pub signal_source: Arc<dyn SignalSource>,
+
+    /// Clock used for everything time-related in the loop.
+    ///
+    /// Only useful to change in tests, see [`crate::clock`].
+    #[builder(setter(custom), default = "Arc::new(SystemClock)")]
+    pub clock: Arc<dyn Clock>,
 }
 
 impl ConfigBuilder {
     fn validate(&self) -> Result<(), String> {
-        if self.cmd.as_ref().map_or(true, Vec::is_empty) {
+        if self.cmd.as_ref().map_or(true, Vec::is_empty)
+            && !matches!(self.container, Some(Some(_)))
+            && self.commands.as_ref().map_or(true, Vec::is_empty)
+            && self.no_command != Some(true)
+        {
             return Err("cmd must not be empty".into());
         }
 
@@ -113,9 +452,182 @@ impl ConfigBuilder {
             return Err("paths must not be empty".into());
         }
 
+        if self.max_runs == Some(Some(0)) {
+            return Err("max_runs must be at least 1".into());
+        }
+
+        if let Some(Some(ref name)) = self.signal {
+            crate::signal::parse(name).map_err(|_| format!("unsupported signal: {}", name))?;
+        }
+
+        if matches!(self.resource_limits, Some(Some(_))) && !crate::resources::is_supported() {
+            return Err("resource_limits are only supported on Linux".into());
+        }
+
+        if self.paths_on_stdin == Some(true) && self.stdin_quit == Some(true) {
+            return Err("paths_on_stdin can't be used with stdin_quit".into());
+        }
+
+        if self.max_event_rate == Some(Some(0)) {
+            return Err("max_event_rate must be at least 1".into());
+        }
+
+        if self.max_concurrent == Some(0) {
+            return Err("max_concurrent must be at least 1".into());
+        }
+
+        if matches!(
+            self.queue_policy,
+            Some(QueuePolicy::Cap(0)) | Some(QueuePolicy::DropOldest(0))
+        ) {
+            return Err("queue_policy must keep at least 1 batch".into());
+        }
+
+        if cfg!(not(unix)) && matches!(self.container, Some(Some(_))) {
+            return Err("container is only supported on Unix".into());
+        }
+
+        if self.exec_final == Some(true) {
+            if cfg!(not(unix)) {
+                return Err("exec_final is only supported on Unix".into());
+            }
+
+            if !matches!(self.max_runs, Some(Some(_))) {
+                return Err("exec_final requires max_runs, for a final run".into());
+            }
+
+            if matches!(self.commands, Some(ref commands) if !commands.is_empty())
+                || matches!(self.readiness, Some(Some(_)))
+                || self.paths_on_stdin == Some(true)
+            {
+                return Err(
+                    "exec_final cannot be used with commands, readiness, or paths_on_stdin".into(),
+                );
+            }
+        }
+
+        let daemonize = self.daemonize == Some(true);
+        if cfg!(not(unix)) && (daemonize || matches!(self.pid_file, Some(Some(_)))) {
+            return Err("daemonize and pid_file are only supported on Unix".into());
+        }
+
+        if !daemonize && matches!(self.log_file, Some(Some(_))) {
+            return Err("log_file requires daemonize".into());
+        }
+
         Ok(())
     }
 
+    /// Start from the settings in environment variables named with `prefix`.
+    ///
+    /// With e.g. `WATCHEXEC` as prefix, these are read:
+    ///
+    /// - `WATCHEXEC_PATHS`, `WATCHEXEC_FILTERS`, and `WATCHEXEC_IGNORES`:
+    ///   lists, separated like `PATH` (by `:` on Unix, `;` on Windows)
+    /// - `WATCHEXEC_COMMAND`: the command, given as-is to the shell
+    /// - `WATCHEXEC_DEBOUNCE`: in milliseconds
+    /// - `WATCHEXEC_SHELL`: `none`, `powershell`, `cmd` (Windows), or a Unix
+    ///   shell invocation, as for [`Shell::Unix`]
+    /// - `WATCHEXEC_ON_BUSY_UPDATE`: `do-nothing`, `queue`, `restart`, or
+    ///   `signal`
+    ///
+    /// Unset or empty variables are left to the defaults. Anything set on the
+    /// builder afterwards takes precedence.
+    pub fn from_env(prefix: &str) -> WResult<Self> {
+        Self::from_vars(prefix, env::var_os)
+    }
+
+    fn from_vars<F>(prefix: &str, var: F) -> WResult<Self>
+    where
+        F: Fn(String) -> Option<OsString>,
+    {
+        let mut builder = Self::default();
+        let get = |name: &str| {
+            let name = format!("{}_{}", prefix, name);
+            var(name.clone())
+                .filter(|value| !value.is_empty())
+                .map(|value| (name, value))
+        };
+        let string = |name: String, value: OsString| -> WResult<String> {
+            value
+                .into_string()
+                .map_err(|_| Error::Generic(format!("{} is not valid unicode", name)))
+        };
+        let list = |name: String, value: &OsStr| -> WResult<Vec<String>> {
+            env::split_paths(value)
+                .map(|item| string(name.clone(), item.into_os_string()))
+                .collect()
+        };
+
+        if let Some((_, value)) = get("PATHS") {
+            builder.paths(env::split_paths(&value).collect::<Vec<_>>());
+        }
+
+        if let Some((name, value)) = get("FILTERS") {
+            builder.filters(list(name, &value)?);
+        }
+
+        if let Some((name, value)) = get("IGNORES") {
+            builder.ignores(list(name, &value)?);
+        }
+
+        if let Some((name, value)) = get("COMMAND") {
+            builder.cmd(vec![string(name, value)?]);
+        }
+
+        if let Some((name, value)) = get("DEBOUNCE") {
+            let value = string(name.clone(), value)?;
+            let millis = value.parse().map_err(|_| {
+                Error::Generic(format!(
+                    "{} is not a number of milliseconds: {}",
+                    name, value
+                ))
+            })?;
+            builder.debounce(Duration::from_millis(millis));
+        }
+
+        if let Some((name, value)) = get("SHELL") {
+            let value = string(name, value)?;
+            builder.shell(match value.to_ascii_lowercase().as_str() {
+                "none" => Shell::None,
+                "powershell" => Shell::Powershell,
+                #[cfg(windows)]
+                "cmd" => Shell::Cmd,
+                _ => Shell::Unix(value),
+            });
+        }
+
+        if let Some((name, value)) = get("ON_BUSY_UPDATE") {
+            let value = string(name.clone(), value)?;
+            builder.on_busy_update(match value.as_str() {
+                "do-nothing" => OnBusyUpdate::DoNothing,
+                "queue" => OnBusyUpdate::Queue,
+                "restart" => OnBusyUpdate::Restart,
+                "signal" => OnBusyUpdate::Signal,
+                _ => {
+                    return Err(Error::Generic(format!(
+                        "{} must be do-nothing, queue, restart, or signal, not {}",
+                        name, value
+                    )))
+                }
+            });
+        }
+
+        Ok(builder)
+    }
+
+    /// Get signals from somewhere else than the process-wide handler.
+    pub fn signal_source(&mut self, source: impl SignalSource + 'static) -> &mut Self {
+        self.signal_source = Some(Arc::new(source));
+        self
+    }
+
+    /// Use a different clock, e.g. a [`MockClock`][crate::testing::MockClock].
+    pub fn clock(&mut self, clock: impl Clock + 'static) -> &mut Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
     #[deprecated(since = "1.15.0", note = "does nothing. set the log level instead")]
     pub fn debug(&mut self, _: impl Into<bool>) -> &mut Self {
         self
@@ -149,3 +661,95 @@ impl ConfigBuilder {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ConfigBuilder;
+    use crate::run::OnBusyUpdate;
+    use crate::Shell;
+    use std::{collections::HashMap, env, ffi::OsString, path::PathBuf, time::Duration};
+
+    fn from_vars(vars: &[(&str, &str)]) -> crate::error::Result<ConfigBuilder> {
+        let vars: HashMap<String, OsString> = vars
+            .iter()
+            .map(|(name, value)| ((*name).to_string(), OsString::from(value)))
+            .collect();
+        ConfigBuilder::from_vars("APP_WATCH", |name| vars.get(&name).cloned())
+    }
+
+    #[test]
+    fn reads_env() {
+        let paths = env::join_paths(["src", "tests"].iter()).expect("joinable");
+        let filters = env::join_paths(["*.rs", "*.toml"].iter()).expect("joinable");
+        let config = from_vars(&[
+            ("APP_WATCH_PATHS", paths.to_str().expect("unicode")),
+            ("APP_WATCH_FILTERS", filters.to_str().expect("unicode")),
+            ("APP_WATCH_IGNORES", ""),
+            ("APP_WATCH_COMMAND", "cargo test"),
+            ("APP_WATCH_DEBOUNCE", "250"),
+            ("APP_WATCH_SHELL", "bash"),
+            ("APP_WATCH_ON_BUSY_UPDATE", "restart"),
+        ])
+        .expect("valid vars")
+        .build()
+        .expect("valid config");
+
+        assert_eq!(
+            config.paths,
+            vec![PathBuf::from("src"), PathBuf::from("tests")]
+        );
+        assert_eq!(
+            config.filters,
+            vec!["*.rs".to_string(), "*.toml".to_string()]
+        );
+        assert!(config.ignores.is_empty());
+        assert_eq!(config.cmd, vec!["cargo test".to_string()]);
+        assert_eq!(config.debounce, Duration::from_millis(250));
+        assert_eq!(config.shell, Shell::Unix("bash".into()));
+        assert!(matches!(config.on_busy_update, OnBusyUpdate::Restart));
+    }
+
+    #[test]
+    fn builder_overrides_env() {
+        let config = from_vars(&[("APP_WATCH_PATHS", "src"), ("APP_WATCH_COMMAND", "make")])
+            .expect("valid vars")
+            .cmd(vec!["make test".into()])
+            .build()
+            .expect("valid config");
+
+        assert_eq!(config.paths, vec![PathBuf::from("src")]);
+        assert_eq!(config.cmd, vec!["make test".to_string()]);
+    }
+
+    #[test]
+    fn needs_a_command_unless_allowed() {
+        let mut builder = ConfigBuilder::default();
+        builder.paths(vec![".".into()]);
+        assert!(builder.build().is_err());
+
+        builder.no_command(true);
+        assert!(builder.build().expect("valid config").cmd.is_empty());
+    }
+
+    #[test]
+    fn exec_final_needs_a_final_run() {
+        let mut builder = ConfigBuilder::default();
+        builder
+            .paths(vec![".".into()])
+            .cmd(vec!["true".into()])
+            .exec_final(true);
+        assert!(builder.build().is_err());
+
+        builder.max_runs(1_u64);
+        assert_eq!(builder.build().is_ok(), cfg!(unix));
+
+        builder.paths_on_stdin(true);
+        assert!(builder.build().is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_env() {
+        assert!(from_vars(&[("APP_WATCH_DEBOUNCE", "soon")]).is_err());
+        assert!(from_vars(&[("APP_WATCH_ON_BUSY_UPDATE", "panic")]).is_err());
+    }
+}
@@ -0,0 +1,254 @@
+//! Running in the background, on Unix.
+//!
+//! With [`Config.daemonize`][crate::config::Config], [`watch`][crate::run::watch]
+//! first forks into the background: the calling process exits once the
+//! daemon is watching, so that no change made after it returns is missed,
+//! and the daemon's stdin is set to null while its stdout and
+//! stderr go to `log_file` (or to null). The working directory is kept, as
+//! paths and the command may be relative to it.
+//!
+//! With `pid_file`, the PID of the watching process is written there for as
+//! long as it watches, so that other processes can find it, and e.g. stop it
+//! with [`signal`].
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::io::{AsRawFd, FromRawFd},
+    path::{Path, PathBuf},
+    process,
+};
+
+use log::{debug, error};
+use nix::{
+    sys::signal::kill,
+    unistd::{dup2, fork, pipe, setsid, ForkResult, Pid},
+};
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::signal::Signal;
+
+/// A file holding the PID of this process, removed when dropped.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    pid: u32,
+}
+
+impl PidFile {
+    /// Write the PID of this process to `path`.
+    ///
+    /// Fails if the file already exists and names a running process. A file
+    /// left over by a process which is gone is overwritten.
+    pub fn create(path: &Path) -> Result<Self> {
+        if let Ok(pid) = read_pid(path) {
+            if is_running(pid) {
+                return Err(Error::Generic(format!(
+                    "process {} from PID file {:?} is still running",
+                    pid, path
+                )));
+            }
+        }
+
+        let pid = process::id();
+        fs::write(path, format!("{}\n", pid))?;
+        debug!("Wrote PID {} to {:?}", pid, path);
+
+        Ok(Self {
+            path: path.into(),
+            pid,
+        })
+    }
+
+    /// The path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Leave it alone if another process has taken it over since
+        if matches!(read_pid(&self.path), Ok(pid) if pid == self.pid) {
+            fs::remove_file(&self.path).ok();
+        }
+    }
+}
+
+/// Read the PID from a PID file.
+pub fn read_pid(path: &Path) -> Result<u32> {
+    let contents = fs::read_to_string(path)?;
+    contents
+        .trim()
+        .parse()
+        .map_err(|_| Error::Generic(format!("invalid PID file {:?}", path)))
+}
+
+/// Send a signal to the process named in a PID file, e.g. to stop a daemon.
+pub fn signal(pid_file: &Path, sig: Signal) -> Result<()> {
+    let pid = read_pid(pid_file)?;
+    debug!("Sending {} to process {} from {:?}", sig, pid, pid_file);
+    kill(to_pid(pid), sig).map_err(io::Error::from)?;
+    Ok(())
+}
+
+/// Whether a process with that PID exists.
+fn is_running(pid: u32) -> bool {
+    // Signal "0" only checks for existence; EPERM means it's someone else's
+    match kill(to_pid(pid), None) {
+        Ok(()) => true,
+        Err(err) => io::Error::from(err).raw_os_error() == Some(nix::libc::EPERM),
+    }
+}
+
+#[allow(clippy::cast_possible_wrap)]
+fn to_pid(pid: u32) -> Pid {
+    Pid::from_raw(pid as i32)
+}
+
+/// A daemon which has yet to report that it started successfully.
+///
+/// The original process waits until [`ready`][Daemon::ready] is called, and
+/// exits with an error if the daemon drops this beforehand.
+#[derive(Debug)]
+pub struct Daemon {
+    ready: File,
+}
+
+impl Daemon {
+    /// Let the original process exit successfully.
+    pub fn ready(mut self) {
+        self.ready.write_all(&[0]).ok();
+    }
+}
+
+/// Fork into the background, returning in the daemon process only.
+///
+/// The daemon is in a new session, without a controlling terminal. Its
+/// stdin is null, and its stdout and stderr are appended to `log_file` if
+/// given, or else discarded.
+///
+/// As with any fork, only the calling thread carries on in the daemon, so
+/// this should be called before any threads are started.
+#[allow(unsafe_code)]
+pub fn daemonize(log_file: Option<&Path>) -> Result<Daemon> {
+    // Open these before forking, so errors are reported to the caller
+    let output = match log_file {
+        Some(path) => OpenOptions::new().create(true).append(true).open(path)?,
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    let null = File::open("/dev/null")?;
+
+    let (ready_rx, ready_tx) = pipe().map_err(io::Error::from)?;
+    let (mut ready_rx, ready_tx) =
+        unsafe { (File::from_raw_fd(ready_rx), File::from_raw_fd(ready_tx)) };
+
+    if let ForkResult::Parent { .. } = unsafe { fork() }.map_err(io::Error::from)? {
+        drop(ready_tx);
+        let mut byte = [0];
+        if ready_rx.read_exact(&mut byte).is_ok() {
+            process::exit(0);
+        }
+
+        error!("The daemon failed to start, see its log for details");
+        process::exit(1);
+    }
+
+    // Only the original process may return an error: a forked one would
+    // carry on as if it were the caller
+    drop(ready_rx);
+    if let Err(err) = detach(&null, &output) {
+        error!("Failed to daemonize: {}", err);
+        unsafe { nix::libc::_exit(1) };
+    }
+
+    Ok(Daemon { ready: ready_tx })
+}
+
+/// The forked side of `daemonize`, returning in the daemon process only.
+#[allow(unsafe_code)]
+fn detach(null: &File, output: &File) -> io::Result<()> {
+    setsid().map_err(io::Error::from)?;
+
+    // Fork again so the daemon isn't a session leader, and so can never
+    // acquire a controlling terminal
+    if let ForkResult::Parent { .. } = unsafe { fork() }.map_err(io::Error::from)? {
+        unsafe { nix::libc::_exit(0) };
+    }
+
+    dup2(null.as_raw_fd(), io::stdin().as_raw_fd()).map_err(io::Error::from)?;
+    dup2(output.as_raw_fd(), io::stdout().as_raw_fd()).map_err(io::Error::from)?;
+    dup2(output.as_raw_fd(), io::stderr().as_raw_fd()).map_err(io::Error::from)?;
+
+    Ok(())
+}
+
+/// Daemonize and write the PID file, as configured.
+///
+/// The daemon, if any, is to be told it's ready once watching has started.
+pub(crate) fn start(args: &Config) -> Result<(Option<PidFile>, Option<Daemon>)> {
+    let daemon = if args.daemonize {
+        Some(daemonize(args.log_file.as_deref())?)
+    } else {
+        None
+    };
+
+    let pid_file = match args.pid_file {
+        Some(ref path) => Some(PidFile::create(path)?),
+        None => None,
+    };
+
+    Ok((pid_file, daemon))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_pid, signal, PidFile};
+    use crate::signal::Signal;
+    use std::{env, fs, os::unix::process::ExitStatusExt, path::PathBuf, process};
+
+    fn pid_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("watchexec-{}-{}.pid", name, process::id()))
+    }
+
+    #[test]
+    fn pid_file_lifecycle() {
+        let path = pid_path("lifecycle");
+        let pid_file = PidFile::create(&path).expect("pid file");
+        assert_eq!(read_pid(&path).expect("valid pid"), process::id());
+
+        // This process is running, so it can't be started twice
+        assert!(PidFile::create(&path).is_err());
+
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn stale_pid_file_is_replaced() {
+        let mut child = process::Command::new("true").spawn().expect("spawn");
+        child.wait().expect("wait");
+
+        let path = pid_path("stale");
+        fs::write(&path, child.id().to_string()).expect("write");
+        let _pid_file = PidFile::create(&path).expect("pid file");
+        assert_eq!(read_pid(&path).expect("valid pid"), process::id());
+    }
+
+    #[test]
+    fn signals_by_pid_file() {
+        let mut child = process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .expect("spawn");
+
+        let path = pid_path("signal");
+        fs::write(&path, child.id().to_string()).expect("write");
+        signal(&path, Signal::SIGTERM).expect("signal");
+        fs::remove_file(&path).ok();
+
+        let status = child.wait().expect("wait");
+        assert_eq!(status.signal(), Some(Signal::SIGTERM as i32));
+    }
+}
@@ -0,0 +1,479 @@
+//! Event batching, usable independently of [`watch`][crate::run::watch].
+//!
+//! # Examples
+//!
+//! ```
+//! # use std::{path::{Path, PathBuf}, sync::mpsc::channel, time::Duration};
+//! # use watchexec::{debounce::Debouncer, watcher::Event};
+//! let (tx, rx) = channel();
+//! tx.send(Event {
+//!     path: Some(PathBuf::from("/src/main.rs")),
+//!     op: Ok(notify::op::WRITE),
+//!     cookie: None,
+//! }).expect("receiver is alive");
+//!
+//! let exclude_git = |path: &Path| path.starts_with("/src/.git");
+//! let mut debouncer = Debouncer::new(rx, exclude_git, Duration::from_millis(50));
+//! let batch = debouncer.next_batch().expect("sender is alive");
+//! assert_eq!(batch[0].path, PathBuf::from("/src/main.rs"));
+//! ```
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{mpsc::Receiver, Arc},
+    time::{Duration, Instant},
+};
+
+use crate::clock::{Clock, SystemClock};
+use crate::pathop::PathOp;
+use crate::watcher::Event;
+
+/// Where a [`Debouncer`] gets its events from.
+///
+/// This is implemented for `Receiver<Event>`, which is what the watcher uses.
+/// Other implementations can decide for themselves what a timeout means, which
+/// is how [`testing::MockWatcher`][crate::testing::MockWatcher] delivers exact
+/// batches without waiting.
+pub trait Source {
+    /// Block until the next event, or return `None` if there will be none.
+    fn recv(&mut self) -> Option<Event>;
+
+    /// Wait at most `timeout` for the next event.
+    ///
+    /// Returns `None` if the timeout elapsed or if there will be no more events.
+    fn recv_timeout(&mut self, timeout: Duration) -> Option<Event>;
+}
+
+impl Source for Receiver<Event> {
+    fn recv(&mut self) -> Option<Event> {
+        Receiver::recv(self).ok()
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> Option<Event> {
+        Receiver::recv_timeout(self, timeout).ok()
+    }
+}
+
+/// Collects events from a channel (or any other [`Source`]) into batches.
+///
+/// Each batch starts with the first event which is not excluded by the filter,
+/// and ends once no new event has arrived for the debounce duration. Events
+/// for the same path and op are only considered once per batch, but the last
+/// time each change kept was received is noted, see
+/// [`received`][Debouncer::received].
+///
+/// The filter is any `Fn(&Path) -> bool` which returns `true` for paths which
+/// should be excluded.
+///
+/// With a [`max_rate`][Debouncer::max_rate], events beyond that many per
+/// second are shed for the rest of the batch: those the filter lets through
+/// are only counted, without being kept or cached, see
+/// [`shed`][Debouncer::shed].
+pub struct Debouncer<F, S = Receiver<Event>> {
+    source: S,
+    filter: F,
+    debounce: Duration,
+    no_meta: bool,
+    max_rate: Option<u32>,
+    progress: Option<Duration>,
+    clock: Arc<dyn Clock>,
+    shed: usize,
+    received: HashMap<PathOp, Instant>,
+}
+
+impl<F, S> Debouncer<F, S>
+where
+    F: Fn(&Path) -> bool,
+    S: Source,
+{
+    /// Create a debouncer reading from `source`.
+    ///
+    /// A zero `debounce` duration makes every batch end right after its first
+    /// event, see [`Config.debounce`][crate::config::Config].
+    pub fn new(source: S, filter: F, debounce: Duration) -> Self {
+        Self {
+            source,
+            filter,
+            debounce,
+            no_meta: false,
+            max_rate: None,
+            progress: None,
+            clock: Arc::new(SystemClock),
+            shed: 0,
+            received: HashMap::new(),
+        }
+    }
+
+    /// Ignore metadata changes.
+    pub fn no_meta(mut self, no_meta: bool) -> Self {
+        self.no_meta = no_meta;
+        self
+    }
+
+    /// Shed events beyond that many per second, see
+    /// [`Config.max_event_rate`][crate::config::Config].
+    pub fn max_rate(mut self, max_rate: Option<u32>) -> Self {
+        self.max_rate = max_rate;
+        self
+    }
+
+    /// Report progress at most that often while a batch accumulates, see
+    /// [`next_batch_reporting`][Debouncer::next_batch_reporting].
+    pub fn progress(mut self, every: Option<Duration>) -> Self {
+        self.progress = every;
+        self
+    }
+
+    /// Measure the event rate and progress with a different clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// How many events were shed from the last batch, see
+    /// [`max_rate`][Debouncer::max_rate].
+    pub const fn shed(&self) -> usize {
+        self.shed
+    }
+
+    /// When a change of the last batch was last received, by the clock.
+    pub fn received(&self, pathop: &PathOp) -> Option<Instant> {
+        self.received.get(pathop).copied()
+    }
+
+    /// Count an event in the rate window, returning whether it's over the rate.
+    fn over_rate(&self, window: &mut (Instant, u32)) -> bool {
+        let max = match self.max_rate {
+            Some(max) => max,
+            None => return false,
+        };
+
+        if self.clock.since(window.0) >= Duration::from_secs(1) {
+            *window = (self.clock.now(), 0);
+        }
+
+        window.1 += 1;
+        window.1 > max
+    }
+
+    /// Note a change as received now, adding it to the batch unless it's
+    /// already there, at index `at`. Returns where it is.
+    fn keep(&mut self, paths: &mut Vec<PathOp>, at: Option<usize>, pathop: &PathOp) -> usize {
+        self.received.insert(pathop.clone(), self.clock.now());
+        match at {
+            Some(at) => at,
+            None => {
+                paths.push(pathop.clone());
+                paths.len() - 1
+            }
+        }
+    }
+
+    /// Block until the next batch is available.
+    ///
+    /// Returns `None` once the source is exhausted, e.g. when all senders for
+    /// the channel are gone.
+    pub fn next_batch(&mut self) -> Option<Vec<PathOp>> {
+        self.next_batch_with(|_| {})
+    }
+
+    /// Block until the next batch is available, calling `on_raw` with every
+    /// event received, before it is filtered.
+    ///
+    /// Returns `None` once the source is exhausted.
+    pub fn next_batch_with<R>(&mut self, mut on_raw: R) -> Option<Vec<PathOp>>
+    where
+        R: FnMut(&Event),
+    {
+        self.next_batch_until(|e| {
+            on_raw(e);
+            true
+        })
+    }
+
+    /// Like [`next_batch_with`][Debouncer::next_batch_with], but `on_raw`
+    /// returns whether to keep waiting.
+    ///
+    /// When it returns `false`, the event is still considered, but the batch
+    /// ends right away. The batch may then be empty. This is used to wake the
+    /// watch loop for things other than filesystem changes.
+    pub fn next_batch_until<R>(&mut self, on_raw: R) -> Option<Vec<PathOp>>
+    where
+        R: FnMut(&Event) -> bool,
+    {
+        self.next_batch_reporting(on_raw, |_| {})
+    }
+
+    /// Like [`next_batch_until`][Debouncer::next_batch_until], but also calls
+    /// `on_progress` with the number of changes kept so far while the batch
+    /// is still accumulating.
+    ///
+    /// This happens as events come in, at most once per
+    /// [`progress`][Debouncer::progress] interval, and never if it isn't set.
+    pub fn next_batch_reporting<R, P>(
+        &mut self,
+        mut on_raw: R,
+        mut on_progress: P,
+    ) -> Option<Vec<PathOp>>
+    where
+        R: FnMut(&Event) -> bool,
+        P: FnMut(usize),
+    {
+        let mut paths = Vec::new();
+        // Where each change is in the batch, or None if it's excluded
+        let mut cache: HashMap<PathOp, Option<usize>> = HashMap::new();
+        self.shed = 0;
+        self.received.clear();
+
+        loop {
+            let e = self.source.recv()?;
+            let keep_waiting = on_raw(&e);
+
+            if let Some(ref path) = e.path {
+                let pathop = PathOp::new(path, e.op.ok(), e.cookie);
+                let is_meta = matches!(pathop.op, Some(op) if PathOp::is_meta(op));
+                if !(self.no_meta && is_meta) {
+                    // Ignore cache for the initial file. Otherwise, in
+                    // debug mode it's hard to track what's going on
+                    let excluded = (self.filter)(path);
+                    if excluded {
+                        cache.entry(pathop).or_insert(None);
+                    } else {
+                        let at =
+                            self.keep(&mut paths, cache.get(&pathop).copied().flatten(), &pathop);
+                        cache.insert(pathop, Some(at));
+                        if keep_waiting {
+                            break;
+                        }
+                    }
+                }
+            }
+
+            if !keep_waiting {
+                return Some(paths);
+            }
+        }
+
+        // Zero debounce: trigger on the first event, anything arriving after
+        // that is left for the next call (and thus to the on_busy_update policy)
+        if self.debounce == Duration::from_secs(0) {
+            return Some(paths);
+        }
+
+        // Wait for filesystem activity to cool off. Past the maximum rate,
+        // events are only counted, until the end of the batch.
+        let mut window = (self.clock.now(), 1);
+        let mut reported = self.clock.now();
+        while let Some(e) = self.source.recv_timeout(self.debounce) {
+            let keep_waiting = on_raw(&e);
+            if let Some(ref path) = e.path {
+                let pathop = PathOp::new(path, e.op.ok(), e.cookie);
+                let cached = cache.get(&pathop).copied();
+                let excluded = match cached {
+                    Some(at) => at.is_none(),
+                    None => (self.filter)(path),
+                };
+
+                if excluded {
+                    // Once shedding, the cache stops growing, so a storm of
+                    // new paths only costs the filter
+                    if self.shed == 0 {
+                        cache.insert(pathop, None);
+                    }
+                } else if self.shed > 0 || self.over_rate(&mut window) {
+                    self.shed += 1;
+                } else {
+                    let at = self.keep(&mut paths, cached.flatten(), &pathop);
+                    cache.insert(pathop, Some(at));
+                }
+            }
+
+            if let Some(every) = self.progress {
+                if self.clock.since(reported) >= every {
+                    on_progress(paths.len());
+                    reported = self.clock.now();
+                }
+            }
+
+            if !keep_waiting {
+                break;
+            }
+        }
+
+        Some(paths)
+    }
+}
+
+impl<F, S> Iterator for Debouncer<F, S>
+where
+    F: Fn(&Path) -> bool,
+    S: Source,
+{
+    type Item = Vec<PathOp>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_batch()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Debouncer;
+    use crate::watcher::Event;
+    use std::{
+        cell::Cell,
+        path::{Path, PathBuf},
+        sync::mpsc::channel,
+        time::Duration,
+    };
+
+    fn event(path: &str) -> Event {
+        Event {
+            path: Some(PathBuf::from(path)),
+            op: Ok(notify::op::WRITE),
+            cookie: None,
+        }
+    }
+
+    fn allow_all(_: &Path) -> bool {
+        false
+    }
+
+    #[test]
+    fn debounce_collects_burst() {
+        let (tx, rx) = channel();
+        tx.send(event("/a")).expect("send");
+        tx.send(event("/b")).expect("send");
+
+        let mut debouncer = Debouncer::new(rx, allow_all, Duration::from_millis(10));
+        let paths = debouncer.next_batch().expect("batch");
+        assert_eq!(paths.len(), 2);
+    }
+
+    #[test]
+    fn zero_debounce_returns_first_event() {
+        let (tx, rx) = channel();
+        tx.send(event("/a")).expect("send");
+        tx.send(event("/b")).expect("send");
+
+        let mut debouncer = Debouncer::new(rx, allow_all, Duration::from_secs(0));
+        let paths = debouncer.next_batch().expect("batch");
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0].path, PathBuf::from("/a"));
+
+        let paths = debouncer.next_batch().expect("batch");
+        assert_eq!(paths[0].path, PathBuf::from("/b"));
+    }
+
+    #[test]
+    fn raw_events_bypass_filter() {
+        let (tx, rx) = channel();
+        tx.send(event("/a.rs")).expect("send");
+        tx.send(event("/b.txt")).expect("send");
+        tx.send(event("/c.rs")).expect("send");
+
+        let mut debouncer = Debouncer::new(
+            rx,
+            |path: &Path| path.extension() == Some("rs".as_ref()),
+            Duration::from_millis(10),
+        );
+
+        let seen = Cell::new(0);
+        let paths = debouncer
+            .next_batch_with(|_| seen.set(seen.get() + 1))
+            .expect("batch");
+        assert_eq!(paths.len(), 1);
+        assert_eq!(seen.get(), 3);
+    }
+
+    #[test]
+    fn woken_batch_ends_early() {
+        let (tx, rx) = channel();
+        tx.send(event("/a")).expect("send");
+        tx.send(Event {
+            path: None,
+            op: Ok(notify::op::Op::empty()),
+            cookie: None,
+        })
+        .expect("send");
+        tx.send(event("/b")).expect("send");
+
+        let mut debouncer = Debouncer::new(rx, allow_all, Duration::from_secs(60));
+        let paths = debouncer
+            .next_batch_until(|e| e.path.is_some())
+            .expect("batch");
+        assert_eq!(paths.len(), 1);
+
+        let paths = debouncer
+            .next_batch_until(|e| e.path.as_deref() != Some(Path::new("/b")))
+            .expect("batch");
+        assert_eq!(paths[0].path, PathBuf::from("/b"));
+    }
+
+    #[test]
+    fn sheds_events_over_the_rate() {
+        let (tx, rx) = channel();
+        for i in 0..10 {
+            tx.send(event(&format!("/{}", i))).expect("send");
+        }
+
+        let mut debouncer =
+            Debouncer::new(rx, allow_all, Duration::from_millis(10)).max_rate(Some(3));
+        let paths = debouncer.next_batch().expect("batch");
+        assert_eq!(paths.len(), 3);
+        assert_eq!(debouncer.shed(), 7);
+
+        tx.send(event("/a")).expect("send");
+        let paths = debouncer.next_batch().expect("batch");
+        assert_eq!(paths.len(), 1);
+        assert_eq!(debouncer.shed(), 0);
+    }
+
+    #[test]
+    fn only_sheds_events_passing_the_filter() {
+        let (tx, rx) = channel();
+        for i in 0..10 {
+            tx.send(event(&format!("/{}.rs", i))).expect("send");
+            tx.send(event(&format!("/{}.txt", i))).expect("send");
+        }
+
+        let mut debouncer = Debouncer::new(
+            rx,
+            |path: &Path| path.extension() == Some("txt".as_ref()),
+            Duration::from_millis(10),
+        )
+        .max_rate(Some(3));
+        let paths = debouncer.next_batch().expect("batch");
+        assert_eq!(paths.len(), 3);
+        assert_eq!(debouncer.shed(), 7);
+    }
+
+    #[test]
+    fn reports_progress() {
+        let (tx, rx) = channel();
+        for path in &["/a", "/b", "/a", "/c"] {
+            tx.send(event(path)).expect("send");
+        }
+
+        let mut debouncer = Debouncer::new(rx, allow_all, Duration::from_millis(10))
+            .progress(Some(Duration::from_secs(0)));
+        let mut reports = Vec::new();
+        let paths = debouncer
+            .next_batch_reporting(|_| true, |pending| reports.push(pending))
+            .expect("batch");
+        assert_eq!(paths.len(), 3);
+        assert_eq!(reports, vec![2, 2, 3]);
+    }
+
+    #[test]
+    fn ends_when_senders_are_gone() {
+        let (tx, rx) = channel();
+        tx.send(event("/a")).expect("send");
+        drop(tx);
+
+        let mut debouncer = Debouncer::new(rx, allow_all, Duration::from_millis(10));
+        assert_eq!(debouncer.next_batch().map(|b| b.len()), Some(1));
+        assert!(debouncer.next_batch().is_none());
+    }
+}
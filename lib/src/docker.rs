@@ -0,0 +1,188 @@
+//! Acting on Docker containers, on Unix.
+//!
+//! When [`Config.container`][crate::config::Config] is set, the
+//! [`ExecHandler`][crate::run::ExecHandler] restarts or signals that container
+//! on every run, according to `container_action`. Without a command, that's
+//! all it does. With a command, it waits for it to finish and only acts on the
+//! container if it succeeded, e.g. to restart a service after a build.
+//!
+//! This talks to the Docker Engine API directly over its Unix socket: the one
+//! in `DOCKER_HOST` if that's a `unix://` address, else the default one.
+
+use std::{
+    env,
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+
+use crate::error::{Error, Result};
+use crate::run::ContainerAction;
+
+const DEFAULT_SOCKET: &str = "/var/run/docker.sock";
+
+/// A client for the Docker Engine API.
+#[derive(Clone, Debug)]
+pub struct Docker {
+    socket: PathBuf,
+}
+
+impl Docker {
+    /// Use the Docker socket at `socket`.
+    pub fn new(socket: impl Into<PathBuf>) -> Self {
+        Self {
+            socket: socket.into(),
+        }
+    }
+
+    /// Use the Docker socket from the environment, as the docker CLI does.
+    pub fn from_env() -> Self {
+        let socket = env::var("DOCKER_HOST")
+            .ok()
+            .filter(|host| host.starts_with("unix://"))
+            .map(|host| PathBuf::from(&host[7..]))
+            .unwrap_or_else(|| DEFAULT_SOCKET.into());
+        Self::new(socket)
+    }
+
+    /// The path of the socket in use.
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    /// Act on a container, by name or ID.
+    pub fn act(&self, container: &str, action: &ContainerAction) -> Result<()> {
+        let container = encode(container);
+        let path = match action {
+            ContainerAction::Restart => format!("/containers/{}/restart", container),
+            ContainerAction::Signal(sig) => {
+                format!("/containers/{}/kill?signal={}", container, encode(sig))
+            }
+        };
+
+        self.post(&path)
+    }
+
+    fn post(&self, path: &str) -> Result<()> {
+        debug!("Docker API request: POST {}", path);
+        let mut stream = UnixStream::connect(&self.socket).map_err(|err| {
+            Error::Generic(format!(
+                "could not connect to Docker at {:?}: {}",
+                self.socket, err
+            ))
+        })?;
+
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: docker\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            path
+        )?;
+
+        let mut response = BufReader::new(stream);
+        let mut status_line = String::new();
+        response.read_line(&mut status_line)?;
+        let status: u16 = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| {
+                Error::Generic(format!("invalid Docker API response: {:?}", status_line))
+            })?;
+
+        if (200..300).contains(&status) {
+            return Ok(());
+        }
+
+        // Errors come with a JSON body holding a message; show it as is
+        let mut rest = String::new();
+        response.read_to_string(&mut rest)?;
+        let body = rest.split("\r\n\r\n").nth(1).unwrap_or_default().trim();
+        Err(Error::Generic(format!(
+            "Docker API error {} for {}: {}",
+            status, path, body
+        )))
+    }
+}
+
+/// Percent-encode anything which isn't safe in a URL path or query.
+fn encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.".contains(&byte) {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, Docker};
+    use crate::run::ContainerAction;
+    use std::{
+        env, fs,
+        io::{BufRead, BufReader, Write},
+        os::unix::net::UnixListener,
+        process, thread,
+    };
+
+    /// Answer one request on a fake socket, returning the request line.
+    fn fake_docker(name: &str, response: &'static str) -> (Docker, thread::JoinHandle<String>) {
+        let socket =
+            env::temp_dir().join(format!("watchexec-docker-{}-{}.sock", name, process::id()));
+        fs::remove_file(&socket).ok();
+        let listener = UnixListener::bind(&socket).expect("bind");
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("accept");
+            let mut request = String::new();
+            BufReader::new(&stream)
+                .read_line(&mut request)
+                .expect("read");
+            stream.write_all(response.as_bytes()).expect("write");
+            request
+        });
+
+        (Docker::new(socket), server)
+    }
+
+    #[test]
+    fn encodes_names() {
+        assert_eq!(encode("my_app-1.web"), "my_app-1.web");
+        assert_eq!(encode("a/b c"), "a%2Fb%20c");
+    }
+
+    #[test]
+    fn restarts() {
+        let (docker, server) = fake_docker("restart", "HTTP/1.1 204 No Content\r\n\r\n");
+        docker
+            .act("web", &ContainerAction::Restart)
+            .expect("restarted");
+        assert_eq!(
+            server.join().expect("server"),
+            "POST /containers/web/restart HTTP/1.1\r\n"
+        );
+        fs::remove_file(docker.socket()).ok();
+    }
+
+    #[test]
+    fn reports_errors() {
+        let (docker, server) = fake_docker(
+            "error",
+            "HTTP/1.1 404 Not Found\r\nContent-Type: application/json\r\n\r\n{\"message\":\"No such container: web\"}\n",
+        );
+        let err = docker
+            .act("web", &ContainerAction::Signal("SIGHUP".into()))
+            .expect_err("no container");
+        assert_eq!(
+            server.join().expect("server"),
+            "POST /containers/web/kill?signal=SIGHUP HTTP/1.1\r\n"
+        );
+        assert!(err.to_string().contains("No such container: web"));
+        fs::remove_file(docker.socket()).ok();
+    }
+}
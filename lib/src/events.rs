@@ -0,0 +1,265 @@
+//! Event sourcing: the watcher, filter, and debouncer, producing batches.
+//!
+//! This is the first half of [`watch`][crate::run::watch], usable on its own:
+//! either by pulling batches from [`Events`] directly (it's an iterator), or
+//! by [spawning][Events::spawn] it on a thread and receiving batches from a
+//! channel, e.g. to feed them to [`actions::consume`][crate::actions::consume].
+//!
+//! It also defines [`Event`], the reasons a [`Handler`][crate::run::Handler]
+//! can be invoked for, as delivered to
+//! [`Handler::on_event`][crate::run::Handler::on_event].
+
+use log::warn;
+use std::{
+    fs::canonicalize,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::mpsc::{channel, Receiver},
+    thread,
+    time::Instant,
+};
+
+use crate::config::Config;
+use crate::debounce::Debouncer;
+use crate::error::{Error, Result};
+use crate::gitignore;
+use crate::ignore;
+use crate::notification_filter::NotificationFilter;
+use crate::pathop::{self, PathOp, Respelling};
+use crate::signal::Signal;
+use crate::watcher::{Event as RawEvent, Injector, Watcher};
+
+/// Why a handler is being invoked.
+///
+/// The watch loop delivers filesystem changes as one slice of `FsChange`s per
+/// batch, and the initial run as a `Manual`. Each slice starts with a `Source`
+/// saying where the rest comes from. The other variants are for handlers
+/// which are also driven from elsewhere, or which pass events on to other
+/// handlers.
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// Where the events following this one come from.
+    Source(Origin),
+
+    /// A path changed on disk.
+    FsChange(PathOp),
+
+    /// That many more changes happened in the same batch, but were shed as
+    /// they came in too fast, see `Config.max_event_rate`.
+    ///
+    /// This comes after the `FsChange`s which were kept, if any. By default,
+    /// handlers run the command for it as for a `Manual` event when there
+    /// are none.
+    BulkChange(usize),
+
+    /// When each path of the `FsChange`s before this was last changed, by
+    /// [`Config.clock`][crate::config::Config], for handlers which care
+    /// about timing.
+    ///
+    /// The watch loop adds this after the `FsChange`s of every batch.
+    Received(Vec<(PathBuf, Instant)>),
+
+    /// Watchexec received a signal.
+    Signal(Signal),
+
+    /// The command exited, as noticed by an
+    /// [`ExecHandler`][crate::run::ExecHandler] under `watch`, from an
+    /// `Origin::Exit`.
+    ProcessExit(ExitStatus),
+
+    /// A run was requested without any change, e.g. the initial run.
+    Manual,
+
+    /// Time passed, for handlers doing something periodically, from an
+    /// `Origin::Timer` with `Config.run_interval`.
+    ///
+    /// By default, handlers run the command for it as for a `Manual` event.
+    Tick,
+}
+
+/// Where events come from, as tagged by the watch loop.
+///
+/// Other sources can deliver events with their own tag through an
+/// [`Injector`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Origin {
+    /// the initial run
+    Start,
+
+    /// the watcher, including paths injected or from a remote agent
+    Filesystem,
+
+    /// a signal received by watchexec
+    Signal,
+
+    /// a trigger file or message
+    Trigger,
+
+    /// a periodic timer
+    Timer,
+
+    /// watchexec stopping on its own, e.g. at the maximum runtime
+    Stop,
+
+    /// an [`ExecHandler`][crate::run::ExecHandler] with a free slot for a
+    /// queued run, see [`QueuePolicy`][crate::run::QueuePolicy]
+    Queue,
+
+    /// an [`ExecHandler`][crate::run::ExecHandler] at the end of its
+    /// cooldown, with changes kept for then, see
+    /// [`Config.cooldown`][crate::config::Config]
+    Cooldown,
+
+    /// a command over its resource limits, by process id, see
+    /// [`ResourceLimits`][crate::run::ResourceLimits]
+    ResourceLimit(u32),
+
+    /// a command exiting, by process id
+    Exit(u32),
+
+    /// a source from outside watchexec
+    Custom(String),
+}
+
+impl Event {
+    /// The path operation, if this is a filesystem change.
+    pub fn pathop(&self) -> Option<&PathOp> {
+        match self {
+            Self::FsChange(op) => Some(op),
+            _ => None,
+        }
+    }
+}
+
+type FilterFn = Box<dyn Fn(&Path) -> bool + Send>;
+
+/// A running watcher, with its filter and debouncer.
+///
+/// Batches are sorted and deduplicated as they would be for a handler.
+pub struct Events {
+    pub(crate) watcher: Watcher,
+    pub(crate) injector: Injector,
+    pub(crate) debouncer: Debouncer<FilterFn, Receiver<RawEvent>>,
+    pub(crate) requests: Receiver<(Origin, Event)>,
+    group_by_directory: bool,
+    respelling: Option<Respelling>,
+}
+
+impl Events {
+    /// Start watching the paths in the config.
+    ///
+    /// Only the parts of the config relevant to watching and filtering are
+    /// used.
+    pub fn new(args: &Config) -> Result<Self> {
+        let paths = canonical_paths(args)?;
+        let filter = load_filter(args, &paths)?;
+
+        let (tx, rx) = channel();
+        let (requests_tx, requests) = channel();
+
+        #[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+        let mut maybe_watcher = Watcher::new(tx.clone(), &paths, args.poll, args.poll_interval);
+
+        #[cfg(target_os = "linux")]
+        if !args.poll {
+            if let Err(notify::Error::Io(ref e)) = maybe_watcher {
+                if e.raw_os_error() == Some(nix::libc::ENOSPC) {
+                    warn!("System notification limit is too small, falling back to polling mode. For better performance increase system limit:\n\tsysctl fs.inotify.max_user_watches=524288");
+                    maybe_watcher = Watcher::new(tx.clone(), &paths, true, args.poll_interval);
+                }
+            }
+        }
+
+        let watcher = maybe_watcher?;
+        if watcher.is_polling() {
+            warn!("Polling for changes every {:?}", args.poll_interval);
+        }
+
+        let filter: FilterFn = Box::new(move |path: &Path| filter.is_excluded(path));
+        let debouncer = Debouncer::new(rx, filter, args.debounce)
+            .no_meta(args.no_meta)
+            .max_rate(args.max_event_rate)
+            .progress(args.progress_interval)
+            .clock(args.clock.clone());
+
+        Ok(Self {
+            watcher,
+            injector: Injector::new(tx, requests_tx),
+            debouncer,
+            requests,
+            group_by_directory: args.group_by_directory,
+            respelling: if args.original_paths {
+                Some(Respelling::new(&args.paths))
+            } else {
+                None
+            },
+        })
+    }
+
+    /// A handle to push synthetic events into this stream.
+    pub fn injector(&self) -> Injector {
+        self.injector.clone()
+    }
+
+    /// Block until the next batch is available.
+    pub fn next_batch(&mut self) -> Option<Vec<PathOp>> {
+        let mut batch = self.debouncer.next_batch()?;
+        if let Some(ref respelling) = self.respelling {
+            respelling.apply(&mut batch);
+        }
+
+        Some(pathop::normalise_batch(batch, self.group_by_directory))
+    }
+
+    /// Move the watcher to a new thread, and receive batches over a channel.
+    ///
+    /// The thread stops when the receiver is dropped.
+    pub fn spawn(self) -> Receiver<Vec<PathOp>> {
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            for batch in self {
+                if tx.send(batch).is_err() {
+                    break;
+                }
+            }
+        });
+
+        rx
+    }
+}
+
+impl Iterator for Events {
+    type Item = Vec<PathOp>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_batch()
+    }
+}
+
+pub(crate) fn canonical_paths(args: &Config) -> Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for path in &args.paths {
+        paths.push(
+            canonicalize(&path)
+                .map_err(|e| Error::Canonicalization(path.to_string_lossy().into_owned(), e))?,
+        );
+    }
+
+    Ok(paths)
+}
+
+/// Build the filter from the config, loading ignore files from the (canonical) paths.
+pub(crate) fn load_filter(args: &Config, paths: &[PathBuf]) -> Result<NotificationFilter> {
+    let ignore = ignore::load_with(if args.no_ignore { &[] } else { paths }, args.ignore_search);
+    let gitignore = gitignore::load_with(
+        if args.no_vcs_ignore || args.no_ignore {
+            &[]
+        } else {
+            paths
+        },
+        args.ignore_search,
+    );
+
+    NotificationFilter::new(&args.filters, &args.ignores, gitignore, ignore)
+}
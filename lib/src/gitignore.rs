@@ -1,48 +1,71 @@
-use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+//! Matching paths against `.gitignore` files, as git does.
+//!
+//! Each [`GitignoreFile`] is scoped to the directory it's in: its patterns
+//! only apply to paths beneath it, and are matched relative to it. Patterns
+//! with a slash at the start or in the middle are anchored to that directory,
+//! others match at any depth below it. A [`Gitignore`] combines several files,
+//! with those in deeper directories taking precedence, as in git.
+//!
+//! Patterns ending with a slash only match directories, and a negated
+//! pattern can't re-include a path if a directory above it is excluded.
+//!
+//! # Examples
+//!
+//! ```
+//! # use std::path::Path;
+//! # use watchexec::gitignore::{Gitignore, GitignoreFile};
+//! let root = GitignoreFile::from_strings(&["*.log", "doc/out"], Path::new("/repo"))
+//!     .expect("valid patterns");
+//! let sub = GitignoreFile::from_strings(&["!keep.log"], Path::new("/repo/src"))
+//!     .expect("valid patterns");
+//! let gitignore = Gitignore::new(vec![root, sub]);
+//!
+//! assert!(gitignore.is_excluded(Path::new("/repo/src/debug.log")));
+//! assert!(!gitignore.is_excluded(Path::new("/repo/src/keep.log")));
+//! assert!(gitignore.is_excluded(Path::new("/repo/doc/out")));
+//! assert!(!gitignore.is_excluded(Path::new("/repo/src/doc/out")));
+//! ```
+
 use log::debug;
 
-use std::borrow::ToOwned;
+use std::fmt;
 use std::fs;
 use std::io;
 use std::io::Read;
 use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
 
+use crate::ignore::IgnoreSearch;
+use crate::pattern::{self, PatternFile};
+
+/// A set of `.gitignore` files.
 pub struct Gitignore {
     files: Vec<GitignoreFile>,
 }
 
+/// Why a `.gitignore` file couldn't be loaded.
 #[derive(Debug)]
 pub enum Error {
     GlobSet(globset::Error),
     Io(io::Error),
 }
 
-struct GitignoreFile {
-    set: GlobSet,
-    patterns: Vec<Pattern>,
-    root: PathBuf,
-}
-
-struct Pattern {
-    pattern: String,
-    pattern_type: PatternType,
-    anchored: bool,
-}
-
-enum PatternType {
-    Ignore,
-    Whitelist,
+/// The patterns from one `.gitignore` file.
+pub struct GitignoreFile {
+    patterns: PatternFile,
 }
 
-#[derive(PartialEq)]
-enum MatchResult {
-    Ignore,
-    Whitelist,
-    None,
+/// Load all the `.gitignore` files of the repositories the paths are in.
+///
+/// Files which can't be read or parsed are skipped. Paths which are not in a
+/// git repository have no `.gitignore` files.
+pub fn load(paths: &[PathBuf]) -> Gitignore {
+    load_with(paths, IgnoreSearch::Root)
 }
 
-pub fn load(paths: &[PathBuf]) -> Gitignore {
+/// Like [`load`], but with [`IgnoreSearch::None`] only the `.gitignore`
+/// files in the paths are loaded, not those elsewhere in their repositories.
+pub fn load_with(paths: &[PathBuf], search: IgnoreSearch) -> Gitignore {
     let mut files = vec![];
 
     for path in paths {
@@ -65,6 +88,12 @@ pub fn load(paths: &[PathBuf]) -> Gitignore {
 
         if let Some(root) = top_level_git_dir {
             debug!("Found the top level git directory: {:?}", root);
+            let root = if search == IgnoreSearch::None {
+                path.as_path()
+            } else {
+                root
+            };
+
             // scan in subdirectories
             for entry in WalkDir::new(root)
                 .into_iter()
@@ -89,35 +118,34 @@ pub fn load(paths: &[PathBuf]) -> Gitignore {
 }
 
 impl Gitignore {
-    const fn new(files: Vec<GitignoreFile>) -> Self {
+    /// Combine `.gitignore` files, in any order.
+    pub const fn new(files: Vec<GitignoreFile>) -> Self {
         Self { files }
     }
 
+    /// Whether git would ignore that (absolute) path.
+    ///
+    /// The filesystem is checked to know whether the path is a directory, for
+    /// patterns ending with a slash. A path which doesn't exist is a file.
     pub fn is_excluded(&self, path: &Path) -> bool {
-        let mut applicable_files: Vec<&GitignoreFile> = self
-            .files
-            .iter()
-            .filter(|f| path.starts_with(&f.root))
-            .collect();
-        applicable_files.sort_by_key(|f| f.root_len());
-
-        // TODO: add user gitignores
-
-        let mut result = MatchResult::None;
+        pattern::is_excluded(&self.applicable_files(path), path)
+    }
 
-        for file in applicable_files {
-            match file.matches(path) {
-                MatchResult::Ignore => result = MatchResult::Ignore,
-                MatchResult::Whitelist => result = MatchResult::Whitelist,
-                MatchResult::None => {}
-            }
-        }
+    /// The topmost directory above that path which is excluded, if any.
+    ///
+    /// Everything beneath it is excluded, whatever the patterns.
+    pub fn excluded_dir<'p>(&self, path: &'p Path) -> Option<&'p Path> {
+        pattern::excluded_dir(&self.applicable_files(path), path)
+    }
 
-        result == MatchResult::Ignore
+    fn applicable_files(&self, path: &Path) -> Vec<&PatternFile> {
+        // TODO: add user gitignores
+        pattern::applicable_files(self.files.iter().map(|f| &f.patterns), path)
     }
 }
 
 impl GitignoreFile {
+    /// Load a `.gitignore` file, scoped to the directory it's in.
     pub fn new(path: &Path) -> Result<Self, Error> {
         let mut file = fs::File::open(path)?;
         let mut contents = String::new();
@@ -129,106 +157,43 @@ impl GitignoreFile {
         Self::from_strings(&lines, root)
     }
 
+    /// Parse the lines of a `.gitignore` file, scoped to `root`.
     pub fn from_strings(strs: &[&str], root: &Path) -> Result<Self, Error> {
-        let mut builder = GlobSetBuilder::new();
-        let mut patterns = vec![];
-
-        let parsed_patterns = Self::parse(strs);
-        for p in parsed_patterns {
-            let mut pat = p.pattern.clone();
-            if !p.anchored && !pat.starts_with("**/") {
-                pat = "**/".to_string() + &pat;
-            }
-
-            if !pat.ends_with("/**") {
-                pat += "/**";
-            }
-
-            let glob = GlobBuilder::new(&pat).literal_separator(true).build()?;
-
-            builder.add(glob);
-            patterns.push(p);
-        }
-
         Ok(Self {
-            set: builder.build()?,
-            patterns,
-            root: root.to_owned(),
+            patterns: PatternFile::from_strings(strs, root, true)?,
         })
     }
 
-    #[cfg(test)]
-    fn is_excluded(&self, path: &Path) -> bool {
-        self.matches(path) == MatchResult::Ignore
+    /// Whether the last pattern of this file alone matching the (absolute)
+    /// path or a directory above it ignores the path.
+    ///
+    /// Unlike with [`Gitignore::is_excluded`], a negated pattern can
+    /// re-include a path from an excluded directory here.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        self.patterns.is_excluded(path)
     }
 
-    fn matches(&self, path: &Path) -> MatchResult {
-        if let Ok(stripped) = path.strip_prefix(&self.root) {
-            let matches = self.set.matches(stripped);
-            if let Some(i) = matches.iter().rev().next() {
-                let pattern = &self.patterns[*i];
-                return match pattern.pattern_type {
-                    PatternType::Whitelist => MatchResult::Whitelist,
-                    PatternType::Ignore => MatchResult::Ignore,
-                };
-            }
-        }
-
-        MatchResult::None
+    /// The directory the patterns apply to.
+    pub fn root(&self) -> &Path {
+        self.patterns.root()
     }
 
     pub fn root_len(&self) -> usize {
-        self.root.as_os_str().len()
-    }
-
-    fn parse(contents: &[&str]) -> Vec<Pattern> {
-        contents
-            .iter()
-            .filter_map(|l| {
-                if !l.is_empty() && !l.starts_with('#') {
-                    Some(Pattern::parse(l))
-                } else {
-                    None
-                }
-            })
-            .collect()
+        self.patterns.root_len()
     }
 }
 
-impl Pattern {
-    fn parse(pattern: &str) -> Self {
-        let mut normalized = String::from(pattern);
-
-        let pattern_type = if normalized.starts_with('!') {
-            normalized.remove(0);
-            PatternType::Whitelist
-        } else {
-            PatternType::Ignore
-        };
-
-        let anchored = if normalized.starts_with('/') {
-            normalized.remove(0);
-            true
-        } else {
-            false
-        };
-
-        if normalized.ends_with('/') {
-            normalized.pop();
-        }
-
-        if normalized.starts_with("\\#") || normalized.starts_with("\\!") {
-            normalized.remove(0);
-        }
-
-        Self {
-            pattern: normalized,
-            pattern_type,
-            anchored,
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::GlobSet(err) => write!(f, "invalid pattern: {}", err),
+            Self::Io(err) => write!(f, "could not read file: {}", err),
         }
     }
 }
 
+impl std::error::Error for Error {}
+
 impl From<globset::Error> for Error {
     fn from(error: globset::Error) -> Self {
         Self::GlobSet(error)
@@ -243,8 +208,14 @@ impl From<io::Error> for Error {
 
 #[cfg(test)]
 mod tests {
-    use super::GitignoreFile;
-    use std::path::PathBuf;
+    use super::{load, load_with, Gitignore, GitignoreFile};
+    use crate::ignore::IgnoreSearch;
+    use crate::pattern::MatchResult;
+    use std::{
+        env, fs,
+        path::PathBuf,
+        process::{self, Command},
+    };
 
     fn base_dir() -> PathBuf {
         PathBuf::from("/home/user/dir")
@@ -360,4 +331,258 @@ mod tests {
         assert!(!file.is_excluded(&base_dir().join("target").join("foo.txt")));
         assert!(file.is_excluded(&base_dir().join("target").join("blah.txt")));
     }
+
+    #[test]
+    fn handles_negative_patterns_in_dir() {
+        let patterns = vec!["target/*", "!target/foo.txt"];
+        let gitignore = Gitignore::new(vec![GitignoreFile::from_strings(&patterns, &base_dir())
+            .expect("test gitignore file invalid")]);
+
+        assert!(!gitignore.is_excluded(&base_dir().join("target").join("foo.txt")));
+        assert!(gitignore.is_excluded(&base_dir().join("target").join("blah.txt")));
+    }
+
+    #[test]
+    fn cannot_reinclude_from_excluded_dir() {
+        let patterns = vec!["target", "!target/foo.txt"];
+        let gitignore = Gitignore::new(vec![GitignoreFile::from_strings(&patterns, &base_dir())
+            .expect("test gitignore file invalid")]);
+
+        assert!(gitignore.is_excluded(&base_dir().join("target").join("foo.txt")));
+    }
+
+    #[test]
+    fn finds_topmost_excluded_dir() {
+        let gitignore = Gitignore::new(vec![build_gitignore("target"), build_gitignore("debug/")]);
+
+        assert_eq!(
+            gitignore.excluded_dir(&base_dir().join("target/debug/build/out.o")),
+            Some(base_dir().join("target").as_path())
+        );
+        assert_eq!(gitignore.excluded_dir(&base_dir().join("target")), None);
+        assert_eq!(
+            gitignore.excluded_dir(&base_dir().join("src/main.rs")),
+            None
+        );
+    }
+
+    #[test]
+    fn directory_only() {
+        let file = build_gitignore("build/");
+
+        assert!(file.patterns.matches(&base_dir().join("build"), &|| false) == MatchResult::None);
+        assert!(file.patterns.matches(&base_dir().join("build"), &|| true) == MatchResult::Ignore);
+        assert!(file.is_excluded(&base_dir().join("src").join("build").join("out.o")));
+    }
+
+    #[test]
+    fn trailing_spaces() {
+        let file = build_gitignore("foo  ");
+        assert!(file.is_excluded(&base_dir().join("foo")));
+
+        let file = build_gitignore("foo\\ ");
+        assert!(file.is_excluded(&base_dir().join("foo ")));
+        assert!(!file.is_excluded(&base_dir().join("foo")));
+    }
+
+    /// Compare with `git check-ignore` in a repository holding those
+    /// `.gitignore` files, ignoring the user's and system's git config.
+    ///
+    /// Paths ending with a slash are created as directories, others don't
+    /// exist.
+    fn check_against_git(name: &str, gitignores: &[(&str, &str)], paths: &[&str]) {
+        let root = env::temp_dir().join(format!("watchexec-gitignore-{}-{}", name, process::id()));
+        let repo = root.join("repo");
+        fs::remove_dir_all(&root).ok();
+        fs::create_dir_all(&repo).expect("create repo");
+
+        let git = |args: &[&str]| {
+            Command::new("git")
+                .arg("-c")
+                .arg("core.excludesFile=")
+                .args(args)
+                .current_dir(&repo)
+                .env("GIT_CONFIG_NOSYSTEM", "1")
+                .env("HOME", &root)
+                .env_remove("XDG_CONFIG_HOME")
+                .status()
+                .expect("git must be installed to run the gitignore conformance tests")
+                .success()
+        };
+
+        assert!(git(&["init", "-q"]), "git init failed");
+
+        for (dir, contents) in gitignores {
+            let dir = repo.join(dir);
+            fs::create_dir_all(&dir).expect("create dir");
+            fs::write(dir.join(".gitignore"), contents).expect("write .gitignore");
+        }
+
+        for path in paths.iter().filter(|path| path.ends_with('/')) {
+            fs::create_dir_all(repo.join(path)).expect("create dir");
+        }
+
+        let gitignore = load(std::slice::from_ref(&repo));
+        for path in paths {
+            let path = path.trim_end_matches('/');
+            let expected = git(&["check-ignore", "-q", "--no-index", path]);
+
+            assert_eq!(
+                gitignore.is_excluded(&repo.join(path)),
+                expected,
+                "{} should be {}",
+                path,
+                if expected { "ignored" } else { "kept" }
+            );
+        }
+
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn nested_files_are_scoped() {
+        check_against_git(
+            "scoped",
+            &[("", "*.tmp\n"), ("sub", "*.log\n/only\n")],
+            &[
+                "a.tmp",
+                "sub/a.tmp",
+                "a.log",
+                "sub/a.log",
+                "sub/deep/a.log",
+                "other/a.log",
+                "only",
+                "sub/only",
+                "sub/deep/only",
+            ],
+        );
+    }
+
+    #[test]
+    fn middle_slash_anchors() {
+        check_against_git(
+            "anchored",
+            &[("", "a/b\nc/*.rs\n"), ("x", "d/e\n")],
+            &[
+                "a/b",
+                "a/b/file",
+                "x/a/b",
+                "c/main.rs",
+                "x/c/main.rs",
+                "c/sub/main.rs",
+                "x/d/e",
+                "d/e",
+                "x/y/d/e",
+            ],
+        );
+    }
+
+    #[test]
+    fn deeper_files_take_precedence() {
+        check_against_git(
+            "precedence",
+            &[
+                ("", "*.log\n!keep.log\n"),
+                ("sub", "keep.log\n!debug.log\n"),
+            ],
+            &[
+                "debug.log",
+                "keep.log",
+                "sub/debug.log",
+                "sub/keep.log",
+                "other/keep.log",
+            ],
+        );
+    }
+
+    #[test]
+    fn directory_only_patterns() {
+        check_against_git(
+            "dironly",
+            &[("", "build/\n/out/\ndoc/tmp/\n")],
+            &[
+                "build",
+                "build/",
+                "build/x",
+                "src/build/",
+                "src/build/x",
+                "out/",
+                "out/x",
+                "src/out/x",
+                "doc/tmp",
+                "doc/tmp/",
+                "doc/tmp/x",
+            ],
+        );
+    }
+
+    #[test]
+    fn negated_patterns() {
+        check_against_git(
+            "negated",
+            &[
+                (
+                    "",
+                    "target\n!target/keep\nlogs/*\n!logs/keep.log\n*.o\n!*.o\nbin/\n!bin/\n",
+                ),
+                ("lib", "!*.o\n"),
+            ],
+            &[
+                "target/keep",
+                "target/other",
+                "logs/keep.log",
+                "logs/other.log",
+                "logs/sub/",
+                "logs/sub/keep.log",
+                "a.o",
+                "bin/",
+                "bin/x",
+                "lib/a.o",
+            ],
+        );
+    }
+
+    #[test]
+    fn double_asterisks() {
+        check_against_git(
+            "asterisks",
+            &[("", "**/cache\na/**/b\nc/**\nfoo**bar\n/d/***\n")],
+            &[
+                "cache",
+                "x/y/cache/z",
+                "a/b",
+                "a/x/y/b",
+                "x/a/b",
+                "c",
+                "c/",
+                "c/x/y",
+                "fooxbar",
+                "fooxx/bar",
+                "x/foobar",
+                "d/x",
+                "d/x/y",
+            ],
+        );
+    }
+
+    #[test]
+    fn search_stays_in_paths() {
+        let repo = env::temp_dir().join(format!("watchexec-gitignore-search-{}", process::id()));
+        let src = repo.join("src");
+        fs::create_dir_all(repo.join(".git")).expect("create repo");
+        fs::create_dir_all(&src).expect("create src");
+        fs::write(repo.join(".gitignore"), "*.b\n").expect("write gitignore");
+        fs::write(src.join(".gitignore"), "*.c\n").expect("write gitignore");
+
+        let paths = [src.clone()];
+        let whole = load_with(&paths, IgnoreSearch::Origin);
+        assert!(whole.is_excluded(&src.join("x.b")));
+        assert!(whole.is_excluded(&src.join("x.c")));
+
+        let only_src = load_with(&paths, IgnoreSearch::None);
+        assert!(!only_src.is_excluded(&src.join("x.b")));
+        assert!(only_src.is_excluded(&src.join("x.c")));
+
+        fs::remove_dir_all(&repo).ok();
+    }
 }
@@ -1,4 +1,3 @@
-use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
 use log::debug;
 use std::collections::HashSet;
 use std::fs;
@@ -7,6 +6,31 @@ use std::io::Read;
 use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
 
+use crate::pattern::{self, PatternFile};
+
+/// How far above the watched paths to look for ignore files.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IgnoreSearch {
+    /// only in the watched paths
+    None,
+
+    /// up to the project origin, the closest directory with a VCS directory
+    /// like `.git`, or only in the watched paths if there is none
+    Origin,
+
+    /// up to the filesystem root
+    Root,
+}
+
+impl Default for IgnoreSearch {
+    fn default() -> Self {
+        Self::Root
+    }
+}
+
+/// What marks the origin of a project, for [`IgnoreSearch::Origin`].
+const ORIGIN_MARKERS: &[&str] = &[".git", ".hg", ".svn", ".bzr", "_darcs", ".pijul"];
+
 pub struct Ignore {
     files: Vec<IgnoreFile>,
 }
@@ -18,42 +42,29 @@ pub enum Error {
 }
 
 struct IgnoreFile {
-    set: GlobSet,
-    patterns: Vec<Pattern>,
-    root: PathBuf,
+    patterns: PatternFile,
 }
 
-struct Pattern {
-    pattern: String,
-    pattern_type: PatternType,
-    anchored: bool,
-}
-
-enum PatternType {
-    Ignore,
-    Whitelist,
-}
-
-#[derive(PartialEq)]
-enum MatchResult {
-    Ignore,
-    Whitelist,
-    None,
+pub fn load(paths: &[PathBuf]) -> Ignore {
+    load_with(paths, IgnoreSearch::Root)
 }
 
-pub fn load(paths: &[PathBuf]) -> Ignore {
+pub fn load_with(paths: &[PathBuf], search: IgnoreSearch) -> Ignore {
     let mut files = vec![];
     let mut checked_dirs = HashSet::new();
 
     for path in paths {
-        let mut p = path.to_owned();
+        let top = match search {
+            IgnoreSearch::None => None,
+            IgnoreSearch::Origin => path.ancestors().find(|dir| is_origin(dir)),
+            IgnoreSearch::Root => path.ancestors().last(),
+        }
+        .unwrap_or(path);
 
-        // walk up to root
-        // FIXME: this makes zero sense and should be removed
-        // but that would be a breaking change
-        loop {
-            if !checked_dirs.contains(&p) {
-                checked_dirs.insert(p.clone());
+        // walk up to the top
+        for p in path.ancestors() {
+            if !checked_dirs.contains(p) {
+                checked_dirs.insert(p.to_owned());
 
                 let ignore_path = p.join(".ignore");
                 if ignore_path.exists() {
@@ -66,11 +77,9 @@ pub fn load(paths: &[PathBuf]) -> Ignore {
                 }
             }
 
-            if p.parent().is_none() {
+            if p == top {
                 break;
             }
-
-            p.pop();
         }
 
         //also look in subfolders
@@ -93,32 +102,31 @@ pub fn load(paths: &[PathBuf]) -> Ignore {
     Ignore::new(files)
 }
 
+fn is_origin(dir: &Path) -> bool {
+    ORIGIN_MARKERS
+        .iter()
+        .any(|marker| dir.join(marker).exists())
+}
+
 impl Ignore {
     const fn new(files: Vec<IgnoreFile>) -> Self {
         Self { files }
     }
 
     pub fn is_excluded(&self, path: &Path) -> bool {
-        let mut applicable_files: Vec<&IgnoreFile> = self
-            .files
-            .iter()
-            .filter(|f| path.starts_with(&f.root))
-            .collect();
-        applicable_files.sort_by_key(|f| f.root_len());
-
-        // TODO: add user ignores
-
-        let mut result = MatchResult::None;
+        pattern::is_excluded(&self.applicable_files(path), path)
+    }
 
-        for file in applicable_files {
-            match file.matches(path) {
-                MatchResult::Ignore => result = MatchResult::Ignore,
-                MatchResult::Whitelist => result = MatchResult::Whitelist,
-                MatchResult::None => {}
-            }
-        }
+    /// The topmost directory above that path which is excluded, if any.
+    ///
+    /// Everything beneath it is excluded, whatever the patterns.
+    pub fn excluded_dir<'p>(&self, path: &'p Path) -> Option<&'p Path> {
+        pattern::excluded_dir(&self.applicable_files(path), path)
+    }
 
-        result == MatchResult::Ignore
+    fn applicable_files(&self, path: &Path) -> Vec<&PatternFile> {
+        // TODO: add user ignores
+        pattern::applicable_files(self.files.iter().map(|f| &f.patterns), path)
     }
 }
 
@@ -135,102 +143,14 @@ impl IgnoreFile {
     }
 
     pub fn from_strings(strs: &[&str], root: &Path) -> Result<Self, Error> {
-        let mut builder = GlobSetBuilder::new();
-        let mut patterns = vec![];
-
-        let parsed_patterns = Self::parse(strs);
-        for p in parsed_patterns {
-            let mut pat = p.pattern.clone();
-            if !p.anchored && !pat.starts_with("**/") {
-                pat = "**/".to_string() + &pat;
-            }
-
-            if !pat.ends_with("/**") {
-                pat += "/**";
-            }
-
-            let glob = GlobBuilder::new(&pat).literal_separator(true).build()?;
-
-            builder.add(glob);
-            patterns.push(p);
-        }
-
         Ok(Self {
-            set: builder.build()?,
-            patterns,
-            root: root.to_owned(),
+            patterns: PatternFile::from_strings(strs, root, false)?,
         })
     }
 
     #[cfg(test)]
     fn is_excluded(&self, path: &Path) -> bool {
-        self.matches(path) == MatchResult::Ignore
-    }
-
-    fn matches(&self, path: &Path) -> MatchResult {
-        if let Ok(stripped) = path.strip_prefix(&self.root) {
-            let matches = self.set.matches(stripped);
-            if let Some(i) = matches.iter().rev().next() {
-                let pattern = &self.patterns[*i];
-                return match pattern.pattern_type {
-                    PatternType::Whitelist => MatchResult::Whitelist,
-                    PatternType::Ignore => MatchResult::Ignore,
-                };
-            }
-        }
-
-        MatchResult::None
-    }
-
-    pub fn root_len(&self) -> usize {
-        self.root.as_os_str().len()
-    }
-
-    fn parse(contents: &[&str]) -> Vec<Pattern> {
-        contents
-            .iter()
-            .filter_map(|l| {
-                if !l.is_empty() && !l.starts_with('#') {
-                    Some(Pattern::parse(l))
-                } else {
-                    None
-                }
-            })
-            .collect()
-    }
-}
-
-impl Pattern {
-    fn parse(pattern: &str) -> Self {
-        let mut normalized = String::from(pattern);
-
-        let pattern_type = if normalized.starts_with('!') {
-            normalized.remove(0);
-            PatternType::Whitelist
-        } else {
-            PatternType::Ignore
-        };
-
-        let anchored = if normalized.starts_with('/') {
-            normalized.remove(0);
-            true
-        } else {
-            false
-        };
-
-        if normalized.ends_with('/') {
-            normalized.pop();
-        }
-
-        if normalized.starts_with("\\#") || normalized.starts_with("\\!") {
-            normalized.remove(0);
-        }
-
-        Self {
-            pattern: normalized,
-            pattern_type,
-            anchored,
-        }
+        self.patterns.is_excluded(path)
     }
 }
 
@@ -248,8 +168,9 @@ impl From<io::Error> for Error {
 
 #[cfg(test)]
 mod tests {
-    use super::IgnoreFile;
-    use std::path::PathBuf;
+    use super::{load_with, Ignore, IgnoreFile, IgnoreSearch};
+    use crate::pattern::MatchResult;
+    use std::{env, fs, path::PathBuf, process};
 
     fn base_dir() -> PathBuf {
         PathBuf::from("/home/user/dir")
@@ -364,4 +285,61 @@ mod tests {
         assert!(!file.is_excluded(&base_dir().join("target").join("foo.txt")));
         assert!(file.is_excluded(&base_dir().join("target").join("blah.txt")));
     }
+
+    #[test]
+    fn handles_whitelisting_in_dir() {
+        let patterns = vec!["target/*", "!target/foo.txt"];
+        let ignore = Ignore::new(vec![
+            IgnoreFile::from_strings(&patterns, &base_dir()).expect("test ignore file invalid")
+        ]);
+
+        assert!(!ignore.is_excluded(&base_dir().join("target").join("foo.txt")));
+        assert!(ignore.is_excluded(&base_dir().join("target").join("blah.txt")));
+    }
+
+    #[test]
+    fn cannot_whitelist_from_excluded_dir() {
+        let patterns = vec!["target", "!target/foo.txt"];
+        let ignore = Ignore::new(vec![
+            IgnoreFile::from_strings(&patterns, &base_dir()).expect("test ignore file invalid")
+        ]);
+
+        assert!(ignore.is_excluded(&base_dir().join("target").join("foo.txt")));
+    }
+
+    #[test]
+    fn directory_only() {
+        let file = build_ignore("build/");
+
+        assert!(file.patterns.matches(&base_dir().join("build"), &|| false) == MatchResult::None);
+        assert!(file.patterns.matches(&base_dir().join("build"), &|| true) == MatchResult::Ignore);
+        assert!(file.is_excluded(&base_dir().join("src").join("build").join("out.o")));
+    }
+
+    #[test]
+    fn search_depth() {
+        let base = env::temp_dir().join(format!("watchexec-ignore-search-{}", process::id()));
+        let project = base.join("project");
+        let src = project.join("src");
+        fs::create_dir_all(project.join(".git")).expect("create project");
+        fs::create_dir_all(&src).expect("create src");
+        fs::write(base.join(".ignore"), "*.a\n").expect("write ignore");
+        fs::write(project.join(".ignore"), "*.b\n").expect("write ignore");
+        fs::write(src.join(".ignore"), "*.c\n").expect("write ignore");
+
+        let paths = [src.clone()];
+        let excluded = |search| {
+            let ignore = load_with(&paths, search);
+            ["x.a", "x.b", "x.c"]
+                .iter()
+                .map(|name| ignore.is_excluded(&src.join(name)))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(excluded(IgnoreSearch::Root), vec![true, true, true]);
+        assert_eq!(excluded(IgnoreSearch::Origin), vec![false, true, true]);
+        assert_eq!(excluded(IgnoreSearch::None), vec![false, false, true]);
+
+        fs::remove_dir_all(&base).ok();
+    }
 }
@@ -8,17 +8,38 @@
 #![doc(html_logo_url = "https://watchexec.github.io/logo:watchexec.svg")]
 #![warn(clippy::unwrap_used)]
 
+pub mod actions;
+pub mod audit;
+pub mod clock;
 pub mod config;
+#[cfg(unix)]
+pub mod daemon;
+pub mod debounce;
+#[cfg(unix)]
+pub mod docker;
 pub mod error;
-mod gitignore;
+pub mod events;
+pub mod gitignore;
 mod ignore;
-mod notification_filter;
+pub mod notification_filter;
 pub mod pathop;
 mod paths;
+mod pattern;
+pub mod record;
+pub mod remote;
+mod resources;
 pub mod run;
+#[cfg(all(windows, feature = "windows-service"))]
+pub mod service;
 mod shell;
 mod signal;
-mod watcher;
+mod systemd;
+pub mod testing;
+pub mod trigger;
+pub mod watcher;
+mod watchexec;
 
 pub use run::{run, watch, Handler};
 pub use shell::Shell;
+pub use signal::{NoSignals, ProcessSignals, Signal, SignalGuard, SignalHandler, SignalSource};
+pub use watchexec::Watchexec;
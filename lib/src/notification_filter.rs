@@ -1,20 +1,104 @@
+//! Watchexec's path matching, usable on its own.
+//!
+//! A [`NotificationFilter`] decides whether a change to a path is of interest,
+//! exactly as the watch loop does, so that other tools can be consistent with
+//! it. In order:
+//!
+//! 1. paths matching an ignore glob are excluded;
+//! 2. paths matching a filter glob are included;
+//! 3. paths ignored by an `.ignore` file, then by a `.gitignore` file, are
+//!    excluded;
+//! 4. if there are filter globs, anything left is excluded, else included.
+//!
+//! Ignore globs without a leading `*` match anywhere, and all of them also
+//! match everything under matching directories.
+//!
+//! Directories excluded by an ignore file are remembered, so that the many
+//! changes beneath one, e.g. in `node_modules`, are excluded without matching
+//! them against every pattern again.
+//!
+//! # Examples
+//!
+//! ```
+//! # use std::path::Path;
+//! # use watchexec::notification_filter::{NotificationFilter, Verdict};
+//! let filter = NotificationFilter::from_globs(&["*.rs".into()], &["target".into()])
+//!     .expect("valid globs");
+//!
+//! assert!(!filter.is_excluded(Path::new("/src/main.rs")));
+//! assert_eq!(
+//!     filter.explain(Path::new("/src/target/main.rs")),
+//!     Verdict::Ignored("target".into())
+//! );
+//! ```
+
+use crate::config::Config;
 use crate::error;
-use crate::gitignore::Gitignore;
-use crate::ignore::Ignore;
+use crate::events::{canonical_paths, load_filter};
+use crate::gitignore::{self, Gitignore};
+use crate::ignore::{self, Ignore};
 use globset::{Glob, GlobSet, GlobSetBuilder};
 use log::debug;
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Why a path is excluded or not, see [`NotificationFilter::explain`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Verdict {
+    /// excluded by that ignore glob
+    Ignored(String),
+
+    /// included by that filter glob
+    Filtered(String),
+
+    /// excluded by an `.ignore` file
+    IgnoreFile,
+
+    /// excluded by a `.gitignore` file
+    Gitignore,
+
+    /// excluded for not matching any of the filter globs
+    Unmatched,
+
+    /// included, as nothing excludes it
+    Included,
+}
+
+impl Verdict {
+    /// Whether changes to the path are excluded.
+    pub fn is_excluded(&self) -> bool {
+        !matches!(self, Self::Filtered(_) | Self::Included)
+    }
+}
 
+/// Filters and ignores, from globs and ignore files.
 pub struct NotificationFilter {
     filters: GlobSet,
-    filter_count: usize,
+    filter_globs: Vec<String>,
     ignores: GlobSet,
+    ignore_globs: Vec<String>,
     gitignore_files: Gitignore,
     ignore_files: Ignore,
+    excluded_dirs: Mutex<HashMap<PathBuf, Verdict>>,
 }
 
 impl NotificationFilter {
-    pub fn new(
+    /// Build the filter used for watching with that config.
+    ///
+    /// That's its `filters` and `ignores`, and unless disabled, the ignore
+    /// files found from its `paths`. The paths must exist.
+    pub fn from_config(args: &Config) -> error::Result<Self> {
+        load_filter(args, &canonical_paths(args)?)
+    }
+
+    /// Build a filter from globs alone, without ignore files.
+    pub fn from_globs(filters: &[String], ignores: &[String]) -> error::Result<Self> {
+        Self::new(filters, ignores, gitignore::load(&[]), ignore::load(&[]))
+    }
+
+    pub(crate) fn new(
         filters: &[String],
         ignores: &[String],
         gitignore_files: Gitignore,
@@ -44,45 +128,93 @@ impl NotificationFilter {
 
         Ok(Self {
             filters: filter_set_builder.build()?,
-            filter_count: filters.len(),
+            filter_globs: filters.to_vec(),
             ignores: ignore_set_builder.build()?,
+            ignore_globs: ignores.to_vec(),
             gitignore_files,
             ignore_files,
+            excluded_dirs: Mutex::new(HashMap::new()),
         })
     }
 
+    /// Whether changes to that path are excluded.
     pub fn is_excluded(&self, path: &Path) -> bool {
-        if self.ignores.is_match(path) {
-            debug!("Ignoring {:?}: matched ignore filter", path);
-            return true;
+        let verdict = self.explain(path);
+        if verdict.is_excluded() {
+            debug!("Ignoring {:?}: {:?}", path, verdict);
         }
 
-        if self.filters.is_match(path) {
-            return false;
+        verdict.is_excluded()
+    }
+
+    /// Why changes to that path are excluded, or not.
+    ///
+    /// When several globs match, the first one given is reported.
+    pub fn explain(&self, path: &Path) -> Verdict {
+        if let Some(&i) = self.ignores.matches(path).first() {
+            return Verdict::Ignored(self.ignore_globs[i].clone());
+        }
+
+        if let Some(&i) = self.filters.matches(path).first() {
+            return Verdict::Filtered(self.filter_globs[i].clone());
+        }
+
+        if let Some(verdict) = self.excluded_parent(path) {
+            return verdict;
+        }
+
+        if let Some(dir) = self.ignore_files.excluded_dir(path) {
+            return self.exclude_dir(dir, Verdict::IgnoreFile);
         }
 
         if self.ignore_files.is_excluded(path) {
-            debug!("Ignoring {:?}: matched ignore file", path);
-            return true;
+            return Verdict::IgnoreFile;
+        }
+
+        if let Some(dir) = self.gitignore_files.excluded_dir(path) {
+            return self.exclude_dir(dir, Verdict::Gitignore);
         }
 
         if self.gitignore_files.is_excluded(path) {
-            debug!("Ignoring {:?}: matched gitignore file", path);
-            return true;
+            return Verdict::Gitignore;
         }
 
-        if self.filter_count > 0 {
-            debug!("Ignoring {:?}: did not match any given filters", path);
+        if self.filter_globs.is_empty() {
+            Verdict::Included
+        } else {
+            Verdict::Unmatched
         }
+    }
+
+    /// The verdict for a directory above that path known to be excluded.
+    fn excluded_parent(&self, path: &Path) -> Option<Verdict> {
+        let excluded_dirs = self
+            .excluded_dirs
+            .lock()
+            .expect("poisoned lock in excluded_parent");
 
-        self.filter_count > 0
+        path.ancestors()
+            .skip(1)
+            .find_map(|dir| excluded_dirs.get(dir))
+            .cloned()
+    }
+
+    /// Remember that a directory is excluded, returning the verdict.
+    fn exclude_dir(&self, dir: &Path, verdict: Verdict) -> Verdict {
+        debug!("Excluding everything under {:?}", dir);
+        self.excluded_dirs
+            .lock()
+            .expect("poisoned lock in exclude_dir")
+            .insert(dir.to_path_buf(), verdict.clone());
+
+        verdict
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::NotificationFilter;
-    use crate::gitignore;
+    use super::{NotificationFilter, Verdict};
+    use crate::gitignore::{self, Gitignore, GitignoreFile};
     use crate::ignore;
     use std::path::Path;
 
@@ -157,4 +289,55 @@ mod tests {
         assert!(!filter.is_excluded(Path::new("hello.rs")));
         assert!(!filter.is_excluded(Path::new("Cargo.toml")));
     }
+
+    #[test]
+    fn test_explains_verdicts() {
+        let filter =
+            NotificationFilter::from_globs(&["*.rs".into(), "src/**".into()], &["*.tmp.rs".into()])
+                .expect("test filter errors");
+
+        assert_eq!(
+            filter.explain(Path::new("a.tmp.rs")),
+            Verdict::Ignored("*.tmp.rs".into())
+        );
+        assert_eq!(
+            filter.explain(Path::new("src/a.rs")),
+            Verdict::Filtered("*.rs".into())
+        );
+        assert_eq!(filter.explain(Path::new("README.md")), Verdict::Unmatched);
+        assert!(filter.explain(Path::new("README.md")).is_excluded());
+
+        let filter = NotificationFilter::from_globs(&[], &[]).expect("test filter errors");
+        assert_eq!(filter.explain(Path::new("README.md")), Verdict::Included);
+    }
+
+    #[test]
+    fn test_remembers_excluded_dirs() {
+        let root = Path::new("/repo");
+        let gitignore =
+            Gitignore::new(vec![GitignoreFile::from_strings(&["node_modules"], root)
+                .expect("test gitignore invalid")]);
+        let filter = NotificationFilter::new(&["*.js".into()], &[], gitignore, ignore::load(&[]))
+            .expect("test filter errors");
+
+        assert_eq!(
+            filter.explain(&root.join("node_modules/a/index.ts")),
+            Verdict::Gitignore
+        );
+        assert!(filter
+            .excluded_dirs
+            .lock()
+            .expect("lock")
+            .contains_key(&root.join("node_modules")));
+        assert_eq!(
+            filter.explain(&root.join("node_modules/b/index.ts")),
+            Verdict::Gitignore
+        );
+
+        // Filters still come first
+        assert_eq!(
+            filter.explain(&root.join("node_modules/a/index.js")),
+            Verdict::Filtered("*.js".into())
+        );
+    }
 }
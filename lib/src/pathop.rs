@@ -1,5 +1,8 @@
 use notify::op;
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 /// Info about a path and its corresponding `notify` event
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
@@ -39,4 +42,189 @@ impl PathOp {
     pub const fn is_meta(op_: op::Op) -> bool {
         op_.contains(op::CHMOD)
     }
+
+    /// Rank of an op when merging several events for the same path.
+    ///
+    /// Structural changes (removal, creation, renames) win over content
+    /// changes, which win over metadata changes.
+    fn significance(op_: Option<op::Op>) -> u8 {
+        match op_ {
+            Some(o) if Self::is_remove(o) => 5,
+            Some(o) if Self::is_create(o) => 4,
+            Some(o) if Self::is_rename(o) => 3,
+            Some(o) if Self::is_write(o) => 2,
+            Some(o) if Self::is_meta(o) => 1,
+            _ => 0,
+        }
+    }
+}
+
+/// Clean up a batch of `PathOp`s before handing it to a handler.
+///
+/// The batch is deduplicated by path, keeping the most significant op for each
+/// (removal, then creation, rename, write, and finally metadata change), and
+/// sorted. If `group_by_directory` is true, the sort is by
+/// parent directory first, so that all the entries of a directory are
+/// contiguous, rather than by full path.
+pub fn normalise_batch(ops: Vec<PathOp>, group_by_directory: bool) -> Vec<PathOp> {
+    let mut by_path: HashMap<PathBuf, PathOp> = HashMap::with_capacity(ops.len());
+    for pathop in ops {
+        match by_path.get(&pathop.path) {
+            Some(existing)
+                if PathOp::significance(existing.op) >= PathOp::significance(pathop.op) => {}
+            _ => {
+                by_path.insert(pathop.path.clone(), pathop);
+            }
+        }
+    }
+
+    let mut batch: Vec<PathOp> = by_path.into_iter().map(|(_, op)| op).collect();
+    if group_by_directory {
+        batch.sort_by(|a, b| {
+            a.path
+                .parent()
+                .cmp(&b.path.parent())
+                .then_with(|| a.path.cmp(&b.path))
+        });
+    } else {
+        batch.sort_by(|a, b| a.path.cmp(&b.path));
+    }
+
+    batch
+}
+
+/// Maps paths under the canonical roots back to the roots as they were given,
+/// see `Config.original_paths`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Respelling {
+    /// canonical and original roots, deepest first
+    roots: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Respelling {
+    /// Roots which can't be canonicalized are left out.
+    pub(crate) fn new(originals: &[PathBuf]) -> Self {
+        let mut roots: Vec<(PathBuf, PathBuf)> = originals
+            .iter()
+            .filter_map(|original| Some((original.canonicalize().ok()?, original.clone())))
+            .collect();
+        roots.sort_by_key(|(canonical, _)| std::cmp::Reverse(canonical.components().count()));
+
+        Self { roots }
+    }
+
+    pub(crate) fn apply(&self, ops: &mut [PathOp]) {
+        for pathop in ops {
+            if let Some(path) = self.respell(&pathop.path) {
+                pathop.path = path;
+            }
+        }
+    }
+
+    fn respell(&self, path: &Path) -> Option<PathBuf> {
+        self.roots.iter().find_map(|(canonical, original)| {
+            let rest = path.strip_prefix(canonical).ok()?;
+            Some(if rest.as_os_str().is_empty() {
+                original.clone()
+            } else {
+                original.join(rest)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalise_batch, PathOp, Respelling};
+    use notify::op;
+    use std::path::{Path, PathBuf};
+
+    fn pathop(path: &str, op_: op::Op) -> PathOp {
+        PathOp::new(Path::new(path), Some(op_), None)
+    }
+
+    fn paths(batch: &[PathOp]) -> Vec<PathBuf> {
+        batch.iter().map(|p| p.path.clone()).collect()
+    }
+
+    #[test]
+    fn normalise_sorts_by_path() {
+        let batch = normalise_batch(
+            vec![pathop("/b", op::WRITE), pathop("/a", op::WRITE)],
+            false,
+        );
+
+        assert_eq!(
+            paths(&batch),
+            vec![PathBuf::from("/a"), PathBuf::from("/b")]
+        );
+    }
+
+    #[test]
+    fn normalise_keeps_most_significant_op() {
+        let batch = normalise_batch(
+            vec![
+                pathop("/a", op::WRITE),
+                pathop("/a", op::REMOVE),
+                pathop("/a", op::CHMOD),
+            ],
+            false,
+        );
+
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].op, Some(op::REMOVE));
+    }
+
+    #[test]
+    fn normalise_groups_by_directory() {
+        let batch = normalise_batch(
+            vec![
+                pathop("/a/b/x", op::WRITE),
+                pathop("/a/c", op::WRITE),
+                pathop("/a/b.txt", op::WRITE),
+            ],
+            true,
+        );
+
+        assert_eq!(
+            paths(&batch),
+            vec![
+                PathBuf::from("/a/b.txt"),
+                PathBuf::from("/a/c"),
+                PathBuf::from("/a/b/x"),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn respells_canonical_paths() {
+        let dir = std::env::temp_dir().join(format!("watchexec-respell-{}", std::process::id()));
+        let real = dir.join("real");
+        let link = dir.join("link");
+        std::fs::create_dir_all(real.join("nested")).expect("create dir");
+        std::os::unix::fs::symlink(&real, &link).expect("symlink");
+
+        let real = real.canonicalize().expect("canonical");
+        let respelling = Respelling::new(&[link.clone(), link.join("nested")]);
+        let mut batch = vec![
+            pathop(real.join("a").to_str().expect("utf8"), op::WRITE),
+            pathop(real.join("nested/b").to_str().expect("utf8"), op::WRITE),
+            pathop(real.to_str().expect("utf8"), op::WRITE),
+            pathop("/elsewhere", op::WRITE),
+        ];
+        respelling.apply(&mut batch);
+
+        assert_eq!(
+            paths(&batch),
+            vec![
+                link.join("a"),
+                link.join("nested").join("b"),
+                link.clone(),
+                PathBuf::from("/elsewhere"),
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
 }
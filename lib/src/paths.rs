@@ -1,4 +1,5 @@
 use crate::pathop::PathOp;
+use crate::run::PathSeparator;
 use std::{
     collections::{HashMap, HashSet},
     path::PathBuf,
@@ -11,12 +12,13 @@ use std::{
 /// `REMOVED` -> `notify::ops::REMOVE`
 /// `CREATED` -> `notify::ops::CREATE`
 /// `RENAMED` -> `notify::ops::RENAME`
-pub fn collect_path_env_vars(pathops: &[PathOp]) -> Vec<(String, String)> {
-    #[cfg(target_family = "unix")]
-    const ENV_SEP: &str = ":";
-    #[cfg(not(target_family = "unix"))]
-    const ENV_SEP: &str = ";";
-
+///
+/// Paths in each variable are separated by `separator`, except that with
+/// `PathSeparator::Nul` only `COMMON_PATH` is set.
+pub fn collect_path_env_vars(
+    pathops: &[PathOp],
+    separator: PathSeparator,
+) -> Vec<(String, String)> {
     let mut by_op = HashMap::new(); // Paths as `String`s collected by `notify::op`
     let mut all_pathbufs = HashSet::new(); // All unique `PathBuf`s
     for pathop in pathops {
@@ -44,6 +46,10 @@ pub fn collect_path_env_vars(pathops: &[PathOp]) -> Vec<(String, String)> {
     if let Some(ref common_path) = common_path {
         vars.push(("WATCHEXEC_COMMON_PATH".to_string(), common_path.to_string()));
     }
+    if separator == PathSeparator::Nul {
+        return vars;
+    }
+
     for (op, paths) in by_op {
         let key = match op {
             op if PathOp::is_create(op) => "WATCHEXEC_CREATED_PATH",
@@ -62,11 +68,32 @@ pub fn collect_path_env_vars(pathops: &[PathOp]) -> Vec<(String, String)> {
         } else {
             paths
         };
-        vars.push((key.to_string(), paths.as_slice().join(ENV_SEP)));
+        vars.push((key.to_string(), paths.as_slice().join(separator.as_str())));
     }
     vars
 }
 
+/// The paths of all `PathOp`s, each followed by `separator`, to feed the
+/// command's stdin.
+///
+/// Unlike environment variables, this has paths which aren't valid UTF-8 too,
+/// on Unix.
+pub fn path_list(pathops: &[PathOp], separator: PathSeparator) -> Vec<u8> {
+    let mut list = Vec::new();
+    for pathop in pathops {
+        #[cfg(unix)]
+        list.extend_from_slice(std::os::unix::ffi::OsStrExt::as_bytes(
+            pathop.path.as_os_str(),
+        ));
+        #[cfg(not(unix))]
+        list.extend_from_slice(pathop.path.to_string_lossy().as_bytes());
+
+        list.extend_from_slice(separator.as_str().as_bytes());
+    }
+
+    list
+}
+
 pub fn get_longest_common_path(paths: &[PathBuf]) -> Option<String> {
     match paths.len() {
         0 => return None,
@@ -102,11 +129,13 @@ pub fn get_longest_common_path(paths: &[PathBuf]) -> Option<String> {
 #[cfg(test)]
 mod tests {
     use crate::pathop::PathOp;
+    use crate::run::PathSeparator;
     use std::collections::HashSet;
     use std::path::PathBuf;
 
     use super::collect_path_env_vars;
     use super::get_longest_common_path;
+    use super::path_list;
 
     #[test]
     #[cfg(unix)]
@@ -216,23 +245,26 @@ mod tests {
     #[cfg(unix)]
     fn pathops_collect_to_env_vars_unix() {
         assert_eq!(
-            collect_path_env_vars(&[
-                PathOp::new(
-                    &PathBuf::from("/tmp/logs/hi"),
-                    Some(notify::op::CREATE),
-                    None,
-                ),
-                PathOp::new(
-                    &PathBuf::from("/tmp/logs/hey/there"),
-                    Some(notify::op::CREATE),
-                    None,
-                ),
-                PathOp::new(
-                    &PathBuf::from("/tmp/logs/bye"),
-                    Some(notify::op::REMOVE),
-                    None,
-                ),
-            ])
+            collect_path_env_vars(
+                &[
+                    PathOp::new(
+                        &PathBuf::from("/tmp/logs/hi"),
+                        Some(notify::op::CREATE),
+                        None,
+                    ),
+                    PathOp::new(
+                        &PathBuf::from("/tmp/logs/hey/there"),
+                        Some(notify::op::CREATE),
+                        None,
+                    ),
+                    PathOp::new(
+                        &PathBuf::from("/tmp/logs/bye"),
+                        Some(notify::op::REMOVE),
+                        None,
+                    ),
+                ],
+                PathSeparator::Platform,
+            )
             .into_iter()
             .collect::<HashSet<_>>(),
             vec![
@@ -252,23 +284,26 @@ mod tests {
     #[cfg(windows)]
     fn pathops_collect_to_env_vars_windows() {
         assert_eq!(
-            collect_path_env_vars(&[
-                PathOp::new(
-                    &PathBuf::from(r"C:\Temp\Logs\hi"),
-                    Some(notify::op::CREATE),
-                    None,
-                ),
-                PathOp::new(
-                    &PathBuf::from(r"C:\Temp\Logs\hey\there"),
-                    Some(notify::op::CREATE),
-                    None,
-                ),
-                PathOp::new(
-                    &PathBuf::from(r"C:\Temp\Logs\bye"),
-                    Some(notify::op::REMOVE),
-                    None,
-                ),
-            ])
+            collect_path_env_vars(
+                &[
+                    PathOp::new(
+                        &PathBuf::from(r"C:\Temp\Logs\hi"),
+                        Some(notify::op::CREATE),
+                        None,
+                    ),
+                    PathOp::new(
+                        &PathBuf::from(r"C:\Temp\Logs\hey\there"),
+                        Some(notify::op::CREATE),
+                        None,
+                    ),
+                    PathOp::new(
+                        &PathBuf::from(r"C:\Temp\Logs\bye"),
+                        Some(notify::op::REMOVE),
+                        None,
+                    ),
+                ],
+                PathSeparator::Platform,
+            )
             .into_iter()
             .collect::<HashSet<_>>(),
             vec![
@@ -286,4 +321,30 @@ mod tests {
             .collect::<HashSet<_>>()
         );
     }
+
+    #[test]
+    #[cfg(unix)]
+    fn separates_paths() {
+        let ops = [
+            PathOp::new(&PathBuf::from("/tmp/a:b"), Some(notify::op::WRITE), None),
+            PathOp::new(&PathBuf::from("/tmp/c d"), Some(notify::op::WRITE), None),
+        ];
+
+        let vars = collect_path_env_vars(&ops, PathSeparator::Newline);
+        assert!(vars.contains(&(
+            "WATCHEXEC_WRITTEN_PATH".to_string(),
+            "/a:b\n/c d".to_string()
+        )));
+
+        let vars = collect_path_env_vars(&ops, PathSeparator::Nul);
+        assert_eq!(
+            vars,
+            vec![("WATCHEXEC_COMMON_PATH".to_string(), "/tmp".to_string())]
+        );
+
+        assert_eq!(
+            path_list(&ops, PathSeparator::Nul),
+            b"/tmp/a:b\0/tmp/c d\0".to_vec()
+        );
+    }
 }
@@ -0,0 +1,298 @@
+//! Patterns of `.gitignore` and `.ignore` files, which share git's syntax.
+//!
+//! Patterns ending with a slash only match directories, `**` is only special
+//! as a whole path component, and a negated pattern can't re-include a path
+//! if a directory above it is excluded.
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::path::{Path, PathBuf};
+
+/// The patterns from one file, scoped to the directory it's in.
+pub(crate) struct PatternFile {
+    set: GlobSet,
+    patterns: Vec<Pattern>,
+    root: PathBuf,
+}
+
+struct Pattern {
+    pattern: String,
+    pattern_type: PatternType,
+    anchored: bool,
+    dir_only: bool,
+}
+
+enum PatternType {
+    Ignore,
+    Whitelist,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum MatchResult {
+    Ignore,
+    Whitelist,
+    None,
+}
+
+/// The files whose patterns apply to the path, sorted from the top down.
+pub(crate) fn applicable_files<'f>(
+    files: impl Iterator<Item = &'f PatternFile>,
+    path: &Path,
+) -> Vec<&'f PatternFile> {
+    let mut applicable_files: Vec<&PatternFile> =
+        files.filter(|f| path.starts_with(&f.root)).collect();
+    applicable_files.sort_by_key(|f| f.root_len());
+
+    applicable_files
+}
+
+/// Whether the files, sorted from the top down, exclude that (absolute) path.
+///
+/// The filesystem is only checked to know whether the path is a directory if
+/// a pattern ending with a slash matches it. A path which doesn't exist is a
+/// file.
+pub(crate) fn is_excluded(files: &[&PatternFile], path: &Path) -> bool {
+    // Nothing can be re-included from an excluded directory
+    excluded_dir(files, path).is_some()
+        || last_match(files, path, &|| path.is_dir()) == MatchResult::Ignore
+}
+
+/// The topmost directory above that path which the files exclude, if any.
+pub(crate) fn excluded_dir<'p>(files: &[&PatternFile], path: &'p Path) -> Option<&'p Path> {
+    path.ancestors()
+        .skip(1)
+        .filter(|dir| last_match(files, dir, &|| true) == MatchResult::Ignore)
+        .last()
+}
+
+/// The last match among the files, which are sorted from the top down.
+fn last_match(files: &[&PatternFile], path: &Path, is_dir: &dyn Fn() -> bool) -> MatchResult {
+    let mut result = MatchResult::None;
+
+    for file in files {
+        match file.matches(path, is_dir) {
+            MatchResult::Ignore => result = MatchResult::Ignore,
+            MatchResult::Whitelist => result = MatchResult::Whitelist,
+            MatchResult::None => {}
+        }
+    }
+
+    result
+}
+
+impl PatternFile {
+    /// Parse the lines of a file, scoped to `root`.
+    ///
+    /// If `slash_anchors`, a separator in the middle of a pattern anchors it
+    /// to the root, as in `.gitignore` files.
+    pub fn from_strings(
+        strs: &[&str],
+        root: &Path,
+        slash_anchors: bool,
+    ) -> Result<Self, globset::Error> {
+        let mut builder = GlobSetBuilder::new();
+        let mut patterns = vec![];
+
+        let parsed_patterns = strs
+            .iter()
+            .filter(|l| !l.trim_end_matches(' ').is_empty() && !l.starts_with('#'))
+            .map(|l| Pattern::parse(l, slash_anchors));
+        for p in parsed_patterns {
+            let mut pat = p.pattern.clone();
+            if !p.anchored && !pat.starts_with("**/") {
+                pat = "**/".to_string() + &pat;
+            }
+
+            // In globset, a trailing `/**` also matches the directory itself
+            if pat.len() > 3 && pat.ends_with("/**") {
+                pat.insert_str(pat.len() - 3, "/*");
+            }
+
+            let glob = GlobBuilder::new(&pat).literal_separator(true).build()?;
+
+            builder.add(glob);
+            patterns.push(p);
+        }
+
+        Ok(Self {
+            set: builder.build()?,
+            patterns,
+            root: root.to_owned(),
+        })
+    }
+
+    /// Whether the last pattern of this file matching the path or a
+    /// directory above it ignores the path.
+    ///
+    /// Unlike [`is_excluded`], this doesn't look at other files, and so lets
+    /// a negated pattern re-include a path from an excluded directory.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        let last = path
+            .ancestors()
+            .skip(1)
+            .map(|dir| self.last_index(dir, &|| true))
+            .fold(self.last_index(path, &|| path.is_dir()), Option::max);
+
+        match last.map(|i| &self.patterns[i].pattern_type) {
+            Some(PatternType::Ignore) => true,
+            Some(PatternType::Whitelist) | None => false,
+        }
+    }
+
+    /// The directory the patterns apply to.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn root_len(&self) -> usize {
+        self.root.as_os_str().len()
+    }
+
+    /// The last pattern matching the path. `is_dir` is only called if that
+    /// may be a pattern ending with a slash.
+    pub fn matches(&self, path: &Path, is_dir: &dyn Fn() -> bool) -> MatchResult {
+        match self.last_index(path, is_dir) {
+            Some(i) => match self.patterns[i].pattern_type {
+                PatternType::Whitelist => MatchResult::Whitelist,
+                PatternType::Ignore => MatchResult::Ignore,
+            },
+            None => MatchResult::None,
+        }
+    }
+
+    fn last_index(&self, path: &Path, is_dir: &dyn Fn() -> bool) -> Option<usize> {
+        let stripped = path.strip_prefix(&self.root).ok()?;
+        if stripped.as_os_str().is_empty() {
+            return None;
+        }
+
+        let mut dir = None;
+        self.set
+            .matches(stripped)
+            .into_iter()
+            .rev()
+            .find(|i| !self.patterns[*i].dir_only || *dir.get_or_insert_with(is_dir))
+    }
+}
+
+impl Pattern {
+    fn parse(pattern: &str, slash_anchors: bool) -> Self {
+        let mut normalized = String::from(pattern);
+
+        // Trailing spaces are ignored unless escaped
+        while normalized.ends_with(' ') && !normalized.ends_with("\\ ") {
+            normalized.pop();
+        }
+
+        let pattern_type = if normalized.starts_with('!') {
+            normalized.remove(0);
+            PatternType::Whitelist
+        } else {
+            PatternType::Ignore
+        };
+
+        let anchored = if normalized.starts_with('/') {
+            normalized.remove(0);
+            true
+        } else {
+            false
+        };
+
+        let dir_only = normalized.ends_with('/');
+        if dir_only {
+            normalized.pop();
+        }
+
+        let anchored = anchored || (slash_anchors && normalized.contains('/'));
+
+        if normalized.starts_with("\\#") || normalized.starts_with("\\!") {
+            normalized.remove(0);
+        }
+
+        Self {
+            pattern: collapse_asterisks(&normalized),
+            pattern_type,
+            anchored,
+            dir_only,
+        }
+    }
+}
+
+/// Only `**` as a whole path component is special: other runs of asterisks
+/// are the same as a single one.
+fn collapse_asterisks(pattern: &str) -> String {
+    let mut out = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            out.push(c);
+            out.extend(chars.next());
+            continue;
+        }
+
+        if c != '*' {
+            out.push(c);
+            continue;
+        }
+
+        let mut run = 1;
+        while chars.peek() == Some(&'*') {
+            chars.next();
+            run += 1;
+        }
+
+        let component_start = out.is_empty() || out.ends_with('/');
+        let component_end = matches!(chars.peek(), None | Some('/'));
+        if run == 2 && component_start && component_end {
+            out.push_str("**");
+        } else {
+            out.push('*');
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{collapse_asterisks, MatchResult, PatternFile};
+    use std::{cell::Cell, path::PathBuf};
+
+    fn base_dir() -> PathBuf {
+        PathBuf::from("/home/user/dir")
+    }
+
+    #[test]
+    fn collapses_asterisks() {
+        assert_eq!(collapse_asterisks("**/a/**/b/**"), "**/a/**/b/**");
+        assert_eq!(collapse_asterisks("a**b/***/c**"), "a*b/*/c*");
+        assert_eq!(collapse_asterisks("\\**"), "\\**");
+    }
+
+    #[test]
+    fn only_checks_directory_for_trailing_slash() {
+        let file = PatternFile::from_strings(&["*.o", "build/"], &base_dir(), true)
+            .expect("valid patterns");
+        let checked = Cell::new(0);
+        let is_dir = || {
+            checked.set(checked.get() + 1);
+            true
+        };
+
+        assert_eq!(
+            file.matches(&base_dir().join("a.o"), &is_dir),
+            MatchResult::Ignore
+        );
+        assert_eq!(
+            file.matches(&base_dir().join("a.rs"), &is_dir),
+            MatchResult::None
+        );
+        assert_eq!(checked.get(), 0);
+
+        assert_eq!(
+            file.matches(&base_dir().join("build"), &is_dir),
+            MatchResult::Ignore
+        );
+        assert_eq!(checked.get(), 1);
+    }
+}
@@ -0,0 +1,340 @@
+//! Recording and replaying event streams.
+//!
+//! When [`Config.record_events`][crate::config::Config] is set, every event
+//! received by the watch loop is appended to that file, with the time it was
+//! received at. Such a recording can later be fed through the same pipeline
+//! with [`replay`], at the original speed or faster, which is useful to
+//! reproduce reports like "my build triggered twice".
+//!
+//! The format is one event per line, with tab-separated fields: milliseconds
+//! since the start of the recording, the raw notify op bits (or `-` if the
+//! backend reported an error), the rename cookie (or `-`), and the path (empty
+//! if there was none) with backslashes and newlines escaped.
+
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Receiver},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use notify::op::Op;
+
+use crate::clock::Clock;
+use crate::debounce::{Debouncer, Source};
+use crate::error::{Error, Result};
+use crate::events::{canonical_paths, load_filter};
+use crate::run::{watch_loop, Handler, Interrupts};
+use crate::watcher::{Event, Injector};
+
+/// Appends events to a recording file.
+pub struct Recorder {
+    file: BufWriter<File>,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Create (or truncate) the recording file at `path`.
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        Ok(Self {
+            file: BufWriter::new(file),
+            start: Instant::now(),
+        })
+    }
+
+    /// Record an event as received now.
+    ///
+    /// Lines are flushed immediately, so that a recording is useful even when
+    /// the process is killed.
+    pub fn record(&mut self, event: &Event) -> Result<()> {
+        let line = encode(self.start.elapsed(), event);
+        writeln!(self.file, "{}", line)?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Record an event, logging rather than returning errors.
+    pub(crate) fn record_or_warn(&mut self, event: &Event) {
+        self.record(event)
+            .unwrap_or_else(|err| warn!("Could not record event: {}", err));
+    }
+}
+
+/// Load a recording from a file.
+pub fn load(path: &Path) -> Result<Vec<(Duration, Event)>> {
+    let file = BufReader::new(File::open(path)?);
+    let mut events = Vec::new();
+    for (n, line) in file.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+
+        events.push(decode(&line).ok_or_else(|| {
+            Error::Generic(format!("invalid recording line {} in {:?}", n + 1, path))
+        })?);
+    }
+
+    Ok(events)
+}
+
+/// Replay a recording through the watch loop, blocking until done.
+///
+/// The handler's config is used as for [`watch`][crate::run::watch], except
+/// that nothing is actually watched: events come from the recording instead.
+/// The loop stops at the end of the recording, or earlier if the handler asks.
+///
+/// `speed` scales the time between events: `1.0` is the original speed, `2.0`
+/// is twice as fast, etc. It must be positive. Waiting is done with the
+/// config's clock, so with a [`MockClock`][crate::testing::MockClock] the
+/// replay happens instantly, but with batches as they were originally.
+pub fn replay<H>(handler: &H, recording: &Path, speed: f64) -> Result<()>
+where
+    H: Handler,
+{
+    if speed.is_nan() || speed <= 0.0 {
+        return Err(Error::Generic(format!(
+            "replay speed must be positive, got {}",
+            speed
+        )));
+    }
+
+    let args = handler.args();
+    let paths = canonical_paths(&args)?;
+    let filter = load_filter(&args, &paths)?;
+
+    let (tx, injected) = channel();
+    let (requests_tx, requests) = channel();
+    let source = ReplaySource {
+        events: load(recording)?.into(),
+        speed,
+        start: args.clock.now(),
+        clock: args.clock.clone(),
+        injected,
+    };
+
+    let debouncer = Debouncer::new(
+        source,
+        |path: &Path| filter.is_excluded(path),
+        args.debounce,
+    )
+    .no_meta(args.no_meta);
+
+    watch_loop(
+        handler,
+        &args,
+        Injector::new(tx, requests_tx),
+        Interrupts {
+            requests: Some(requests),
+            ..Interrupts::default()
+        },
+        debouncer,
+    )
+}
+
+struct ReplaySource {
+    events: VecDeque<(Duration, Event)>,
+    speed: f64,
+    start: Instant,
+    clock: Arc<dyn Clock>,
+    injected: Receiver<Event>,
+}
+
+impl ReplaySource {
+    /// How long from now until the next recorded event is due.
+    fn until_next(&self) -> Option<Duration> {
+        self.events.front().map(|(at, _)| {
+            Duration::from_secs_f64(at.as_secs_f64() / self.speed)
+                .checked_sub(self.clock.since(self.start))
+                .unwrap_or_default()
+        })
+    }
+}
+
+impl Source for ReplaySource {
+    fn recv(&mut self) -> Option<Event> {
+        if let Ok(event) = self.injected.try_recv() {
+            return Some(event);
+        }
+
+        self.clock.sleep(self.until_next()?);
+        self.events.pop_front().map(|(_, event)| event)
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> Option<Event> {
+        if let Ok(event) = self.injected.try_recv() {
+            return Some(event);
+        }
+
+        match self.until_next() {
+            Some(due) if due <= timeout => {
+                self.clock.sleep(due);
+                self.events.pop_front().map(|(_, event)| event)
+            }
+            Some(_) => {
+                self.clock.sleep(timeout);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+fn encode(at: Duration, event: &Event) -> String {
+    let op = match event.op {
+        Ok(op) => op.bits().to_string(),
+        Err(_) => "-".into(),
+    };
+    let cookie = event
+        .cookie
+        .map_or_else(|| "-".into(), |cookie| cookie.to_string());
+    let path = event
+        .path
+        .as_ref()
+        .map_or_else(String::new, |path| escape(&path.to_string_lossy()));
+
+    format!("{}\t{}\t{}\t{}", at.as_millis(), op, cookie, path)
+}
+
+fn decode(line: &str) -> Option<(Duration, Event)> {
+    let mut fields = line.splitn(4, '\t');
+    let at = Duration::from_millis(fields.next()?.parse().ok()?);
+    let op = match fields.next()? {
+        "-" => Err(notify::Error::Generic("recorded error".into())),
+        bits => Ok(Op::from_bits(bits.parse().ok()?)?),
+    };
+    let cookie = match fields.next()? {
+        "-" => None,
+        cookie => Some(cookie.parse().ok()?),
+    };
+    let path = match fields.next()? {
+        "" => None,
+        path => Some(PathBuf::from(unescape(path))),
+    };
+
+    Some((at, Event { path, op, cookie }))
+}
+
+/// Escape backslashes and newlines, so that anything fits on a line.
+pub(crate) fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+/// Undo [`escape`].
+pub(crate) fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => out.push('\n'),
+                Some(other) => out.push(other),
+                None => out.push('\\'),
+            }
+        } else {
+            out.push(c);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, ReplaySource};
+    use crate::clock::Clock;
+    use crate::debounce::Source;
+    use crate::testing::MockClock;
+    use crate::watcher::Event;
+    use std::{path::PathBuf, sync::mpsc::channel, sync::Arc, time::Duration};
+
+    fn event(path: &str) -> Event {
+        Event {
+            path: Some(PathBuf::from(path)),
+            op: Ok(notify::op::WRITE | notify::op::CLOSE_WRITE),
+            cookie: Some(42),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let line = encode(
+            Duration::from_millis(1234),
+            &event("/tmp/odd\\name\nhere\tx"),
+        );
+        assert!(!line.contains('\n'));
+
+        let (at, decoded) = decode(&line).expect("valid line");
+        assert_eq!(at, Duration::from_millis(1234));
+        assert_eq!(decoded.path, Some(PathBuf::from("/tmp/odd\\name\nhere\tx")));
+        assert_eq!(
+            decoded.op.ok(),
+            Some(notify::op::WRITE | notify::op::CLOSE_WRITE)
+        );
+        assert_eq!(decoded.cookie, Some(42));
+    }
+
+    #[test]
+    fn roundtrip_without_path_or_op() {
+        let line = encode(
+            Duration::from_millis(5),
+            &Event {
+                path: None,
+                op: Err(notify::Error::Generic("oops".into())),
+                cookie: None,
+            },
+        );
+
+        let (_, decoded) = decode(&line).expect("valid line");
+        assert_eq!(decoded.path, None);
+        assert!(decoded.op.is_err());
+        assert_eq!(decoded.cookie, None);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(decode("not a recording").is_none());
+        assert!(decode("12\tfoo\t-\t/a").is_none());
+    }
+
+    #[test]
+    fn replay_keeps_gaps_at_speed() {
+        let clock = MockClock::new();
+        let (_tx, injected) = channel();
+        let mut source = ReplaySource {
+            events: vec![
+                (Duration::from_millis(0), event("/a")),
+                (Duration::from_millis(1), event("/b")),
+                (Duration::from_millis(10_000), event("/c")),
+            ]
+            .into(),
+            speed: 2.0,
+            start: clock.now(),
+            clock: Arc::new(clock.clone()),
+            injected,
+        };
+
+        let timeout = Duration::from_millis(100);
+        assert!(source.recv().is_some());
+        assert!(source.recv_timeout(timeout).is_some());
+        assert!(source.recv_timeout(timeout).is_none());
+        assert_eq!(
+            source.recv().and_then(|e| e.path),
+            Some(PathBuf::from("/c"))
+        );
+        assert!(source.recv().is_none());
+        assert_eq!(clock.elapsed(), Duration::from_millis(5000));
+    }
+}
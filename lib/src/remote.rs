@@ -0,0 +1,419 @@
+//! Watching on another machine.
+//!
+//! Some setups don't deliver filesystem events locally, like Docker volumes on
+//! macOS or network shares. There, a small agent can watch the paths where
+//! the changes happen, with [`serve`] or [`listen`], and stream them to the
+//! watchexec process which runs the commands, configured with
+//! [`Config.remote_agent`][crate::config::Config] to connect to it.
+//!
+//! The agent filters and debounces changes according to its own config, and
+//! sends their paths relative to the watched path they're in. On the other
+//! end, they're resolved against the local watched path at the same position
+//! in `paths`, then injected into the watch loop like any other event. So the
+//! agent could watch `/app` in a container while watchexec watches `./src`,
+//! where that volume is mounted from.
+//!
+//! Agents can be reached over TCP, or through the output of a command, e.g.
+//! `ssh host my-agent` for an agent which calls [`serve`] with stdout.
+//!
+//! The protocol is line-based: a `watchexec-remote 1` header, then one line
+//! per changed path, with tab-separated fields: the index of the watched
+//! path, the raw notify op bits (or `-`), the rename cookie (or `-`), and the
+//! relative path with backslashes and newlines escaped.
+//!
+//! # Examples
+//!
+//! An agent serving clients on port 4000:
+//!
+//! ```no_run
+//! # use watchexec::{config::ConfigBuilder, remote};
+//! let config = ConfigBuilder::default()
+//!     .cmd(vec!["true".into()]) // required, but not run by the agent
+//!     .paths(vec!["/app".into()])
+//!     .build()
+//!     .expect("valid config");
+//! remote::listen(&config, "0.0.0.0:4000").expect("agent failed");
+//! ```
+
+use std::{
+    io::{BufRead, BufReader, BufWriter, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread,
+};
+
+use log::{debug, info, warn};
+use notify::op::{self, Op};
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::events::{canonical_paths, Events};
+use crate::pathop::PathOp;
+use crate::record::{escape, unescape};
+use crate::watcher::Injector;
+
+const HEADER: &str = "watchexec-remote 1";
+
+/// How to reach a remote agent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RemoteAgent {
+    /// connect to an agent listening at that address
+    Tcp(String),
+
+    /// run that command (without a shell), and read events from its output
+    Command(Vec<String>),
+}
+
+/// Watch the paths in the config, and send changes to `out` until it fails.
+///
+/// Returns `Ok` when the other end is gone.
+pub fn serve<W: Write>(args: &Config, out: W) -> Result<()> {
+    let roots = canonical_paths(args)?;
+    let events = Events::new(args)?;
+
+    let mut out = BufWriter::new(out);
+    let sent = writeln!(out, "{}", HEADER).and_then(|_| out.flush());
+    if sent.is_err() {
+        return Ok(());
+    }
+
+    for batch in events {
+        for pathop in &batch {
+            if let Some(line) = encode(&roots, pathop) {
+                if writeln!(out, "{}", line).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+
+        if out.flush().is_err() {
+            return Ok(());
+        }
+    }
+
+    Ok(())
+}
+
+/// Listen on a TCP address, serving each client from its own thread, forever.
+///
+/// A client which is gone is only noticed once there's a change to send it,
+/// but it doesn't hold up the others meanwhile.
+pub fn listen(args: &Config, addr: &str) -> Result<()> {
+    // Fail here rather than for every client
+    canonical_paths(args)?;
+
+    let listener = TcpListener::bind(addr)?;
+    info!("Remote agent listening on {}", listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        let (stream, peer) = match stream.and_then(|s| s.peer_addr().map(|peer| (s, peer))) {
+            Ok(client) => client,
+            Err(err) => {
+                warn!("Could not accept remote client: {}", err);
+                continue;
+            }
+        };
+
+        info!("Serving {}", peer);
+        let args = args.clone();
+        thread::spawn(move || match serve(&args, stream) {
+            Ok(()) => info!("Client {} gone", peer),
+            Err(err) => warn!("Could not serve {}: {}", peer, err),
+        });
+    }
+
+    Ok(())
+}
+
+/// A connection to an agent, which stops the agent's command (if any) when
+/// dropped.
+pub(crate) struct Connection {
+    child: Option<Child>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(ref mut child) = self.child {
+            // It may have exited already, but still has to be waited for
+            child.kill().ok();
+            child.wait().ok();
+        }
+    }
+}
+
+/// Connect to the agent, and inject its events from a thread.
+///
+/// Fails if the agent can't be reached. Once connected, losing the agent is
+/// only logged, and watching carries on locally until the connection is
+/// dropped.
+pub(crate) fn connect(
+    agent: &RemoteAgent,
+    args: &Config,
+    injector: Injector,
+) -> Result<Connection> {
+    let mut connection = Connection { child: None };
+    let reader: Box<dyn BufRead + Send> = match agent {
+        RemoteAgent::Tcp(addr) => Box::new(BufReader::new(TcpStream::connect(addr)?)),
+        RemoteAgent::Command(cmd) => {
+            let (program, args) = cmd
+                .split_first()
+                .ok_or_else(|| Error::Generic("remote agent command is empty".into()))?;
+            let child = connection.child.get_or_insert(
+                Command::new(program)
+                    .args(args)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::piped())
+                    .spawn()?,
+            );
+            Box::new(BufReader::new(child.stdout.take().ok_or_else(|| {
+                Error::Generic("no output from remote agent command".into())
+            })?))
+        }
+    };
+
+    let mut lines = reader.lines();
+    match lines.next() {
+        Some(Ok(ref header)) if header == HEADER => {}
+        other => {
+            return Err(Error::Generic(format!(
+                "unexpected remote agent header: {:?}",
+                other
+            )))
+        }
+    }
+
+    info!("Connected to remote agent {:?}", agent);
+    let roots = canonical_paths(args)?;
+    thread::spawn(move || {
+        for line in lines {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    warn!("Lost the remote agent: {}", err);
+                    return;
+                }
+            };
+
+            match decode(&roots, &line) {
+                Some(pathop) => {
+                    debug!("Remote change: {:?}", pathop);
+                    if injector.pathop(pathop).is_err() {
+                        return;
+                    }
+                }
+                None => warn!("Invalid line from remote agent: {:?}", line),
+            }
+        }
+
+        warn!("The remote agent is gone");
+    });
+
+    Ok(connection)
+}
+
+fn encode(roots: &[PathBuf], pathop: &PathOp) -> Option<String> {
+    let (index, relative) = roots
+        .iter()
+        .enumerate()
+        .find_map(|(i, root)| Some((i, pathop.path.strip_prefix(root).ok()?)))?;
+
+    let op = pathop
+        .op
+        .map_or_else(|| "-".into(), |op| op.bits().to_string());
+    let cookie = pathop
+        .cookie
+        .map_or_else(|| "-".into(), |cookie| cookie.to_string());
+
+    Some(format!(
+        "{}\t{}\t{}\t{}",
+        index,
+        op,
+        cookie,
+        escape(&relative.to_string_lossy())
+    ))
+}
+
+fn decode(roots: &[PathBuf], line: &str) -> Option<PathOp> {
+    let mut fields = line.splitn(4, '\t');
+    let root = roots.get(fields.next()?.parse::<usize>().ok()?)?;
+    let op = match fields.next()? {
+        // A change without more detail is as good as a write
+        "-" => op::WRITE,
+        bits => Op::from_bits(bits.parse().ok()?)?,
+    };
+    let cookie = match fields.next()? {
+        "-" => None,
+        cookie => Some(cookie.parse().ok()?),
+    };
+    let relative = PathBuf::from(unescape(fields.next()?));
+
+    Some(PathOp::new(&join(root, &relative), Some(op), cookie))
+}
+
+/// Resolve a relative path from the agent, without escaping the root.
+fn join(root: &Path, relative: &Path) -> PathBuf {
+    let mut path = root.to_path_buf();
+    for component in relative.components() {
+        if let std::path::Component::Normal(part) = component {
+            path.push(part);
+        }
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect, decode, encode, join, listen, serve, RemoteAgent, HEADER};
+    use crate::config::ConfigBuilder;
+    use crate::pathop::PathOp;
+    use crate::watcher::Injector;
+    use std::{
+        env, fs,
+        io::{BufRead, BufReader},
+        net::{TcpListener, TcpStream},
+        path::{Path, PathBuf},
+        process,
+        sync::mpsc::channel,
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn maps_between_roots() {
+        let remote = [PathBuf::from("/other"), PathBuf::from("/app")];
+        let local = [
+            PathBuf::from("/home/me/other"),
+            PathBuf::from("/home/me/src"),
+        ];
+
+        let line = encode(
+            &remote,
+            &PathOp::new(
+                Path::new("/app/lib/odd\nname.rs"),
+                Some(notify::op::CREATE),
+                Some(3),
+            ),
+        )
+        .expect("path under a root");
+
+        let pathop = decode(&local, &line).expect("valid line");
+        assert_eq!(pathop.path, Path::new("/home/me/src/lib/odd\nname.rs"));
+        assert_eq!(pathop.op, Some(notify::op::CREATE));
+        assert_eq!(pathop.cookie, Some(3));
+    }
+
+    #[test]
+    fn skips_paths_outside_roots() {
+        let roots = [PathBuf::from("/app")];
+        assert!(encode(&roots, &PathOp::new(Path::new("/etc/passwd"), None, None)).is_none());
+        assert!(decode(&roots, "1\t-\t-\tfile").is_none());
+        assert_eq!(
+            join(&roots[0], Path::new("../../etc/passwd")),
+            Path::new("/app/etc/passwd")
+        );
+    }
+
+    #[test]
+    fn streams_changes() {
+        let dir = env::temp_dir().join(format!("watchexec-remote-{}", process::id()));
+        fs::create_dir_all(&dir).expect("create dir");
+        let config = ConfigBuilder::default()
+            .cmd(vec!["true".into()])
+            .paths(vec![dir.clone()])
+            .build()
+            .expect("valid config");
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr").to_string();
+        let agent_config = config.clone();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().expect("accept");
+            serve(&agent_config, stream).expect("served");
+        });
+
+        let (tx, rx) = channel();
+        let (requests, _) = channel();
+        connect(
+            &RemoteAgent::Tcp(addr),
+            &config,
+            Injector::new(tx, requests),
+        )
+        .expect("connected");
+        fs::write(dir.join("changed"), "hi").expect("write");
+
+        let event = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("remote event");
+        let expected = fs::canonicalize(&dir).expect("canonical").join("changed");
+        fs::remove_dir_all(&dir).ok();
+        assert_eq!(event.path, Some(expected));
+    }
+
+    #[test]
+    fn serves_clients_at_once() {
+        let config = ConfigBuilder::default()
+            .cmd(vec!["true".into()])
+            .paths(vec![env::temp_dir()])
+            .build()
+            .expect("valid config");
+
+        // Find a free port
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .expect("free port");
+        thread::spawn(move || listen(&config, &addr.to_string()));
+
+        let header = || {
+            let stream = (0..50)
+                .find_map(|_| {
+                    TcpStream::connect(addr)
+                        .map_err(|_| thread::sleep(Duration::from_millis(100)))
+                        .ok()
+                })
+                .expect("connect");
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .expect("timeout");
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).expect("header");
+            (reader, line)
+        };
+
+        // The first client stays connected while the second is served
+        let (_first, line) = header();
+        assert_eq!(line.trim_end(), HEADER);
+        let (_second, line) = header();
+        assert_eq!(line.trim_end(), HEADER);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stops_agent_command() {
+        let config = ConfigBuilder::default()
+            .cmd(vec!["true".into()])
+            .paths(vec![env::temp_dir()])
+            .build()
+            .expect("valid config");
+
+        let (tx, _rx) = channel();
+        let (requests, _) = channel();
+        let connection = connect(
+            &RemoteAgent::Command(vec![
+                "sh".into(),
+                "-c".into(),
+                "echo 'watchexec-remote 1'; exec sleep 10".into(),
+            ]),
+            &config,
+            Injector::new(tx, requests),
+        )
+        .expect("connected");
+        let pid = connection.child.as_ref().expect("agent command").id();
+        drop(connection);
+
+        // Not even a zombie is left
+        let pid = nix::unistd::Pid::from_raw(pid as i32);
+        assert!(nix::sys::signal::kill(pid, None).is_err());
+    }
+}
@@ -0,0 +1,179 @@
+//! Resource usage of commands, for [`Config.resource_limits`][crate::config::Config].
+//!
+//! This reads procfs, and so is only supported on Linux.
+
+use std::time::Duration;
+
+use crate::run::ResourceLimits;
+
+/// What a command (or its process group) uses at some point.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Usage {
+    /// resident memory, in bytes
+    pub memory: u64,
+
+    /// CPU time used so far, in clock ticks
+    pub cpu_ticks: u64,
+}
+
+pub(crate) const fn is_supported() -> bool {
+    cfg!(target_os = "linux")
+}
+
+/// The usage of a process, or of all the processes in its group.
+///
+/// Returns `None` once the process is gone.
+#[cfg(target_os = "linux")]
+pub(crate) fn usage(pid: u32, group: bool) -> Option<Usage> {
+    use std::fs;
+
+    if !group {
+        return stat(&fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?)
+            .map(|(_, usage)| usage);
+    }
+
+    let mut total = None;
+    for entry in fs::read_dir("/proc").ok()?.filter_map(Result::ok) {
+        let is_pid = entry
+            .file_name()
+            .to_str()
+            .map_or(false, |name| name.bytes().all(|b| b.is_ascii_digit()));
+        if !is_pid {
+            continue;
+        }
+
+        // Processes can exit at any time, and then can't be read
+        if let Some((pgrp, usage)) = fs::read_to_string(entry.path().join("stat"))
+            .ok()
+            .and_then(|content| stat(&content))
+        {
+            if pgrp == pid {
+                let total = total.get_or_insert_with(Usage::default);
+                total.memory += usage.memory;
+                total.cpu_ticks += usage.cpu_ticks;
+            }
+        }
+    }
+
+    total
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) const fn usage(_pid: u32, _group: bool) -> Option<Usage> {
+    None
+}
+
+/// Parse `/proc/<pid>/stat`, see proc(5), into the process group and usage.
+#[cfg(target_os = "linux")]
+fn stat(content: &str) -> Option<(u32, Usage)> {
+    use nix::unistd::{sysconf, SysconfVar};
+
+    // The command name may contain anything, so skip it from the end
+    let fields: Vec<&str> = content[content.rfind(')')? + 1..]
+        .split_whitespace()
+        .collect();
+    let field = |n: usize| -> Option<u64> { fields.get(n - 3)?.parse().ok() };
+
+    let page_size = sysconf(SysconfVar::PAGE_SIZE)
+        .ok()
+        .flatten()
+        .unwrap_or(4096);
+    let usage = Usage {
+        memory: field(24)?.saturating_mul(page_size as u64),
+        cpu_ticks: field(14)? + field(15)?,
+    };
+
+    Some((field(5)? as u32, usage))
+}
+
+#[cfg(target_os = "linux")]
+fn ticks_per_second() -> u64 {
+    use nix::unistd::{sysconf, SysconfVar};
+
+    sysconf(SysconfVar::CLK_TCK)
+        .ok()
+        .flatten()
+        .map_or(100, |ticks| ticks as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+const fn ticks_per_second() -> u64 {
+    100
+}
+
+/// Which limit is exceeded, if any, given the usage `elapsed` ago.
+pub(crate) fn exceeded(
+    limits: &ResourceLimits,
+    usage: &Usage,
+    previous: Option<&Usage>,
+    elapsed: Duration,
+) -> Option<String> {
+    if let Some(max) = limits.memory {
+        if usage.memory > max {
+            return Some(format!(
+                "using {} bytes of memory, over {}",
+                usage.memory, max
+            ));
+        }
+    }
+
+    if let (Some(max), Some(previous)) = (limits.cpu, previous) {
+        let seconds = elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            let ticks = usage.cpu_ticks.saturating_sub(previous.cpu_ticks);
+            let percent = ticks as f64 / ticks_per_second() as f64 / seconds * 100.0;
+            if percent > max {
+                return Some(format!("using {:.0}% CPU, over {:.0}%", percent, max));
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{exceeded, Usage};
+    use crate::run::ResourceLimits;
+    use std::time::Duration;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reads_own_usage() {
+        let usage = super::usage(std::process::id(), false).expect("running");
+        assert!(usage.memory > 0);
+    }
+
+    #[test]
+    fn checks_limits() {
+        let limits = ResourceLimits {
+            memory: Some(1000),
+            cpu: Some(50.0),
+        };
+        let second = Duration::from_secs(1);
+        let idle = Usage {
+            memory: 10,
+            cpu_ticks: 0,
+        };
+
+        assert!(exceeded(&limits, &idle, None, second).is_none());
+        assert!(exceeded(
+            &limits,
+            &Usage {
+                memory: 2000,
+                ..idle
+            },
+            None,
+            second
+        )
+        .is_some());
+
+        let busy = Usage {
+            cpu_ticks: super::ticks_per_second(),
+            ..idle
+        };
+        assert!(exceeded(&limits, &busy, None, second).is_none());
+        assert!(exceeded(&limits, &busy, Some(&idle), second).is_some());
+        assert!(exceeded(&limits, &busy, Some(&idle), second * 4).is_none());
+    }
+}
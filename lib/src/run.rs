@@ -1,27 +1,34 @@
-#[cfg(unix)]
-use command_group::UnixChildExt;
-use command_group::{CommandGroup, GroupChild};
 use log::{debug, info, warn};
 
 use std::{
     collections::HashMap,
-    fs::canonicalize,
-    process::Child,
+    io, mem,
+    path::{Path, PathBuf},
     sync::{
+        atomic::{AtomicBool, Ordering},
         mpsc::{channel, Receiver},
         Arc, Mutex,
     },
-    time::Duration,
+    thread,
+    time::Instant,
 };
 
+use crate::audit::AuditLog;
+use crate::clock;
 use crate::config::Config;
+use crate::debounce::{Debouncer, Source};
 use crate::error::{Error, Result};
-use crate::gitignore;
-use crate::ignore;
-use crate::notification_filter::NotificationFilter;
-use crate::pathop::PathOp;
-use crate::signal::{self, Signal};
-use crate::watcher::{Event, Watcher};
+use crate::events::{Event as HandlerEvent, Events, Origin};
+use crate::pathop::{self, PathOp, Respelling};
+use crate::record::Recorder;
+use crate::remote;
+use crate::signal::Signal;
+use crate::systemd::{self, Watchdog};
+use crate::trigger;
+use crate::watcher::{Event, Injector};
+
+pub use crate::actions::{run_once, ChildProcess, ExecHandler};
+pub use crate::ignore::IgnoreSearch;
 
 /// Behaviour to use when handling updates while the command is running.
 #[derive(Clone, Copy, Debug)]
@@ -29,7 +36,7 @@ pub enum OnBusyUpdate {
     /// ignore updates while busy
     DoNothing,
 
-    /// wait for the command to exit, then start a new one
+    /// wait for the command to exit, then start a new one, see `queue_policy`
     Queue,
 
     /// restart the command immediately
@@ -45,6 +52,136 @@ impl Default for OnBusyUpdate {
     }
 }
 
+/// A command run alongside the main one, see `Config.commands`.
+#[derive(Clone, Debug, Default)]
+pub struct CommandSpec {
+    /// what to call it in logs and the audit log
+    pub name: String,
+
+    /// the command to run, as for `Config.cmd`
+    pub cmd: Vec<String>,
+
+    /// what to do with updates while it runs
+    pub on_busy_update: OnBusyUpdate,
+}
+
+/// How to tell that a run of the command is ready, e.g. serving requests.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Readiness {
+    /// a line of its output (stdout or stderr) contains that text
+    Line(String),
+
+    /// a TCP connection to that address succeeds
+    Port(String),
+}
+
+/// What to keep of the updates waiting for a run with [`OnBusyUpdate::Queue`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum QueuePolicy {
+    /// keep every batch, each getting its own run, in order
+    Unbounded,
+
+    /// merge all waiting batches into one, for a single run (the default)
+    Collapse,
+
+    /// keep at most that many batches, dropping any further ones
+    Cap(usize),
+
+    /// keep at most that many batches, dropping the oldest to make room
+    DropOldest(usize),
+}
+
+impl Default for QueuePolicy {
+    fn default() -> Self {
+        Self::Collapse
+    }
+}
+
+/// How paths are separated in the lists handed to the command.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PathSeparator {
+    /// `:` on Unix and `;` on Windows, as in `PATH`
+    Platform,
+
+    /// a newline
+    Newline,
+
+    /// a NUL byte, as for `xargs -0`
+    ///
+    /// Environment variables can't contain NUL, so the `WATCHEXEC_*_PATH`
+    /// variables listing changed paths are then not set, see
+    /// `Config.paths_on_stdin` instead.
+    Nul,
+}
+
+impl PathSeparator {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            #[cfg(unix)]
+            Self::Platform => ":",
+            #[cfg(not(unix))]
+            Self::Platform => ";",
+            Self::Newline => "\n",
+            Self::Nul => "\0",
+        }
+    }
+}
+
+impl Default for PathSeparator {
+    fn default() -> Self {
+        Self::Platform
+    }
+}
+
+/// Usage above which a command is restarted, see `Config.resource_limits`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ResourceLimits {
+    /// resident memory of the command and its process group, in bytes
+    pub memory: Option<u64>,
+
+    /// CPU usage of the command and its process group, in percent of one
+    /// core, over each `resource_poll` interval
+    pub cpu: Option<f64>,
+}
+
+/// What to do with the Docker container, see [`crate::docker`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ContainerAction {
+    /// stop and start the container
+    Restart,
+
+    /// send a signal (e.g. `SIGHUP`) to the container's main process
+    Signal(String),
+}
+
+impl Default for ContainerAction {
+    fn default() -> Self {
+        Self::Restart
+    }
+}
+
+/// What to do with a signal received while watching.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SignalAction {
+    /// do nothing
+    Ignore,
+
+    /// pass it to the handler as an event, e.g. for the command
+    Forward,
+
+    /// trigger a run, as for a manual request
+    Run,
+
+    /// pass it to the handler as an event, then stop watching
+    Shutdown,
+}
+
+impl Default for SignalAction {
+    fn default() -> Self {
+        Self::Shutdown
+    }
+}
+
 pub trait Handler {
     /// Called through a manual request, such as an initial run.
     ///
@@ -55,7 +192,11 @@ pub trait Handler {
     /// - `Err`: an error has occurred while processing, quit.
     /// - `Ok(true)`: everything is fine and the loop can continue.
     /// - `Ok(false)`: everything is fine but we should gracefully stop.
-    fn on_manual(&self) -> Result<bool>;
+    ///
+    /// Does nothing by default.
+    fn on_manual(&self) -> Result<bool> {
+        Ok(true)
+    }
 
     /// Called through a file-update request.
     ///
@@ -70,7 +211,79 @@ pub trait Handler {
     /// - `Err`: an error has occurred while processing, quit.
     /// - `Ok(true)`: everything is fine and the loop can continue.
     /// - `Ok(false)`: everything is fine but we should gracefully stop.
-    fn on_update(&self, ops: &[PathOp]) -> Result<bool>;
+    ///
+    /// Does nothing by default.
+    fn on_update(&self, _ops: &[PathOp]) -> Result<bool> {
+        Ok(true)
+    }
+
+    /// Called with everything the handler is invoked for.
+    ///
+    /// This is what `watch` calls: a `Manual` event for the initial run, the
+    /// `FsChange`s of a batch all together, `Signal`s depending on what
+    /// [`on_signal`][Handler::on_signal] returns, and events from other
+    /// sources (see [`Injector::deliver`]). Each call starts with a `Source`
+    /// event saying where the rest comes from. Handlers wanting the full
+    /// context of why they were invoked can implement this instead of
+    /// [`on_manual`][Handler::on_manual] and [`on_update`][Handler::on_update].
+    ///
+    /// The return value has the same meaning as for `on_update`.
+    ///
+    /// By default, calls `on_manual` for a `Manual` or `Tick` event, and `on_update`
+    /// with the path ops of all `FsChange`s, if there are any. Other events
+    /// are ignored. See [`dispatch`].
+    fn on_event(&self, events: &[HandlerEvent]) -> Result<bool> {
+        dispatch(self, events)
+    }
+
+    /// Called by `watch` when a signal is received, to decide what to do.
+    ///
+    /// `SIGCHLD` is not passed on. Note that with the default
+    /// [`signal_source`][Config::signal_source], a second `SIGINT` or
+    /// `SIGTERM` always terminates the process on Unix, whatever this returns.
+    ///
+    /// By default, `SIGINT`, `SIGTERM` and `SIGHUP` are passed on and
+    /// watching stops, as watchexec does on its own. Other signals, like the
+    /// `SIGCONT` of resuming after `Ctrl-Z`, are only passed on.
+    fn on_signal(&self, sig: Signal) -> SignalAction {
+        match sig {
+            Signal::SIGINT | Signal::SIGTERM | Signal::SIGHUP => SignalAction::Shutdown,
+            Signal::SIGCHLD => SignalAction::Ignore,
+            _ => SignalAction::Forward,
+        }
+    }
+
+    /// Called while a batch of changes is still accumulating, with how many
+    /// changes it has so far, see
+    /// [`Config.progress_interval`][crate::config::Config].
+    ///
+    /// Does nothing by default.
+    fn on_pending(&self, _count: usize) {}
+
+    /// Called for every event received from the watcher backend, before any
+    /// filtering or debouncing happens.
+    ///
+    /// This has the full fidelity of the backend event, including the rename
+    /// cookie and ops which would otherwise be filtered out, and so is useful
+    /// for embedders who need that alongside the processed batches.
+    ///
+    /// Does nothing by default.
+    fn on_raw_event(&self, _event: &Event) {}
+
+    /// Called once by `watch` when the watcher is established, before the
+    /// initial run (if any).
+    ///
+    /// The [`Injector`] can be kept around to push synthetic events into the
+    /// pipeline, e.g. from another thread.
+    ///
+    /// Does nothing by default.
+    fn on_start(&self, _injector: Injector) {}
+
+    /// Called once by `watch` when the loop has stopped, for whatever reason,
+    /// before the config's `teardown_cmd` is run.
+    ///
+    /// Does nothing by default.
+    fn on_stop(&self) {}
 
     /// Called once by `watch` at the very start.
     ///
@@ -84,354 +297,740 @@ pub trait Handler {
 /// Starts watching, and calls a handler when something happens.
 ///
 /// Given an argument structure and a `Handler` type, starts the watcher loop, blocking until done.
+///
+/// The config's `setup_cmd` is run before watching starts, and its
+/// `teardown_cmd` once the loop stops, for whatever reason, and the handler's
+/// [`on_stop`][Handler::on_stop] has returned.
+///
+/// If the config has a `max_runtime`, the loop stops once it has elapsed,
+/// after handing a `SIGTERM` signal event to the handler (so an
+/// [`ExecHandler`] terminates its command). The same happens when stdin is
+/// closed, if the config has `stdin_quit`.
+///
+/// With `daemonize`, this returns in a background process only, see the
+/// [`daemon`][crate::daemon] module.
+///
+/// Several sessions can run at once in the same process, e.g. on different
+/// threads, watching different paths with different handlers. Each of them
+/// gets the signals sent to the process, unless their config has another
+/// [`signal_source`][Config::signal_source]. Daemonizing affects the whole
+/// process, and so should only be used with a single session.
 pub fn watch<H>(handler: &H) -> Result<()>
 where
     H: Handler,
 {
     let args = handler.args();
 
-    let mut paths = vec![];
-    for path in &args.paths {
-        paths.push(
-            canonicalize(&path)
-                .map_err(|e| Error::Canonicalization(path.to_string_lossy().into_owned(), e))?,
-        );
-    }
+    // Forking has to happen before any thread is started
+    #[cfg(unix)]
+    let (_pid_file, daemon) = crate::daemon::start(&args)?;
+
+    // The handler has to be installed before the watcher starts its threads,
+    // but can only wake the loop once it's up
+    let (signal_tx, signals) = channel();
+    let signal_tx = Mutex::new(signal_tx);
+    let waker: Arc<Mutex<Option<Injector>>> = Arc::default();
+    let weak_waker = Arc::downgrade(&waker);
+    let _signals = args.signal_source.install(Box::new(move |sig: Signal| {
+        if matches!(sig, Signal::SIGCHLD) {
+            return;
+        }
 
-    let ignore = ignore::load(if args.no_ignore { &[] } else { &paths });
-    let gitignore = gitignore::load(if args.no_vcs_ignore || args.no_ignore {
-        &[]
-    } else {
-        &paths
-    });
-    let filter = NotificationFilter::new(&args.filters, &args.ignores, gitignore, ignore)?;
+        if let Some(waker) = weak_waker.upgrade() {
+            signal_tx
+                .lock()
+                .expect("poisoned lock in watch")
+                .send(sig)
+                .ok();
+
+            if let Some(ref injector) = *waker.lock().expect("poisoned lock in watch") {
+                injector.wake().ok();
+            }
+        }
+    }));
+
+    run_hook(&args, &args.setup_cmd, "setup")?;
+
+    let result = Events::new(&args).and_then(|events| {
+        let injector = events.injector();
+        *waker.lock().expect("poisoned lock in watch") = Some(injector.clone());
+
+        let _agent = match args.remote_agent {
+            Some(ref agent) => Some(remote::connect(agent, &args, injector.clone())?),
+            None => None,
+        };
+
+        let stop = Arc::new(AtomicBool::new(false));
+        if let Some(max) = args.max_runtime {
+            // Stop and wake the loop up when the time is up, even if nothing
+            // happens, however long the initial run took
+            let clock = args.clock.clone();
+            let stop = stop.clone();
+            let waker = injector.clone();
+            thread::spawn(move || {
+                clock::wait(&*clock, max);
+                info!("Reached the maximum runtime, stopping");
+                stop.store(true, Ordering::SeqCst);
+                waker.wake().ok();
+            });
+        }
 
-    let (tx, rx) = channel();
+        if let Some(interval) = args.run_interval {
+            let clock = args.clock.clone();
+            let timer = injector.clone();
+            thread::spawn(move || loop {
+                clock::wait(&*clock, interval);
+                if timer.deliver(Origin::Timer, HandlerEvent::Tick).is_err() {
+                    break;
+                }
+            });
+        }
+
+        if let Some(ref listener) = args.trigger_listener {
+            trigger::start(listener, &args, injector.clone())?;
+        }
 
-    #[cfg_attr(not(target_os = "linux"), allow(clippy::redundant_clone, unused_mut))]
-    let mut maybe_watcher = Watcher::new(tx.clone(), &paths, args.poll, args.poll_interval);
+        let Events {
+            watcher: _watcher,
+            debouncer,
+            requests,
+            ..
+        } = events;
+
+        let interrupts = Interrupts {
+            signals: Some(signals),
+            stop,
+            requests: Some(requests),
+        };
+
+        if args.stdin_quit {
+            let stop = interrupts.stop.clone();
+            let waker = injector.clone();
+            thread::spawn(move || {
+                // Ignore errors: they mean we can't read stdin anymore either
+                io::copy(&mut io::stdin().lock(), &mut io::sink()).ok();
+                info!("Stdin closed, stopping");
+                stop.store(true, Ordering::SeqCst);
+                waker.wake().ok();
+            });
+        }
 
-    #[cfg(target_os = "linux")]
-    if !args.poll {
-        if let Err(notify::Error::Io(ref e)) = maybe_watcher {
-            if e.raw_os_error() == Some(nix::libc::ENOSPC) {
-                warn!("System notification limit is too small, falling back to polling mode. For better performance increase system limit:\n\tsysctl fs.inotify.max_user_watches=524288");
-                maybe_watcher = Watcher::new(tx, &paths, true, args.poll_interval);
+        // The watcher is up, so changes made from now on are seen
+        #[cfg(unix)]
+        {
+            if let Some(daemon) = daemon {
+                daemon.ready();
             }
         }
-    }
+        systemd::ready();
+        let _watchdog = Watchdog::start();
+        let result = watch_loop(handler, &args, injector, interrupts, debouncer);
+        systemd::stopping();
+        handler.on_stop();
+        result
+    });
 
-    let watcher = maybe_watcher?;
-    if watcher.is_polling() {
-        warn!("Polling for changes every {:?}", args.poll_interval);
-    }
+    run_hook(&args, &args.teardown_cmd, "teardown").unwrap_or_else(|err| warn!("{}", err));
 
-    // Call handler initially, if necessary
-    if args.run_initially && !handler.on_manual()? {
+    result
+}
+
+/// Run a hook command to completion, if there is one.
+pub(crate) fn run_hook(args: &Config, cmd: &[String], what: &str) -> Result<()> {
+    if cmd.is_empty() {
         return Ok(());
     }
 
-    loop {
-        debug!("Waiting for filesystem activity");
-        let paths = wait_fs(&rx, &filter, args.debounce, args.no_meta);
-        info!("Paths updated: {:?}", paths);
+    let mut command = args.shell.to_command(cmd);
+    debug!("Running {} command: {:?}", what, command);
 
-        if !handler.on_update(&paths)? {
-            break;
-        }
+    let status = command.status()?;
+    if !status.success() {
+        return Err(Error::Generic(format!(
+            "{} command failed: {}",
+            what, status
+        )));
     }
 
     Ok(())
 }
 
-#[derive(Debug)]
-pub enum ChildProcess {
-    None,
-    Grouped(GroupChild),
-    Ungrouped(Child),
-}
+/// What can wake the watch loop up, other than events.
+#[derive(Default)]
+pub(crate) struct Interrupts {
+    /// Signals to handle according to [`Handler::on_signal`].
+    pub signals: Option<Receiver<Signal>>,
 
-impl Default for ChildProcess {
-    fn default() -> Self {
-        ChildProcess::None
-    }
+    /// Set to stop the loop gracefully, as when the maximum runtime is reached.
+    pub stop: Arc<AtomicBool>,
+
+    /// Events from other sources, to deliver to the handler as they come.
+    pub requests: Option<Receiver<(Origin, HandlerEvent)>>,
 }
 
-impl ChildProcess {
-    #[cfg(unix)]
-    fn signal(&mut self, sig: Signal) -> Result<()> {
-        match self {
-            Self::None => Ok(()),
-            Self::Grouped(c) => {
-                debug!("Sending signal {} to process group id={}", sig, c.id());
-                c.signal(sig)
-            }
-            Self::Ungrouped(c) => {
-                debug!("Sending signal {} to process id={}", sig, c.id());
-                c.signal(sig)
-            }
-        }
-        .map_err(|e| e.into())
-    }
+/// The part of [`watch`] after the watcher is set up, shared with the mock watcher.
+pub(crate) fn watch_loop<H, F, S>(
+    handler: &H,
+    args: &Config,
+    injector: Injector,
+    interrupts: Interrupts,
+    mut debouncer: Debouncer<F, S>,
+) -> Result<()>
+where
+    H: Handler,
+    F: Fn(&Path) -> bool,
+    S: Source,
+{
+    let mut recorder = match args.record_events {
+        Some(ref path) => Some(Recorder::create(path)?),
+        None => None,
+    };
+    let audit = match args.audit_log {
+        Some(ref path) => Some(AuditLog::open(path)?),
+        None => None,
+    };
+
+    let trigger_file = args.trigger_file.as_deref().map(resolve_trigger_file);
+    let respelling = if args.original_paths {
+        Some(Respelling::new(&args.paths))
+    } else {
+        None
+    };
 
-    fn kill(&mut self) -> Result<()> {
-        match self {
-            Self::None => Ok(()),
-            Self::Grouped(c) => {
-                debug!("Killing process group id={}", c.id());
-                c.kill()
-            }
-            Self::Ungrouped(c) => {
-                debug!("Killing process id={}", c.id());
-                c.kill()
-            }
-        }
-        .map_err(|e| e.into())
-    }
+    handler.on_start(injector);
 
-    fn is_running(&mut self) -> Result<bool> {
-        match self {
-            Self::None => Ok(false),
-            Self::Grouped(c) => c.try_wait().map(|w| w.is_none()),
-            Self::Ungrouped(c) => c.try_wait().map(|w| w.is_none()),
-        }
-        .map_err(|e| e.into())
+    // Call handler initially, if necessary
+    if args.run_initially && !handler.on_event(&tagged(Origin::Start, HandlerEvent::Manual))? {
+        return Ok(());
     }
 
-    fn wait(&mut self) -> Result<()> {
-        match self {
-            Self::None => Ok(()),
-            Self::Grouped(c) => c.wait().map(drop),
-            Self::Ungrouped(c) => c.wait().map(drop),
+    let started = args.clock.now();
+    let stopping = || {
+        interrupts.stop.load(Ordering::SeqCst)
+            || matches!(args.max_runtime, Some(max) if args.clock.since(started) >= max)
+    };
+
+    let mut received = Vec::new();
+    let mut requested = Vec::new();
+    let mut triggered = false;
+    let mut last_trigger = None;
+    loop {
+        if stopping() {
+            debug!("Stopping gracefully");
+            handler.on_event(&tagged(Origin::Stop, HandlerEvent::Signal(Signal::SIGTERM)))?;
+            break;
         }
-        .map_err(|e| e.into())
-    }
-}
 
-pub struct ExecHandler {
-    args: Config,
-    signal: Option<Signal>,
-    child_process: Arc<Mutex<ChildProcess>>,
-}
+        // Signals and requests which came while the handler was busy, or
+        // before the loop could be woken up, are handled without waiting
+        if let Some(ref signals) = interrupts.signals {
+            received.extend(signals.try_iter());
+        }
+        if let Some(ref requests) = interrupts.requests {
+            requested.extend(requests.try_iter());
+        }
 
-impl ExecHandler {
-    pub fn new(args: Config) -> Result<Self> {
-        let child_process: Arc<Mutex<ChildProcess>> = Arc::default();
-        let weak_child = Arc::downgrade(&child_process);
+        debug!("Waiting for filesystem activity");
+        let mut paths = if !received.is_empty() || !requested.is_empty() {
+            Vec::new()
+        } else {
+            match debouncer.next_batch_reporting(
+                |e| {
+                    if let Some(ref mut recorder) = recorder {
+                        recorder.record_or_warn(e);
+                    }
 
-        // Convert signal string to the corresponding integer
-        let signal = signal::new(args.signal.clone());
+                    handler.on_raw_event(e);
+
+                    if trigger_file.is_some() && e.path.as_deref() == trigger_file.as_deref() {
+                        // One touch makes several events, which only run once
+                        let again =
+                            last_trigger.map_or(false, |at| args.clock.since(at) < args.debounce);
+                        if again {
+                            debug!("Trigger file touched again");
+                        } else {
+                            debug!("Trigger file touched");
+                            triggered = true;
+                        }
+                        last_trigger = Some(args.clock.now());
+                    }
 
-        signal::install_handler(move |sig: Signal| {
-            if let Some(lock) = weak_child.upgrade() {
-                let mut child = lock.lock().expect("poisoned lock in install_handler");
-                match sig {
-                    Signal::SIGCHLD => {
-                        child.is_running().ok();
+                    // Signals and other sources end the batch early, so they're
+                    // handled right away
+                    if let Some(ref signals) = interrupts.signals {
+                        received.extend(signals.try_iter());
                     }
-                    _ => {
-                        #[cfg(unix)]
-                        child.signal(sig).unwrap_or_else(|err| {
-                            warn!("Could not pass on signal to command: {}", err)
-                        });
-
-                        #[cfg(not(unix))]
-                        child.kill().unwrap_or_else(|err| {
-                            warn!("Could not pass on termination to command: {}", err)
-                        });
+                    if let Some(ref requests) = interrupts.requests {
+                        requested.extend(requests.try_iter());
                     }
-                }
+
+                    received.is_empty() && requested.is_empty() && !triggered && !stopping()
+                },
+                |pending| handler.on_pending(pending),
+            ) {
+                Some(paths) => paths,
+                None => break,
             }
-        });
+        };
 
-        Ok(Self {
-            args,
-            signal,
-            child_process,
-        })
-    }
+        for sig in received.drain(..) {
+            let action = handler.on_signal(sig);
+            debug!("Received signal {}, action: {:?}", sig, action);
+            let keep_going = match action {
+                SignalAction::Ignore => true,
+                SignalAction::Forward => {
+                    handler.on_event(&tagged(Origin::Signal, HandlerEvent::Signal(sig)))?
+                }
+                SignalAction::Run => {
+                    handler.on_event(&tagged(Origin::Signal, HandlerEvent::Manual))?
+                }
+                SignalAction::Shutdown => {
+                    handler.on_event(&tagged(Origin::Signal, HandlerEvent::Signal(sig)))?;
+                    false
+                }
+            };
 
-    fn spawn(&self, ops: &[PathOp]) -> Result<()> {
-        if self.args.clear_screen {
-            clearscreen::clear()?;
+            if !keep_going {
+                return Ok(());
+            }
         }
 
-        let mut child = self.child_process.lock()?;
-        child.kill().ok();
+        dedup_runs(&mut requested);
+        for (origin, event) in requested.drain(..) {
+            if stopping() {
+                break;
+            }
 
-        let mut command = self.args.shell.to_command(&self.args.cmd);
-        debug!("Assembled command: {:?}", command);
+            debug!("Delivering {:?} from {:?}", event, origin);
+            if !handler.on_event(&tagged(origin, event))? {
+                return Ok(());
+            }
+        }
 
-        if !self.args.no_environment {
-            for (name, val) in crate::paths::collect_path_env_vars(ops) {
-                debug!("Command environment: {}={:?}", name, val);
-                command.env(name, val);
+        if mem::take(&mut triggered) && !stopping() {
+            if !handler.on_event(&tagged(Origin::Trigger, HandlerEvent::Manual))? {
+                return Ok(());
             }
+
+            // Events of the same touch may only come after the run
+            last_trigger = Some(args.clock.now());
         }
 
-        debug!("Launching command");
-        *child = if self.args.use_process_group {
-            ChildProcess::Grouped(command.group_spawn()?)
-        } else {
-            ChildProcess::Ungrouped(command.spawn()?)
-        };
+        if let Some(ref trigger_file) = trigger_file {
+            paths.retain(|op| &op.path != trigger_file);
+        }
+
+        // Shed changes are still changes, even if none were kept
+        let shed = debouncer.shed();
+        if (paths.is_empty() && shed == 0) || stopping() {
+            continue;
+        }
 
-        Ok(())
+        let received: Vec<_> = paths.iter().map(|op| debouncer.received(op)).collect();
+        if let Some(ref respelling) = respelling {
+            respelling.apply(&mut paths);
+        }
+
+        let received = latest_received(&paths, &received);
+        let paths = pathop::normalise_batch(paths, args.group_by_directory);
+        info!("Paths updated: {:?}", paths);
+        if let Some(ref audit) = audit {
+            audit.batch(&paths);
+        }
+
+        let mut events = fs_changes(paths);
+        events.push(HandlerEvent::Received(received));
+        if shed > 0 {
+            warn!("Too many changes at once, shed {} events", shed);
+            events.push(HandlerEvent::BulkChange(shed));
+        }
+
+        if !handler.on_event(&events)? {
+            break;
+        }
     }
 
-    pub fn has_running_process(&self) -> Result<bool> {
-        self.child_process
-            .lock()
-            .expect("poisoned lock in has_running_process")
-            .is_running()
+    Ok(())
+}
+
+/// When each path was last changed, given when each of its ops was.
+fn latest_received(ops: &[PathOp], received: &[Option<Instant>]) -> Vec<(PathBuf, Instant)> {
+    let mut latest: HashMap<PathBuf, Instant> = HashMap::with_capacity(ops.len());
+    for (op, at) in ops.iter().zip(received) {
+        if let Some(at) = *at {
+            let entry = latest.entry(op.path.clone()).or_insert(at);
+            *entry = (*entry).max(at);
+        }
     }
+
+    latest.into_iter().collect()
 }
 
-impl Handler for ExecHandler {
-    fn args(&self) -> Config {
-        self.args.clone()
+/// Where events for the trigger file will be, as far as it can be known.
+///
+/// The file itself may not exist yet, but its directory should.
+fn resolve_trigger_file(path: &Path) -> PathBuf {
+    match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+
+            dir.canonicalize()
+                .map_or_else(|_| path.to_path_buf(), |dir| dir.join(name))
+        }
+        _ => path.to_path_buf(),
     }
+}
 
-    // Only returns Err() on lock poisoning.
-    fn on_manual(&self) -> Result<bool> {
-        if self.args.once {
-            return Ok(true);
+/// Drop requests to run which repeat an earlier one from the same origin,
+/// e.g. several trigger messages received while the handler was busy, as
+/// they'd only run the command again for nothing.
+fn dedup_runs(requests: &mut Vec<(Origin, HandlerEvent)>) {
+    let mut seen = Vec::new();
+    requests.retain(|(origin, event)| {
+        if !matches!(event, HandlerEvent::Manual | HandlerEvent::Tick) {
+            return true;
         }
 
-        self.spawn(&[])?;
-        Ok(true)
+        let key = (origin.clone(), mem::discriminant(event));
+        if seen.contains(&key) {
+            false
+        } else {
+            seen.push(key);
+            true
+        }
+    });
+}
+
+/// What [`Handler::on_event`] does by default.
+///
+/// Handlers which implement `on_event` to look at some events themselves can
+/// call this to have the rest dispatched to `on_manual` and `on_update`.
+pub fn dispatch<H>(handler: &H, events: &[HandlerEvent]) -> Result<bool>
+where
+    H: Handler + ?Sized,
+{
+    let mut keep_going = true;
+    let mut ops = Vec::new();
+    let mut shed = false;
+    for event in events {
+        match event {
+            HandlerEvent::FsChange(op) => ops.push(op.clone()),
+            HandlerEvent::BulkChange(_) => shed = true,
+            HandlerEvent::Manual | HandlerEvent::Tick => keep_going &= handler.on_manual()?,
+            _ => {}
+        }
     }
 
-    fn on_update(&self, ops: &[PathOp]) -> Result<bool> {
-        log::debug!("ON UPDATE: called");
+    if !ops.is_empty() {
+        keep_going &= handler.on_update(&ops)?;
+    } else if shed {
+        keep_going &= handler.on_manual()?;
+    }
 
-        let signal = self.signal.unwrap_or(Signal::SIGTERM);
-        let has_running_processes = self.has_running_process()?;
+    Ok(keep_going)
+}
 
-        log::debug!(
-            "ON UPDATE: has_running_processes: {} --- on_busy_update: {:?}",
-            has_running_processes,
-            self.args.on_busy_update
-        );
+/// The events for a batch of changes, as delivered by the watch loop.
+pub(crate) fn fs_changes(ops: Vec<PathOp>) -> Vec<HandlerEvent> {
+    let mut events = Vec::with_capacity(ops.len() + 1);
+    events.push(HandlerEvent::Source(Origin::Filesystem));
+    events.extend(ops.into_iter().map(HandlerEvent::FsChange));
+    events
+}
 
-        match (has_running_processes, self.args.on_busy_update) {
-            // If nothing is running, start the command
-            (false, _) => {
-                self.spawn(ops)?;
-            }
+/// A lone event, as delivered by the watch loop.
+pub(crate) fn tagged(origin: Origin, event: HandlerEvent) -> [HandlerEvent; 2] {
+    [HandlerEvent::Source(origin), event]
+}
 
-            // Just send a signal to the command, do nothing more
-            (true, OnBusyUpdate::Signal) => signal_process(&self.child_process, signal)?,
+pub fn run(args: Config) -> Result<()> {
+    watch(&ExecHandler::new(args)?)
+}
 
-            // Send a signal to the command, wait for it to exit, then run the command again
-            (true, OnBusyUpdate::Restart) => {
-                signal_process(&self.child_process, signal)?;
-                wait_on_process(&self.child_process)?;
-                self.spawn(ops)?;
-            }
+#[cfg(test)]
+mod tests {
+    use super::{dedup_runs, run_hook, watch, watch_loop, Handler, Interrupts};
+    use crate::config::{Config, ConfigBuilder};
+    use crate::debounce::Debouncer;
+    use crate::error::Result;
+    use crate::events::{Event, Origin};
+    use crate::signal::{Signal, SignalGuard, SignalHandler, SignalSource};
+    use crate::testing::MockClock;
+    use crate::watcher::Injector;
+    use std::{
+        fmt,
+        path::Path,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc::{channel, RecvTimeoutError},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Option<SignalHandler>>>);
+
+    impl fmt::Debug for Captured {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("Captured")
+        }
+    }
 
-            // Wait for the command to end, then run it again
-            (true, OnBusyUpdate::Queue) => {
-                wait_on_process(&self.child_process)?;
-                self.spawn(ops)?;
-            }
+    impl SignalSource for Captured {
+        fn install(&self, handler: SignalHandler) -> SignalGuard {
+            *self.0.lock().expect("lock") = Some(handler);
+            SignalGuard::none()
+        }
+    }
+
+    struct Host {
+        config: Config,
+        captured: Captured,
+        seen: Mutex<Vec<Signal>>,
+    }
 
-            (true, OnBusyUpdate::DoNothing) => {}
+    impl Handler for Host {
+        fn on_start(&self, _injector: Injector) {
+            // Pretend the host application got a signal
+            let captured = self.captured.0.lock().expect("lock");
+            captured.as_ref().expect("installed")(Signal::SIGTERM);
         }
 
-        // Handle once option for integration testing
-        if self.args.once {
-            if let Some(signal) = self.signal {
-                signal_process(&self.child_process, signal)?;
+        fn on_event(&self, events: &[Event]) -> Result<bool> {
+            for event in events {
+                if let Event::Signal(sig) = event {
+                    self.seen.lock().expect("lock").push(*sig);
+                }
             }
+            Ok(true)
+        }
 
-            wait_on_process(&self.child_process)?;
+        fn args(&self) -> Config {
+            self.config.clone()
+        }
+    }
 
-            return Ok(false);
+    #[cfg(unix)]
+    #[test]
+    fn runs_concurrent_sessions() {
+        let dir = std::env::temp_dir().join(format!("watchexec-sessions-{}", std::process::id()));
+        let sessions: Vec<_> = ["one", "two"]
+            .iter()
+            .map(|name| {
+                let tree = dir.join(name);
+                std::fs::create_dir_all(&tree).expect("create tree");
+                let config = ConfigBuilder::default()
+                    .paths(vec![tree.clone()])
+                    .cmd(vec![format!(
+                        "echo {} > {}",
+                        name,
+                        tree.join("ran").display()
+                    )])
+                    .max_runs(1_u64)
+                    .build()
+                    .expect("valid config");
+
+                (tree, std::thread::spawn(move || super::run(config)))
+            })
+            .collect();
+
+        for (name, (tree, session)) in ["one", "two"].iter().zip(sessions) {
+            session.join().expect("session thread").expect("session");
+            let ran = std::fs::read_to_string(tree.join("ran")).expect("command ran");
+            assert_eq!(ran.trim(), *name);
         }
 
-        Ok(true)
+        std::fs::remove_dir_all(&dir).ok();
     }
-}
 
-pub fn run(args: Config) -> Result<()> {
-    watch(&ExecHandler::new(args)?)
-}
+    #[test]
+    fn runs_once_per_origin() {
+        let mut requests = vec![
+            (Origin::Trigger, Event::Manual),
+            (Origin::Timer, Event::Tick),
+            (Origin::Trigger, Event::Manual),
+            (Origin::Custom("editor".into()), Event::Manual),
+            (Origin::Timer, Event::Tick),
+            (Origin::Custom("other".into()), Event::Manual),
+        ];
+        dedup_runs(&mut requests);
+
+        let origins: Vec<_> = requests.into_iter().map(|(origin, _)| origin).collect();
+        assert_eq!(
+            origins,
+            vec![
+                Origin::Trigger,
+                Origin::Timer,
+                Origin::Custom("editor".into()),
+                Origin::Custom("other".into()),
+            ]
+        );
+    }
 
-fn wait_fs(
-    rx: &Receiver<Event>,
-    filter: &NotificationFilter,
-    debounce: Duration,
-    no_meta: bool,
-) -> Vec<PathOp> {
-    let mut paths = Vec::new();
-    let mut cache = HashMap::new();
+    #[test]
+    fn uses_signal_source() {
+        let captured = Captured::default();
+        let config = ConfigBuilder::default()
+            .cmd(vec!["true".into()])
+            .paths(vec![concat!(env!("CARGO_MANIFEST_DIR"), "/src").into()])
+            .run_initially(false)
+            .signal_source(captured.clone())
+            .build()
+            .expect("valid config");
+
+        let host = Host {
+            config,
+            captured,
+            seen: Mutex::default(),
+        };
 
-    loop {
-        let e = rx.recv().expect("error when reading event");
+        watch(&host).expect("watch");
+        let seen = host.seen.lock().expect("lock");
+        assert!(matches!(seen[..], [Signal::SIGTERM]));
+    }
 
-        if let Some(ref path) = e.path {
-            let pathop = PathOp::new(path, e.op.ok(), e.cookie);
-            if let Some(op) = pathop.op {
-                if no_meta && PathOp::is_meta(op) {
-                    continue;
-                }
-            }
+    #[test]
+    fn handles_signals_received_before_waiting() {
+        let config = ConfigBuilder::default()
+            .cmd(vec!["true".into()])
+            .paths(vec![".".into()])
+            .run_initially(false)
+            .build()
+            .expect("valid config");
+        let captured = Captured::default();
+        let _guard = captured.install(Box::new(|_| {}));
+        let host = Host {
+            config: config.clone(),
+            captured,
+            seen: Mutex::default(),
+        };
+
+        // Nothing will ever come from the watcher to wake the loop up
+        let (_, raw) = channel();
+        let debouncer = Debouncer::new(raw, |_: &Path| false, config.debounce);
+        let (signal_tx, signals) = channel();
+        signal_tx.send(Signal::SIGTERM).expect("send");
+        let (tx, _) = channel();
+        let (requests_tx, requests) = channel();
+        let interrupts = Interrupts {
+            signals: Some(signals),
+            requests: Some(requests),
+            ..Interrupts::default()
+        };
+
+        watch_loop(
+            &host,
+            &config,
+            Injector::new(tx, requests_tx),
+            interrupts,
+            debouncer,
+        )
+        .expect("loop ran");
+        let seen = host.seen.lock().expect("lock");
+        assert!(matches!(seen[..], [Signal::SIGTERM]));
+    }
+
+    #[test]
+    fn stops_at_max_runtime_after_slow_start() {
+        struct SlowStart(Config);
 
-            // Ignore cache for the initial file. Otherwise, in
-            // debug mode it's hard to track what's going on
-            let excluded = filter.is_excluded(path);
-            if !cache.contains_key(&pathop) {
-                cache.insert(pathop.clone(), excluded);
+        impl Handler for SlowStart {
+            fn on_manual(&self) -> Result<bool> {
+                std::thread::sleep(Duration::from_millis(300));
+                Ok(true)
             }
 
-            if !excluded {
-                paths.push(pathop);
-                break;
+            fn args(&self) -> Config {
+                self.0.clone()
             }
         }
-    }
 
-    // Wait for filesystem activity to cool off
-    while let Ok(e) = rx.recv_timeout(debounce) {
-        if let Some(ref path) = e.path {
-            let pathop = PathOp::new(path, e.op.ok(), e.cookie);
-            if cache.contains_key(&pathop) {
-                continue;
-            }
+        let dir = std::env::temp_dir().join(format!("watchexec-runtime-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("create dir");
+        let config = ConfigBuilder::default()
+            .cmd(vec!["true".into()])
+            .paths(vec![dir.clone()])
+            .max_runtime(Duration::from_millis(100))
+            .build()
+            .expect("valid config");
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || tx.send(watch(&SlowStart(config)).is_ok()));
+        let stopped = rx.recv_timeout(Duration::from_secs(5));
+        std::fs::remove_dir_all(&dir).ok();
+        assert_eq!(stopped, Ok(true));
+    }
 
-            let excluded = filter.is_excluded(path);
+    #[test]
+    fn timers_follow_the_clock() {
+        struct Ticks(Config, Arc<AtomicUsize>);
 
-            cache.insert(pathop.clone(), excluded);
+        impl Handler for Ticks {
+            fn on_event(&self, events: &[Event]) -> Result<bool> {
+                for event in events {
+                    if let Event::Tick = event {
+                        self.1.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+                Ok(true)
+            }
 
-            if !excluded {
-                paths.push(pathop);
+            fn args(&self) -> Config {
+                self.0.clone()
             }
         }
-    }
 
-    paths
-}
+        let dir = std::env::temp_dir().join(format!("watchexec-timers-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("create dir");
+        let clock = MockClock::new();
+        let config = ConfigBuilder::default()
+            .cmd(vec!["true".into()])
+            .paths(vec![dir.clone()])
+            .run_interval(Duration::from_secs(1))
+            .max_runtime(Duration::from_secs(3))
+            .clock(clock.clone())
+            .build()
+            .expect("valid config");
+
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let handler = Ticks(config, ticks.clone());
+        let (tx, rx) = channel();
+        std::thread::spawn(move || tx.send(watch(&handler).is_ok()));
+
+        // Nothing happens while the clock stands still
+        assert!(rx.recv_timeout(Duration::from_millis(300)).is_err());
+        assert_eq!(ticks.load(Ordering::SeqCst), 0);
+
+        let mut stopped = Err(RecvTimeoutError::Timeout);
+        for _ in 0..50 {
+            clock.advance(Duration::from_millis(500));
+            stopped = rx.recv_timeout(Duration::from_millis(100));
+            if stopped.is_ok() {
+                break;
+            }
+        }
 
-fn signal_process(process: &Mutex<ChildProcess>, signal: Signal) -> Result<()> {
-    let mut child = process.lock().expect("poisoned lock in signal_process");
+        std::fs::remove_dir_all(&dir).ok();
+        assert_eq!(stopped, Ok(true));
+        assert!((2..=3).contains(&ticks.load(Ordering::SeqCst)));
+    }
 
     #[cfg(unix)]
-    child.signal(signal)?;
-
-    #[cfg(not(unix))]
-    if matches!(signal, Signal::SIGTERM | Signal::SIGKILL) {
-        child.kill()?;
-    } else {
-        debug!("Ignoring signal to send to process");
+    #[test]
+    fn hooks_fail_on_error_status() {
+        let config = ConfigBuilder::default()
+            .cmd(vec!["true".into()])
+            .paths(vec![".".into()])
+            .build()
+            .expect("valid config");
+
+        assert!(run_hook(&config, &[], "setup").is_ok());
+        assert!(run_hook(&config, &["true".into()], "setup").is_ok());
+        assert!(run_hook(&config, &["exit 3".into()], "setup").is_err());
     }
-
-    Ok(())
-}
-
-fn wait_on_process(process: &Mutex<ChildProcess>) -> Result<()> {
-    process
-        .lock()
-        .expect("poisoned lock in wait_on_process")
-        .wait()
 }
@@ -0,0 +1,221 @@
+//! Hosting the watch loop as a Windows service.
+//!
+//! With the `windows-service` feature on Windows, [`run`] hands the current
+//! process over to the service control manager, and runs [`watch`] as the
+//! service. Requests from the service control manager are mapped onto the
+//! loop and the command:
+//!
+//! - stop (and system shutdown) is handled as a `SIGTERM`, according to
+//!   [`Handler::on_signal`];
+//! - pause stops the command, and ignores changes until the service continues;
+//! - continue handles changes again, and runs the command.
+//!
+//! Registering the service itself (e.g. with `sc.exe create`) is left to the
+//! embedder.
+
+use std::{
+    cell::Cell,
+    ffi::OsString,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{error, warn};
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
+    service_dispatcher,
+};
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::events::Event;
+use crate::pathop::PathOp;
+use crate::run::{watch, Handler, SignalAction};
+use crate::signal::{self, Signal};
+use crate::watcher::{Event as RawEvent, Injector};
+
+type ServiceMain = Box<dyn FnOnce() -> Result<()> + Send>;
+
+lazy_static::lazy_static! {
+    static ref SERVICE: Mutex<Option<(&'static str, ServiceMain)>> = Mutex::new(None);
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Run the watch loop as the Windows service `name`, blocking until it stops.
+///
+/// This must be called from the process started by the service control
+/// manager, and only once. It returns an error straight away otherwise.
+pub fn run<H>(name: &'static str, handler: H) -> Result<()>
+where
+    H: Handler + Send + 'static,
+{
+    *SERVICE.lock().expect("poisoned lock in service::run") = Some((
+        name,
+        Box::new(move || {
+            watch(&ServiceHandler {
+                inner: handler,
+                paused: Cell::new(false),
+            })
+        }),
+    ));
+
+    service_dispatcher::start(name, ffi_service_main)
+        .map_err(|err| Error::Generic(format!("could not start service dispatcher: {}", err)))
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    let (name, main) = match SERVICE
+        .lock()
+        .expect("poisoned lock in service_main")
+        .take()
+    {
+        Some(service) => service,
+        None => return,
+    };
+
+    let status: Arc<Mutex<Option<ServiceStatusHandle>>> = Arc::default();
+    let control_status = status.clone();
+    let registered = service_control_handler::register(name, move |control| {
+        // Signals are picked up by the handler installed by `watch`
+        let (sig, state) = match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                (Signal::SIGTERM, ServiceState::StopPending)
+            }
+            ServiceControl::Pause => (Signal::SIGSTOP, ServiceState::Paused),
+            ServiceControl::Continue => (Signal::SIGCONT, ServiceState::Running),
+            ServiceControl::Interrogate => return ServiceControlHandlerResult::NoError,
+            _ => return ServiceControlHandlerResult::NotImplemented,
+        };
+
+        signal::invoke(sig);
+        if let Some(handle) = *control_status
+            .lock()
+            .expect("poisoned lock in service control")
+        {
+            set_state(handle, state, 0);
+        }
+
+        ServiceControlHandlerResult::NoError
+    });
+
+    let handle = match registered {
+        Ok(handle) => handle,
+        Err(err) => {
+            error!("Could not register service control handler: {}", err);
+            return;
+        }
+    };
+
+    *status.lock().expect("poisoned lock in service_main") = Some(handle);
+    set_state(handle, ServiceState::Running, 0);
+
+    let exit_code = match main() {
+        Ok(()) => 0,
+        Err(err) => {
+            error!("Watch loop failed: {}", err);
+            1
+        }
+    };
+
+    set_state(handle, ServiceState::Stopped, exit_code);
+}
+
+fn set_state(handle: ServiceStatusHandle, state: ServiceState, exit_code: u32) {
+    let controls_accepted = match state {
+        ServiceState::StopPending | ServiceState::Stopped => ServiceControlAccept::empty(),
+        _ => {
+            ServiceControlAccept::STOP
+                | ServiceControlAccept::SHUTDOWN
+                | ServiceControlAccept::PAUSE_CONTINUE
+        }
+    };
+
+    handle
+        .set_service_status(ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted,
+            exit_code: ServiceExitCode::Win32(exit_code),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        })
+        .unwrap_or_else(|err| warn!("Could not set service status: {}", err));
+}
+
+/// Wraps the embedder's handler to implement pausing.
+struct ServiceHandler<H> {
+    inner: H,
+    paused: Cell<bool>,
+}
+
+impl<H> Handler for ServiceHandler<H>
+where
+    H: Handler,
+{
+    fn args(&self) -> Config {
+        self.inner.args()
+    }
+
+    fn on_manual(&self) -> Result<bool> {
+        self.inner.on_manual()
+    }
+
+    fn on_update(&self, ops: &[PathOp]) -> Result<bool> {
+        self.inner.on_update(ops)
+    }
+
+    fn on_event(&self, events: &[Event]) -> Result<bool> {
+        if !self.paused.get() {
+            return self.inner.on_event(events);
+        }
+
+        let events: Vec<Event> = events
+            .iter()
+            .filter(|event| event.pathop().is_none())
+            .cloned()
+            .collect();
+
+        if events.is_empty() {
+            Ok(true)
+        } else {
+            self.inner.on_event(&events)
+        }
+    }
+
+    fn on_signal(&self, sig: Signal) -> SignalAction {
+        match sig {
+            Signal::SIGSTOP => {
+                self.paused.set(true);
+                SignalAction::Forward
+            }
+            Signal::SIGCONT => {
+                self.paused.set(false);
+                SignalAction::Run
+            }
+            _ => self.inner.on_signal(sig),
+        }
+    }
+
+    fn on_pending(&self, count: usize) {
+        self.inner.on_pending(count)
+    }
+
+    fn on_raw_event(&self, event: &RawEvent) {
+        self.inner.on_raw_event(event)
+    }
+
+    fn on_start(&self, injector: Injector) {
+        self.inner.on_start(injector)
+    }
+
+    fn on_stop(&self) {
+        self.inner.on_stop()
+    }
+}
@@ -1,8 +1,24 @@
-use std::sync::Mutex;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::error::{Error, Result};
+
+type CleanupFn = Arc<dyn Fn(self::Signal) + Send + Sync>;
+
+/// The handlers of all the guards currently alive.
+///
+/// Signals are delivered to the whole process, so each of them is passed to
+/// every handler. The delivery machinery is set up for the first handler, and
+/// torn down with the last one.
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    handlers: Vec<(u64, CleanupFn)>,
+    delivery: Option<Delivery>,
+}
 
-type CleanupFn = Box<dyn Fn(self::Signal) + Send>;
 lazy_static::lazy_static! {
-    static ref CLEANUP: Mutex<Option<CleanupFn>> = Mutex::new(None);
+    static ref HANDLERS: Mutex<Registry> = Mutex::default();
 }
 
 // Indicate interest in SIGCHLD by setting a dummy handler
@@ -16,7 +32,7 @@ pub use nix::sys::signal::Signal;
 // This is a dummy enum for Windows
 #[cfg(windows)]
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Signal {
     SIGKILL,
     SIGTERM,
@@ -29,9 +45,6 @@ pub enum Signal {
     SIGUSR2,
 }
 
-#[cfg(windows)]
-use std::fmt;
-
 #[cfg(windows)]
 impl fmt::Display for Signal {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -80,38 +93,285 @@ impl ConvertToLibc for Signal {
     }
 }
 
-pub fn new(signal_name: Option<String>) -> Option<Signal> {
-    if let Some(signame) = signal_name {
-        let signal = match signame.as_ref() {
-            "SIGKILL" | "KILL" => Signal::SIGKILL,
-            "SIGTERM" | "TERM" => Signal::SIGTERM,
-            "SIGINT" | "INT" => Signal::SIGINT,
-            "SIGHUP" | "HUP" => Signal::SIGHUP,
-            "SIGSTOP" | "STOP" => Signal::SIGSTOP,
-            "SIGCONT" | "CONT" => Signal::SIGCONT,
-            "SIGCHLD" | "CHLD" => Signal::SIGCHLD,
-            "SIGUSR1" | "USR1" => Signal::SIGUSR1,
-            "SIGUSR2" | "USR2" => Signal::SIGUSR2,
-            _ => panic!("unsupported signal: {}", signame),
-        };
+/// A signal to send to the command, as configured.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChildSignal {
+    /// one of the signals watchexec knows by name
+    Standard(Signal),
+
+    /// any other signal, by number, like realtime signals (Unix only)
+    Number(i32),
+}
+
+impl From<Signal> for ChildSignal {
+    fn from(sig: Signal) -> Self {
+        Self::Standard(sig)
+    }
+}
+
+impl fmt::Display for ChildSignal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Standard(sig) => write!(f, "{}", sig),
+            Self::Number(number) => write!(f, "signal {}", number),
+        }
+    }
+}
+
+pub fn new(signal_name: Option<String>) -> Result<Option<ChildSignal>> {
+    signal_name.map(|name| parse(&name)).transpose()
+}
+
+/// Parse a signal from its name, with or without the `SIG` prefix, or its
+/// number. On Linux, realtime signals can be given as `SIGRTMIN+n` or
+/// `SIGRTMAX-n`.
+pub fn parse(name: &str) -> Result<ChildSignal> {
+    let unsupported = || Error::Generic(format!("unsupported signal: {}", name));
+
+    if let Ok(number) = name.trim().parse() {
+        return from_number(number).ok_or_else(unsupported);
+    }
+
+    let name = name.trim().to_ascii_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{}", name)
+    };
+
+    if let Some(number) = realtime(&name) {
+        return Ok(ChildSignal::Number(number));
+    }
+
+    from_name(&name)
+        .map(ChildSignal::Standard)
+        .ok_or_else(unsupported)
+}
+
+#[cfg(unix)]
+fn from_name(name: &str) -> Option<Signal> {
+    name.parse().ok()
+}
+
+#[cfg(windows)]
+fn from_name(name: &str) -> Option<Signal> {
+    Some(match name {
+        "SIGKILL" => Signal::SIGKILL,
+        "SIGTERM" => Signal::SIGTERM,
+        "SIGINT" => Signal::SIGINT,
+        "SIGHUP" => Signal::SIGHUP,
+        "SIGSTOP" => Signal::SIGSTOP,
+        "SIGCONT" => Signal::SIGCONT,
+        "SIGCHLD" => Signal::SIGCHLD,
+        "SIGUSR1" => Signal::SIGUSR1,
+        "SIGUSR2" => Signal::SIGUSR2,
+        _ => return None,
+    })
+}
+
+#[cfg(unix)]
+fn from_number(number: c_int) -> Option<ChildSignal> {
+    use std::convert::TryFrom;
+
+    match Signal::try_from(number) {
+        Ok(sig) => Some(ChildSignal::Standard(sig)),
+        Err(_) if realtime_range().map_or(false, |(min, max)| (min..=max).contains(&number)) => {
+            Some(ChildSignal::Number(number))
+        }
+        Err(_) => None,
+    }
+}
+
+#[cfg(windows)]
+fn from_number(_number: i32) -> Option<ChildSignal> {
+    None
+}
 
-        Some(signal)
+/// The number of a realtime signal given by name, if it's one.
+fn realtime(name: &str) -> Option<i32> {
+    let (min, max) = realtime_range()?;
+    let base = if name.starts_with("SIGRTMIN") {
+        min
+    } else if name.starts_with("SIGRTMAX") {
+        max
+    } else {
+        return None;
+    };
+
+    let offset = &name[8..];
+    let number = if offset.is_empty() {
+        base
+    } else if offset.starts_with('+') {
+        base.checked_add(offset[1..].parse().ok()?)?
+    } else if offset.starts_with('-') {
+        base.checked_sub(offset[1..].parse().ok()?)?
+    } else {
+        return None;
+    };
+
+    if (min..=max).contains(&number) {
+        Some(number)
     } else {
         None
     }
 }
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[allow(clippy::unnecessary_wraps)]
+fn realtime_range() -> Option<(i32, i32)> {
+    Some((SIGRTMIN(), SIGRTMAX()))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const fn realtime_range() -> Option<(i32, i32)> {
+    None
+}
+
+/// Send a signal by number to a process, or to a process group if negative.
 #[cfg(unix)]
-pub fn install_handler<F>(handler: F)
+pub fn send_raw(pid: i32, signal: c_int) -> std::io::Result<()> {
+    if unsafe { kill(pid, signal) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Receives the signals sent to watchexec, see [`SignalSource`].
+pub type SignalHandler = Box<dyn Fn(Signal) + Send + Sync>;
+
+/// Where [`watch`][crate::run::watch] gets signals from.
+///
+/// This is set in [`Config.signal_source`][crate::config::Config], and is
+/// [`ProcessSignals`] by default. Host applications which manage signals
+/// themselves can implement this to call the handler with the signals they
+/// want watchexec to act upon (and so forward to the command), or use
+/// [`NoSignals`] to not receive any.
+pub trait SignalSource: fmt::Debug + Send + Sync {
+    /// Start delivering signals to `handler`, until the guard is dropped.
+    ///
+    /// Called once by `watch`, before any thread is started, and the guard is
+    /// dropped when it returns. The handler can be called from any thread.
+    fn install(&self, handler: SignalHandler) -> SignalGuard;
+}
+
+/// Undoes the installation of a signal handler when dropped.
+#[must_use = "the handler is uninstalled when the guard is dropped"]
+pub struct SignalGuard(Option<Box<dyn FnOnce() + Send>>);
+
+impl SignalGuard {
+    /// A guard which calls `uninstall` when dropped.
+    pub fn new<F>(uninstall: F) -> Self
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        Self(Some(Box::new(uninstall)))
+    }
+
+    /// A guard which does nothing.
+    pub fn none() -> Self {
+        Self(None)
+    }
+}
+
+impl fmt::Debug for SignalGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SignalGuard").finish()
+    }
+}
+
+impl Drop for SignalGuard {
+    fn drop(&mut self) {
+        if let Some(uninstall) = self.0.take() {
+            uninstall();
+        }
+    }
+}
+
+/// Installs the process-wide signal handler.
+///
+/// This masks the signals for threads started afterwards. A second `SIGINT`
+/// or `SIGTERM` terminates the process right away on Unix. Concurrent `watch`
+/// sessions each get every signal.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProcessSignals;
+
+impl SignalSource for ProcessSignals {
+    fn install(&self, handler: SignalHandler) -> SignalGuard {
+        install_handler(handler)
+    }
+}
+
+/// Never delivers any signal, leaving their handling to the host.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoSignals;
+
+impl SignalSource for NoSignals {
+    fn install(&self, _handler: SignalHandler) -> SignalGuard {
+        SignalGuard::none()
+    }
+}
+
+/// Install a process-wide signal handler, until the guard is dropped.
+///
+/// This also masks the signals for threads started after this point, so it
+/// should be called before any threads are spawned. Dropping the guard
+/// restores the signal mask of the calling thread, so it should be dropped on
+/// that thread.
+///
+/// Several handlers can be installed at once, e.g. by concurrent `watch`
+/// sessions, and each signal is then passed to all of them. Guards can be
+/// dropped in any order.
+///
+/// On Unix, the handlers are called once for every signal received, except
+/// that a second `SIGINT` or `SIGTERM` terminates the process right away, so
+/// that a stuck program can always be interrupted.
+pub fn install_handler<F>(handler: F) -> SignalGuard
 where
     F: Fn(self::Signal) + 'static + Send + Sync,
 {
-    use log::debug;
+    #[cfg(unix)]
+    let previous_mask = watched()
+        .thread_swap_mask(nix::sys::signal::SigmaskHow::SIG_SETMASK)
+        .expect("unable to set signal mask");
+
+    let id = {
+        let mut registry = HANDLERS
+            .lock()
+            .expect("poisoned lock in signal::install_handler");
+        let id = registry.next_id;
+        registry.next_id += 1;
+        registry.handlers.push((id, Arc::new(handler)));
+        if registry.delivery.is_none() {
+            registry.delivery = Some(Delivery::start());
+        }
+        id
+    };
+
+    SignalGuard::new(move || {
+        let delivery = {
+            let mut registry = HANDLERS
+                .lock()
+                .expect("poisoned lock in signal::install_handler");
+            registry.handlers.retain(|(other, _)| *other != id);
+            if registry.handlers.is_empty() {
+                registry.delivery.take()
+            } else {
+                None
+            }
+        };
+        drop(delivery);
+
+        #[cfg(unix)]
+        let _ = previous_mask.thread_set_mask();
+    })
+}
+
+/// The signals watchexec handles.
+#[cfg(unix)]
+fn watched() -> nix::sys::signal::SigSet {
     use nix::sys::signal::*;
-    use std::thread;
 
-    // Mask all signals interesting to us. The mask propagates
-    // to all threads started after this point.
     let mut mask = SigSet::empty();
     mask.add(SIGKILL);
     mask.add(SIGTERM);
@@ -122,33 +382,75 @@ where
     mask.add(SIGCHLD);
     mask.add(SIGUSR1);
     mask.add(SIGUSR2);
-    mask.thread_set_mask().expect("unable to set signal mask");
-
-    set_handler(handler);
-
-    #[allow(unsafe_code)]
-    unsafe {
-        let _ = sigaction(
-            SIGCHLD,
-            &SigAction::new(
-                SigHandler::Handler(sigchld_handler),
-                SaFlags::empty(),
-                SigSet::empty(),
-            ),
-        );
-    }
+    mask
+}
+
+/// Passes signals on to the registered handlers until dropped.
+#[cfg(unix)]
+struct Delivery {
+    stop: Arc<std::sync::atomic::AtomicBool>,
+    waiter: pthread_t,
+    previous_sigchld: Option<nix::sys::signal::SigAction>,
+}
+
+#[cfg(unix)]
+impl Delivery {
+    fn start() -> Self {
+        use log::debug;
+        use nix::sys::signal::*;
+        use std::sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc,
+        };
+        use std::thread;
+
+        #[allow(unsafe_code)]
+        let previous_sigchld = unsafe {
+            sigaction(
+                SIGCHLD,
+                &SigAction::new(
+                    SigHandler::Handler(sigchld_handler),
+                    SaFlags::empty(),
+                    SigSet::empty(),
+                ),
+            )
+            .ok()
+        };
 
-    // Spawn a thread to catch these signals
-    thread::spawn(move || {
-        loop {
-            let signal = mask.wait().expect("Unable to sigwait");
-            debug!("Received {:?}", signal);
+        // Spawn a thread to catch these signals. It has to have them all
+        // masked, whichever thread starts it.
+        let mask = watched();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = mask.thread_block();
 
-            // Invoke closure
-            invoke(signal);
+            #[allow(unsafe_code)]
+            tx.send(unsafe { pthread_self() }).ok();
 
-            // Restore default behavior for received signal and unmask it
-            if signal != SIGCHLD {
+            // Interrupts and terminations already received once
+            let mut received = SigSet::empty();
+
+            loop {
+                let signal = mask.wait().expect("Unable to sigwait");
+                if stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                debug!("Received {:?}", signal);
+
+                if !matches!(signal, SIGINT | SIGTERM) || !received.contains(signal) {
+                    received.add(signal);
+
+                    // Invoke closures
+                    invoke(signal);
+                    continue;
+                }
+
+                // The second time around, don't rely on the closures: restore
+                // default behavior for the signal, and re-raise it unmasked
+                debug!("Received {:?} again, forcing default behaviour", signal);
                 let default_action =
                     SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
 
@@ -156,52 +458,181 @@ where
                 unsafe {
                     let _ = sigaction(signal, &default_action);
                 }
+
+                let mut new_mask = SigSet::empty();
+                new_mask.add(signal);
+
+                let _ = new_mask.thread_unblock();
+                let _ = raise(signal);
+                let _ = new_mask.thread_block();
             }
+        });
+
+        Self {
+            stop,
+            waiter: rx.recv().expect("signal thread is alive"),
+            previous_sigchld,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Delivery {
+    fn drop(&mut self) {
+        use nix::sys::signal::{sigaction, SIGCHLD};
+        use std::sync::atomic::Ordering;
 
-            let mut new_mask = SigSet::empty();
-            new_mask.add(signal);
+        // Wake the signal thread up so it notices it has to stop
+        self.stop.store(true, Ordering::SeqCst);
+        unsafe {
+            pthread_kill(self.waiter, SIGCONT as c_int);
+        }
 
-            // Re-raise with signal unmasked
-            let _ = new_mask.thread_unblock();
-            let _ = raise(signal);
-            let _ = new_mask.thread_block();
+        if let Some(ref action) = self.previous_sigchld {
+            unsafe {
+                let _ = sigaction(SIGCHLD, action);
+            }
         }
-    });
+    }
 }
 
+/// Passes console events on to the registered handlers until dropped.
+#[cfg(windows)]
+struct Delivery;
+
 #[cfg(windows)]
 #[allow(unsafe_code)]
-pub fn install_handler<F>(handler: F)
-where
-    F: Fn(self::Signal) + 'static + Send + Sync,
-{
-    use winapi::shared::minwindef::{BOOL, DWORD, FALSE, TRUE};
-    use winapi::um::consoleapi::SetConsoleCtrlHandler;
+unsafe extern "system" fn ctrl_handler(
+    _: winapi::shared::minwindef::DWORD,
+) -> winapi::shared::minwindef::BOOL {
+    invoke(self::Signal::SIGTERM);
 
-    pub unsafe extern "system" fn ctrl_handler(_: DWORD) -> BOOL {
-        invoke(self::Signal::SIGTERM);
+    winapi::shared::minwindef::FALSE
+}
 
-        FALSE
-    }
+#[cfg(windows)]
+impl Delivery {
+    fn start() -> Self {
+        use winapi::shared::minwindef::TRUE;
+        use winapi::um::consoleapi::SetConsoleCtrlHandler;
 
-    set_handler(handler);
+        unsafe {
+            SetConsoleCtrlHandler(Some(ctrl_handler), TRUE);
+        }
 
-    unsafe {
-        SetConsoleCtrlHandler(Some(ctrl_handler), TRUE);
+        Self
     }
 }
 
-fn invoke(sig: self::Signal) {
-    if let Some(ref handler) = *CLEANUP.lock().expect("poisoned lock in signal::invoke") {
-        handler(sig)
+#[cfg(windows)]
+impl Drop for Delivery {
+    fn drop(&mut self) {
+        use winapi::shared::minwindef::FALSE;
+        use winapi::um::consoleapi::SetConsoleCtrlHandler;
+
+        unsafe {
+            SetConsoleCtrlHandler(Some(ctrl_handler), FALSE);
+        }
     }
 }
 
-fn set_handler<F>(handler: F)
-where
-    F: Fn(self::Signal) + 'static + Send + Sync,
-{
-    *CLEANUP
+/// Pass a signal to all the installed handlers.
+pub(crate) fn invoke(sig: self::Signal) {
+    // Don't hold the lock while handlers run, so they can (un)install others
+    let handlers: Vec<CleanupFn> = HANDLERS
         .lock()
-        .expect("poisoned lock in signal::set_handler") = Some(Box::new(handler));
+        .expect("poisoned lock in signal::invoke")
+        .handlers
+        .iter()
+        .map(|(_, handler)| handler.clone())
+        .collect();
+
+    for handler in handlers {
+        handler(sig);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{install_handler, invoke, parse, ChildSignal, Signal};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn handlers_are_independent() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let record = |name: &'static str| {
+            let seen = seen.clone();
+            move |_: Signal| seen.lock().expect("lock").push(name)
+        };
+
+        let first = install_handler(record("first"));
+        invoke(Signal::SIGUSR1);
+
+        let second = install_handler(record("second"));
+        invoke(Signal::SIGUSR1);
+
+        // Not in reverse order
+        drop(first);
+        invoke(Signal::SIGUSR1);
+
+        drop(second);
+        invoke(Signal::SIGUSR1);
+
+        assert_eq!(
+            *seen.lock().expect("lock"),
+            vec!["first", "first", "second", "second"]
+        );
+    }
+
+    #[test]
+    fn parses_names_and_numbers() {
+        for name in &["SIGHUP", "HUP", "hup", "sighup"] {
+            assert_eq!(
+                parse(name).expect("valid name"),
+                ChildSignal::Standard(Signal::SIGHUP)
+            );
+        }
+
+        assert!(parse("SIGNOPE").is_err());
+        assert!(parse("").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn parses_all_posix_signals() {
+        assert_eq!(
+            parse("WINCH").expect("valid name"),
+            ChildSignal::Standard(Signal::SIGWINCH)
+        );
+        assert_eq!(
+            parse("15").expect("valid number"),
+            ChildSignal::Standard(Signal::SIGTERM)
+        );
+        assert!(parse("-1").is_err());
+        assert!(parse("1000").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn parses_realtime_signals() {
+        let min = nix::libc::SIGRTMIN();
+        let max = nix::libc::SIGRTMAX();
+
+        assert_eq!(parse("SIGRTMIN").expect("min"), ChildSignal::Number(min));
+        assert_eq!(
+            parse("RTMIN+3").expect("min+3"),
+            ChildSignal::Number(min + 3)
+        );
+        assert_eq!(
+            parse("SIGRTMAX-1").expect("max-1"),
+            ChildSignal::Number(max - 1)
+        );
+        assert_eq!(
+            parse(&(min + 1).to_string()).expect("number"),
+            ChildSignal::Number(min + 1)
+        );
+        assert!(parse("SIGRTMAX+1").is_err());
+        assert!(parse("SIGRTMIN-1").is_err());
+        assert!(parse("SIGRTMINUS").is_err());
+    }
 }
@@ -0,0 +1,81 @@
+//! Readiness and status notifications for systemd services.
+//!
+//! With the `systemd` feature on Unix, `watch` tells the service manager when
+//! it's ready, and keeps its status line up to date with what the command is
+//! doing. If the unit has `WatchdogSec=` set, watchdog keep-alives are sent
+//! for as long as the loop runs.
+//!
+//! Outside of systemd (or without the feature), these do nothing.
+
+#[cfg(all(unix, feature = "systemd"))]
+use log::debug;
+#[cfg(all(unix, feature = "systemd"))]
+use sd_notify::NotifyState;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Watching is established.
+pub(crate) fn ready() {
+    #[cfg(all(unix, feature = "systemd"))]
+    send(&[
+        NotifyState::Ready,
+        NotifyState::Status("Watching for changes"),
+    ]);
+}
+
+/// Update the status line.
+pub(crate) fn status(_status: &str) {
+    #[cfg(all(unix, feature = "systemd"))]
+    send(&[NotifyState::Status(_status)]);
+}
+
+/// The loop is shutting down.
+pub(crate) fn stopping() {
+    #[cfg(all(unix, feature = "systemd"))]
+    send(&[NotifyState::Stopping]);
+}
+
+#[cfg(all(unix, feature = "systemd"))]
+fn send(states: &[NotifyState<'_>]) {
+    sd_notify::notify(false, states)
+        .unwrap_or_else(|err| debug!("Could not notify systemd: {}", err));
+}
+
+/// Sends watchdog keep-alives until dropped, if systemd asked for them.
+pub(crate) struct Watchdog {
+    stop: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    pub(crate) fn start() -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+
+        #[cfg(all(unix, feature = "systemd"))]
+        {
+            let mut usec = 0;
+            if sd_notify::watchdog_enabled(false, &mut usec) {
+                // Ping twice per interval, as recommended by sd_watchdog_enabled(3)
+                let interval = std::time::Duration::from_micros(usec) / 2;
+                debug!("Sending systemd watchdog keep-alives every {:?}", interval);
+
+                let stop = stop.clone();
+                std::thread::spawn(move || {
+                    while !stop.load(Ordering::SeqCst) {
+                        send(&[NotifyState::Watchdog]);
+                        std::thread::sleep(interval);
+                    }
+                });
+            }
+        }
+
+        Self { stop }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,729 @@
+//! Utilities for testing code built on watchexec.
+//!
+//! The [`MockWatcher`] runs the same loop as [`watch`][crate::run::watch], but
+//! instead of watching the filesystem it delivers a scripted sequence of
+//! events, and instead of waiting for the debounce window to elapse it ends a
+//! batch wherever the script says so. This makes it possible to unit-test
+//! [`Handler`] implementations deterministically and without sleeping.
+//!
+//! For finer control over timing, the script can also contain waits, which
+//! advance the config's [`Clock`]. Combined with a [`MockClock`], this
+//! simulates the whole loop in virtual time: debounce windows, timeouts and
+//! the like all play out exactly, but instantly.
+//!
+//! # Examples
+//!
+//! ```
+//! # use std::cell::RefCell;
+//! # use watchexec::{config::{Config, ConfigBuilder}, error::Result, pathop::PathOp, run::Handler, testing::MockWatcher};
+//! struct Recorder(Config, RefCell<Vec<usize>>);
+//!
+//! impl Handler for Recorder {
+//!     fn args(&self) -> Config { self.0.clone() }
+//!     fn on_manual(&self) -> Result<bool> { Ok(true) }
+//!     fn on_update(&self, ops: &[PathOp]) -> Result<bool> {
+//!         self.1.borrow_mut().push(ops.len());
+//!         Ok(true)
+//!     }
+//! }
+//!
+//! let config = ConfigBuilder::default()
+//!     .cmd(vec!["true".into()])
+//!     .paths(vec![".".into()])
+//!     .build()
+//!     .expect("valid config");
+//! let handler = Recorder(config, RefCell::default());
+//!
+//! let mut watcher = MockWatcher::new();
+//! watcher.write("/a").write("/b").settle().remove("/c");
+//! watcher.run(&handler).expect("loop ran");
+//!
+//! assert_eq!(*handler.1.borrow(), vec![2, 1]);
+//! ```
+
+use std::{
+    collections::VecDeque,
+    path::Path,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::clock::Clock;
+use crate::debounce::{Debouncer, Source};
+use crate::error::Result;
+use crate::gitignore;
+use crate::ignore;
+use crate::notification_filter::NotificationFilter;
+use crate::run::{watch_loop, Handler, Interrupts};
+use crate::signal::Signal;
+use crate::watcher::{wake_event, Event, Injector};
+
+/// A fake watcher driven by a script of events.
+///
+/// Paths are used as given: they are not canonicalised, nor checked to exist.
+/// Filters and ignores from the handler's config apply, but ignore files are
+/// not loaded. The loop stops when the script is exhausted, or earlier if the
+/// handler asks for it.
+#[derive(Debug, Default)]
+pub struct MockWatcher {
+    script: VecDeque<Step>,
+}
+
+#[derive(Debug)]
+enum Step {
+    Event(Event),
+    Signal(Signal),
+    Settle,
+    Wait(Duration),
+}
+
+impl MockWatcher {
+    /// Create a mock watcher with an empty script.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a raw event to the script.
+    pub fn event(&mut self, event: Event) -> &mut Self {
+        self.script.push_back(Step::Event(event));
+        self
+    }
+
+    /// Add a creation event for a path to the script.
+    pub fn create(&mut self, path: impl AsRef<Path>) -> &mut Self {
+        self.op(path, notify::op::CREATE)
+    }
+
+    /// Add a write event for a path to the script.
+    pub fn write(&mut self, path: impl AsRef<Path>) -> &mut Self {
+        self.op(path, notify::op::WRITE)
+    }
+
+    /// Add a removal event for a path to the script.
+    pub fn remove(&mut self, path: impl AsRef<Path>) -> &mut Self {
+        self.op(path, notify::op::REMOVE)
+    }
+
+    /// Add a signal to the script.
+    ///
+    /// It is handled as by [`watch`][crate::run::watch], according to
+    /// [`Handler::on_signal`], ending the current batch.
+    pub fn signal(&mut self, sig: Signal) -> &mut Self {
+        self.script.push_back(Step::Signal(sig));
+        self
+    }
+
+    /// End the current batch, as if the debounce window had elapsed.
+    ///
+    /// The end of the script also ends the last batch.
+    pub fn settle(&mut self) -> &mut Self {
+        self.script.push_back(Step::Settle);
+        self
+    }
+
+    /// Let some time pass before the next step, using the config's clock.
+    ///
+    /// If this is longer than the debounce window, the current batch ends.
+    pub fn wait(&mut self, duration: Duration) -> &mut Self {
+        self.script.push_back(Step::Wait(duration));
+        self
+    }
+
+    fn op(&mut self, path: impl AsRef<Path>, op: notify::op::Op) -> &mut Self {
+        self.event(Event {
+            path: Some(path.as_ref().to_path_buf()),
+            op: Ok(op),
+            cookie: None,
+        })
+    }
+
+    /// Run the watch loop with this script, blocking until done.
+    ///
+    /// Events pushed through the [`Injector`] given to the handler are
+    /// delivered ahead of the rest of the script.
+    pub fn run<H>(self, handler: &H) -> Result<()>
+    where
+        H: Handler,
+    {
+        let args = handler.args();
+        let filter = NotificationFilter::new(
+            &args.filters,
+            &args.ignores,
+            gitignore::load(&[]),
+            ignore::load(&[]),
+        )?;
+
+        let (tx, injected) = channel();
+        let (requests_tx, requests) = channel();
+        let (signal_tx, signals) = channel();
+        let source = MockSource {
+            script: self.script,
+            clock: args.clock.clone(),
+            injected,
+            signals: signal_tx,
+        };
+
+        let debouncer = Debouncer::new(
+            source,
+            |path: &Path| filter.is_excluded(path),
+            args.debounce,
+        )
+        .no_meta(args.no_meta)
+        .max_rate(args.max_event_rate)
+        .progress(args.progress_interval)
+        .clock(args.clock.clone());
+
+        let interrupts = Interrupts {
+            signals: Some(signals),
+            requests: Some(requests),
+            ..Interrupts::default()
+        };
+
+        let injector = Injector::new(tx, requests_tx);
+        watch_loop(handler, &args, injector, interrupts, debouncer)
+    }
+}
+
+struct MockSource {
+    script: VecDeque<Step>,
+    clock: Arc<dyn Clock>,
+    injected: Receiver<Event>,
+    signals: Sender<Signal>,
+}
+
+impl MockSource {
+    /// Deliver a signal to the loop, and wake it up.
+    fn signal(&self, sig: Signal) -> Event {
+        self.signals.send(sig).ok();
+        wake_event()
+    }
+}
+
+impl Source for MockSource {
+    fn recv(&mut self) -> Option<Event> {
+        loop {
+            if let Ok(event) = self.injected.try_recv() {
+                return Some(event);
+            }
+
+            match self.script.pop_front()? {
+                Step::Event(event) => return Some(event),
+                Step::Signal(sig) => return Some(self.signal(sig)),
+                Step::Settle => continue,
+                Step::Wait(duration) => self.clock.sleep(duration),
+            }
+        }
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> Option<Event> {
+        let mut remaining = timeout;
+        loop {
+            if let Ok(event) = self.injected.try_recv() {
+                return Some(event);
+            }
+
+            match self.script.pop_front()? {
+                Step::Event(event) => return Some(event),
+                Step::Signal(sig) => return Some(self.signal(sig)),
+                Step::Settle => {
+                    self.clock.sleep(remaining);
+                    return None;
+                }
+                Step::Wait(duration) if duration <= remaining => {
+                    self.clock.sleep(duration);
+                    remaining -= duration;
+                }
+                Step::Wait(duration) => {
+                    self.clock.sleep(remaining);
+                    self.script.push_front(Step::Wait(duration - remaining));
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// A clock which only moves when told to.
+///
+/// Sleeping on this clock returns immediately, advancing it by the duration.
+/// Clones share the same time, so a test can keep one around to inspect or
+/// advance the clock given to the config.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    start: Instant,
+    offset: Arc<Mutex<Duration>>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockClock {
+    /// Create a mock clock, starting now.
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            offset: Arc::default(),
+        }
+    }
+
+    /// Move the clock forward.
+    pub fn advance(&self, duration: Duration) {
+        *self.offset.lock().expect("poisoned lock in MockClock") += duration;
+    }
+
+    /// Total time the clock has moved forward since creation.
+    pub fn elapsed(&self) -> Duration {
+        *self.offset.lock().expect("poisoned lock in MockClock")
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MockClock, MockWatcher};
+    use crate::config::{Config, ConfigBuilder};
+    use crate::error::Result;
+    use crate::events::{Event, Origin};
+    use crate::pathop::PathOp;
+    use crate::run::{Handler, SignalAction};
+    use crate::signal::Signal;
+    use crate::watcher::Injector;
+    use std::{
+        cell::{Cell, RefCell},
+        path::PathBuf,
+        time::Duration,
+    };
+
+    struct Recorder {
+        config: Config,
+        manual: Cell<usize>,
+        batches: RefCell<Vec<Vec<PathBuf>>>,
+        stop_after: usize,
+        inject: Option<PathBuf>,
+    }
+
+    impl Recorder {
+        fn new(config: Config) -> Self {
+            Self {
+                config,
+                manual: Cell::new(0),
+                batches: RefCell::default(),
+                stop_after: usize::MAX,
+                inject: None,
+            }
+        }
+    }
+
+    impl Handler for Recorder {
+        fn args(&self) -> Config {
+            self.config.clone()
+        }
+
+        fn on_start(&self, injector: Injector) {
+            if let Some(ref path) = self.inject {
+                injector.path(path).expect("loop is running");
+            }
+        }
+
+        fn on_manual(&self) -> Result<bool> {
+            self.manual.set(self.manual.get() + 1);
+            Ok(true)
+        }
+
+        fn on_update(&self, ops: &[PathOp]) -> Result<bool> {
+            let mut batches = self.batches.borrow_mut();
+            batches.push(ops.iter().map(|op| op.path.clone()).collect());
+            Ok(batches.len() < self.stop_after)
+        }
+    }
+
+    fn config() -> ConfigBuilder {
+        let mut builder = ConfigBuilder::default();
+        builder.cmd(vec!["true".into()]).paths(vec![".".into()]);
+        builder
+    }
+
+    #[test]
+    fn trigger_file_runs_on_its_own() {
+        let handler = Recorder::new(
+            config()
+                .ignores(vec!["**/.trigger".into()])
+                .trigger_file("/proj/.trigger")
+                .build()
+                .expect("valid config"),
+        );
+
+        let mut watcher = MockWatcher::new();
+        watcher
+            .write("/proj/a")
+            .write("/proj/.trigger")
+            .write("/proj/b")
+            .settle()
+            .write("/proj/.trigger");
+        watcher.run(&handler).expect("loop ran");
+
+        assert_eq!(handler.manual.get(), 3);
+        assert_eq!(
+            *handler.batches.borrow(),
+            vec![
+                vec![PathBuf::from("/proj/a")],
+                vec![PathBuf::from("/proj/b")]
+            ]
+        );
+    }
+
+    #[test]
+    fn trigger_file_runs_once_per_touch() {
+        let handler = Recorder::new(
+            config()
+                .clock(MockClock::new())
+                .trigger_file("/proj/.trigger")
+                .build()
+                .expect("valid config"),
+        );
+
+        let mut watcher = MockWatcher::new();
+        watcher
+            .create("/proj/.trigger")
+            .write("/proj/.trigger")
+            .write("/proj/.trigger")
+            .wait(Duration::from_secs(1))
+            .write("/proj/.trigger");
+        watcher.run(&handler).expect("loop ran");
+
+        assert_eq!(handler.manual.get(), 3);
+        assert!(handler.batches.borrow().is_empty());
+    }
+
+    #[test]
+    fn runs_for_shed_changes_alone() {
+        let handler = Recorder::new(
+            config()
+                .clock(MockClock::new())
+                .trigger_file("/proj/.trigger")
+                .max_event_rate(1_u32)
+                .build()
+                .expect("valid config"),
+        );
+
+        // Only the second touch of the trigger file is kept, the rest is shed
+        let mut watcher = MockWatcher::new();
+        watcher
+            .write("/proj/.trigger")
+            .write("/proj/.trigger")
+            .write("/proj/a")
+            .write("/proj/b");
+        watcher.run(&handler).expect("loop ran");
+
+        // The initial run, the trigger file, and the shed changes
+        assert_eq!(handler.manual.get(), 3);
+        assert!(handler.batches.borrow().is_empty());
+    }
+
+    #[test]
+    fn delivers_scripted_batches() {
+        let handler = Recorder::new(config().build().expect("valid config"));
+
+        let mut watcher = MockWatcher::new();
+        watcher.write("/b").write("/a").settle().create("/c");
+        watcher.run(&handler).expect("loop ran");
+
+        assert_eq!(handler.manual.get(), 1);
+        assert_eq!(
+            *handler.batches.borrow(),
+            vec![
+                vec![PathBuf::from("/a"), PathBuf::from("/b")],
+                vec![PathBuf::from("/c")],
+            ]
+        );
+    }
+
+    #[test]
+    fn applies_config_filters() {
+        let handler = Recorder::new(
+            config()
+                .ignores(vec!["*.log".into()])
+                .run_initially(false)
+                .build()
+                .expect("valid config"),
+        );
+
+        let mut watcher = MockWatcher::new();
+        watcher
+            .write("/debug.log")
+            .settle()
+            .write("/main.rs")
+            .write("/trace.log");
+        watcher.run(&handler).expect("loop ran");
+
+        assert_eq!(handler.manual.get(), 0);
+        assert_eq!(
+            *handler.batches.borrow(),
+            vec![vec![PathBuf::from("/main.rs")]]
+        );
+    }
+
+    #[test]
+    fn stops_when_handler_asks() {
+        let mut handler = Recorder::new(config().build().expect("valid config"));
+        handler.stop_after = 1;
+
+        let mut watcher = MockWatcher::new();
+        watcher.write("/a").settle().write("/b");
+        watcher.run(&handler).expect("loop ran");
+
+        assert_eq!(handler.batches.borrow().len(), 1);
+    }
+
+    #[test]
+    fn delivers_injected_events() {
+        let mut handler = Recorder::new(config().build().expect("valid config"));
+        handler.inject = Some(PathBuf::from("/config.toml"));
+
+        let mut watcher = MockWatcher::new();
+        watcher.settle().write("/a");
+        watcher.run(&handler).expect("loop ran");
+
+        assert_eq!(
+            *handler.batches.borrow(),
+            vec![
+                vec![PathBuf::from("/config.toml")],
+                vec![PathBuf::from("/a")]
+            ]
+        );
+    }
+
+    #[test]
+    fn waits_split_batches_in_virtual_time() {
+        let clock = MockClock::new();
+        let handler = Recorder::new(
+            config()
+                .debounce(Duration::from_millis(100))
+                .clock(clock.clone())
+                .build()
+                .expect("valid config"),
+        );
+
+        let mut watcher = MockWatcher::new();
+        watcher
+            .write("/a")
+            .wait(Duration::from_millis(50))
+            .write("/b")
+            .wait(Duration::from_secs(60))
+            .write("/c")
+            .settle();
+        watcher.run(&handler).expect("loop ran");
+
+        assert_eq!(
+            *handler.batches.borrow(),
+            vec![
+                vec![PathBuf::from("/a"), PathBuf::from("/b")],
+                vec![PathBuf::from("/c")],
+            ]
+        );
+        assert_eq!(clock.elapsed(), Duration::from_millis(60_150));
+    }
+
+    #[test]
+    fn resuming_does_not_stop() {
+        let handler = Recorder::new(config().run_initially(false).build().expect("valid config"));
+
+        let mut watcher = MockWatcher::new();
+        watcher
+            .signal(Signal::SIGCONT)
+            .write("/a")
+            .settle()
+            .signal(Signal::SIGTERM)
+            .write("/b");
+        watcher.run(&handler).expect("loop ran");
+
+        assert_eq!(*handler.batches.borrow(), vec![vec![PathBuf::from("/a")]]);
+    }
+
+    #[test]
+    fn signals_follow_on_signal() {
+        struct OnSignal(Config, RefCell<Vec<String>>);
+
+        impl Handler for OnSignal {
+            fn args(&self) -> Config {
+                self.0.clone()
+            }
+
+            fn on_signal(&self, sig: Signal) -> SignalAction {
+                match sig {
+                    Signal::SIGUSR1 => SignalAction::Run,
+                    Signal::SIGUSR2 => SignalAction::Ignore,
+                    Signal::SIGHUP => SignalAction::Forward,
+                    _ => SignalAction::Shutdown,
+                }
+            }
+
+            fn on_event(&self, events: &[Event]) -> Result<bool> {
+                let mut seen = self.1.borrow_mut();
+                for event in events {
+                    seen.push(match event {
+                        Event::Manual => "manual".into(),
+                        Event::FsChange(op) => op.path.display().to_string(),
+                        Event::Signal(sig) => sig.to_string(),
+                        Event::Source(_) | Event::Received(_) => continue,
+                        other => format!("{:?}", other),
+                    });
+                }
+
+                Ok(true)
+            }
+        }
+
+        let handler = OnSignal(
+            config().run_initially(false).build().expect("valid config"),
+            RefCell::default(),
+        );
+
+        let mut watcher = MockWatcher::new();
+        watcher
+            .signal(Signal::SIGUSR1)
+            .signal(Signal::SIGUSR2)
+            .write("/a")
+            .settle()
+            .signal(Signal::SIGHUP)
+            .signal(Signal::SIGTERM)
+            .write("/b");
+        watcher.run(&handler).expect("loop ran");
+
+        assert_eq!(
+            *handler.1.borrow(),
+            vec!["manual", "/a", "SIGHUP", "SIGTERM"]
+        );
+    }
+
+    #[test]
+    fn stops_at_max_runtime() {
+        let clock = MockClock::new();
+        let handler = Recorder::new(
+            config()
+                .max_runtime(Duration::from_secs(10))
+                .clock(clock.clone())
+                .build()
+                .expect("valid config"),
+        );
+
+        let mut watcher = MockWatcher::new();
+        watcher
+            .write("/a")
+            .settle()
+            .wait(Duration::from_secs(11))
+            .write("/b")
+            .settle()
+            .write("/c");
+        watcher.run(&handler).expect("loop ran");
+
+        assert_eq!(*handler.batches.borrow(), vec![vec![PathBuf::from("/a")]]);
+    }
+
+    #[test]
+    fn on_event_sees_why() {
+        struct Why(Config, RefCell<Vec<String>>);
+
+        impl Handler for Why {
+            fn args(&self) -> Config {
+                self.0.clone()
+            }
+
+            fn on_event(&self, events: &[Event]) -> Result<bool> {
+                let mut seen = self.1.borrow_mut();
+                for event in events {
+                    seen.push(match event {
+                        Event::Manual => "manual".into(),
+                        Event::FsChange(op) => op.path.display().to_string(),
+                        Event::Received(times) => format!("received {}", times.len()),
+                        other => format!("{:?}", other),
+                    });
+                }
+
+                Ok(true)
+            }
+        }
+
+        let handler = Why(config().build().expect("valid config"), RefCell::default());
+
+        let mut watcher = MockWatcher::new();
+        watcher.write("/a").write("/b");
+        watcher.run(&handler).expect("loop ran");
+
+        assert_eq!(
+            *handler.1.borrow(),
+            vec![
+                "Source(Start)",
+                "manual",
+                "Source(Filesystem)",
+                "/a",
+                "/b",
+                "received 2"
+            ]
+        );
+    }
+
+    #[test]
+    fn other_sources_are_tagged() {
+        struct Editor(Config, RefCell<Vec<String>>);
+
+        impl Handler for Editor {
+            fn args(&self) -> Config {
+                self.0.clone()
+            }
+
+            fn on_start(&self, injector: Injector) {
+                injector
+                    .run(Origin::Custom("editor".into()))
+                    .expect("loop is running");
+            }
+
+            fn on_event(&self, events: &[Event]) -> Result<bool> {
+                let mut seen = self.1.borrow_mut();
+                for event in events {
+                    seen.push(match event {
+                        Event::FsChange(op) => op.path.display().to_string(),
+                        Event::Received(_) => continue,
+                        other => format!("{:?}", other),
+                    });
+                }
+
+                Ok(true)
+            }
+        }
+
+        let handler = Editor(
+            config().run_initially(false).build().expect("valid config"),
+            RefCell::default(),
+        );
+
+        let mut watcher = MockWatcher::new();
+        watcher.write("/a");
+        watcher.run(&handler).expect("loop ran");
+
+        assert_eq!(
+            *handler.1.borrow(),
+            vec![
+                "Source(Custom(\"editor\"))",
+                "Manual",
+                "Source(Filesystem)",
+                "/a"
+            ]
+        );
+    }
+}
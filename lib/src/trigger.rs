@@ -0,0 +1,242 @@
+//! Triggering runs over the network.
+//!
+//! When [`Config.trigger_listener`][crate::config::Config] is set, watchexec
+//! listens on that address for line-based messages, from e.g. build farms or
+//! editor plugins:
+//!
+//! - `trigger` requests a run, as if a file had changed.
+//! - `trigger <path>` reports a change to that path, with the rest of the line
+//!   as the path (relative paths are resolved from the working directory). It
+//!   then goes through filtering and debouncing like any other change. Only
+//!   paths under the watched paths are accepted, and they can't use `..`.
+//!
+//! Over TCP, each line is answered with `ok`, or `error: ` and a reason. Over
+//! UDP, a datagram may hold several lines, and there's no answer.
+//!
+//! There's no authentication: anyone who can reach the address can run the
+//! command, so it should only be bound to a trusted one, like localhost.
+
+use std::{
+    env,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream, UdpSocket},
+    path::{Component, Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::events::{canonical_paths, Origin};
+use crate::watcher::Injector;
+
+/// How many receive errors in a row the UDP listener puts up with, waiting a
+/// little longer after each, before it gives up.
+const MAX_RECV_ERRORS: u32 = 10;
+
+/// How long the UDP listener waits after its first receive error.
+const RECV_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Where to listen for triggers.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TriggerListener {
+    /// accept TCP connections on that address
+    Tcp(String),
+
+    /// receive UDP datagrams on that address
+    Udp(String),
+}
+
+/// Bind the listener, then handle messages from a thread.
+///
+/// Paths in messages are only accepted under the paths of the config, either
+/// as given or canonicalized.
+pub(crate) fn start(listener: &TriggerListener, args: &Config, injector: Injector) -> Result<()> {
+    let cwd = env::current_dir()?;
+    let mut roots = canonical_paths(args)?;
+    roots.extend(args.paths.iter().map(|path| cwd.join(path)));
+
+    match listener {
+        TriggerListener::Tcp(addr) => {
+            let listener = TcpListener::bind(addr)?;
+            info!("Listening for triggers on tcp://{}", listener.local_addr()?);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            let roots = roots.clone();
+                            let injector = injector.clone();
+                            thread::spawn(move || serve_tcp(stream, &roots, &injector));
+                        }
+                        Err(err) => warn!("Could not accept trigger connection: {}", err),
+                    }
+                }
+            });
+        }
+        TriggerListener::Udp(addr) => {
+            let socket = UdpSocket::bind(addr)?;
+            info!("Listening for triggers on udp://{}", socket.local_addr()?);
+            thread::spawn(move || {
+                let mut buf = [0; 65536];
+                let mut errors = 0;
+                loop {
+                    let len = match socket.recv(&mut buf) {
+                        Ok(len) => len,
+                        Err(err) => {
+                            errors += 1;
+                            if errors == MAX_RECV_ERRORS {
+                                return warn!("Could not receive triggers, giving up: {}", err);
+                            }
+
+                            warn!("Could not receive trigger: {}", err);
+                            thread::sleep(RECV_BACKOFF * errors);
+                            continue;
+                        }
+                    };
+
+                    errors = 0;
+                    for line in String::from_utf8_lossy(&buf[..len]).lines() {
+                        handle(line, &roots, &injector)
+                            .unwrap_or_else(|err| warn!("Bad trigger: {}", err));
+                    }
+                }
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn serve_tcp(stream: TcpStream, roots: &[PathBuf], injector: &Injector) {
+    let mut reply = match stream.try_clone() {
+        Ok(reply) => reply,
+        Err(err) => return warn!("Could not handle trigger connection: {}", err),
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let answer = match line
+            .map_err(Error::from)
+            .and_then(|line| handle(&line, roots, injector))
+        {
+            Ok(()) => "ok".to_string(),
+            Err(Error::Generic(reason)) => format!("error: {}", reason),
+            Err(err) => format!("error: {}", err),
+        };
+
+        if writeln!(reply, "{}", answer).is_err() {
+            return;
+        }
+    }
+}
+
+/// Act on one message, with paths under `roots` only.
+fn handle(line: &str, roots: &[PathBuf], injector: &Injector) -> Result<()> {
+    let line = line.trim_end_matches('\r');
+    debug!("Trigger message: {:?}", line);
+
+    if line == "trigger" {
+        injector.run(Origin::Trigger)
+    } else if line.starts_with("trigger ") && line.len() > 8 {
+        let path = Path::new(&line[8..]);
+        if path.components().any(|c| c == Component::ParentDir) {
+            return Err(Error::Generic(format!("path {:?} goes up with ..", path)));
+        }
+
+        let path = env::current_dir()?.join(path);
+        if !roots.iter().any(|root| path.starts_with(root)) {
+            return Err(Error::Generic(format!("path {:?} is not watched", path)));
+        }
+
+        injector.path(&path)
+    } else {
+        Err(Error::Generic(format!("unknown message {:?}", line)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{handle, start, TriggerListener};
+    use crate::config::ConfigBuilder;
+    use crate::events::{Event, Origin};
+    use crate::watcher::Injector;
+    use std::{
+        env,
+        io::{BufRead, BufReader, Write},
+        net::{TcpListener, TcpStream},
+        sync::mpsc::channel,
+    };
+
+    #[test]
+    fn handles_messages() {
+        let (tx, rx) = channel();
+        let (requests_tx, requests) = channel();
+        let injector = Injector::new(tx, requests_tx);
+        let cwd = env::current_dir().expect("cwd");
+        let roots = [cwd.join("src")];
+
+        handle("trigger\r", &roots, &injector).expect("trigger");
+        let (origin, event) = requests.try_recv().expect("run requested");
+        assert_eq!(origin, Origin::Trigger);
+        assert!(matches!(event, Event::Manual));
+        assert_eq!(rx.try_recv().expect("woken").path, None);
+
+        handle("trigger src/my file.rs", &roots, &injector).expect("trigger path");
+        assert!(requests.try_recv().is_err());
+        assert_eq!(
+            rx.try_recv().expect("path").path,
+            Some(cwd.join("src/my file.rs"))
+        );
+
+        assert!(handle("triggered", &roots, &injector).is_err());
+        assert!(handle("trigger ", &roots, &injector).is_err());
+        assert!(handle("", &roots, &injector).is_err());
+    }
+
+    #[test]
+    fn only_takes_watched_paths() {
+        let (tx, rx) = channel();
+        let (requests_tx, _requests) = channel();
+        let injector = Injector::new(tx, requests_tx);
+        let roots = [env::current_dir().expect("cwd").join("src")];
+
+        assert!(handle("trigger tests/a.rs", &roots, &injector).is_err());
+        assert!(handle("trigger src/../../etc/passwd", &roots, &injector).is_err());
+        assert!(handle("trigger /etc/passwd", &roots, &injector).is_err());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn answers_over_tcp() {
+        // Find a free port
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .expect("free port")
+            .to_string();
+
+        let config = ConfigBuilder::default()
+            .cmd(vec!["true".into()])
+            .paths(vec![".".into()])
+            .build()
+            .expect("valid config");
+
+        let (tx, _rx) = channel();
+        let (requests_tx, _requests) = channel();
+        start(
+            &TriggerListener::Tcp(addr.clone()),
+            &config,
+            Injector::new(tx, requests_tx),
+        )
+        .expect("listening");
+
+        let mut stream = TcpStream::connect(addr).expect("connect");
+        stream.write_all(b"trigger\nnope\n").expect("send");
+        let mut answers = BufReader::new(stream).lines();
+        assert_eq!(answers.next().expect("answer").expect("line"), "ok");
+        assert_eq!(
+            answers.next().expect("answer").expect("line"),
+            "error: unknown message \"nope\""
+        );
+    }
+}
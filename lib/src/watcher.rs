@@ -1,10 +1,13 @@
 use log::debug;
 use notify::{raw_watcher, PollWatcher, RecommendedWatcher, RecursiveMode};
 use std::convert::TryFrom;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::Sender;
 use std::time::Duration;
 
+use crate::events::{Event as HandlerEvent, Origin};
+use crate::pathop::PathOp;
+
 /// Thin wrapper over the notify crate
 ///
 /// `PollWatcher` and `RecommendedWatcher` are distinct types, but watchexec
@@ -60,3 +63,126 @@ impl Watcher {
         matches!(self.watcher_impl, WatcherImpl::Poll(_))
     }
 }
+
+/// Handle to push synthetic events into the watch pipeline.
+///
+/// Injected events go through the same filtering, debouncing, and handling as
+/// events coming from the filesystem. This lets a host application act as if a
+/// file changed when it knows of a change the watcher cannot see, e.g. when
+/// configuration is stored in a database.
+///
+/// It can also deliver events straight to the handler, tagged with where they
+/// come from, so that other sources can be combined with the watcher.
+///
+/// Obtained through [`Handler::on_start`][crate::run::Handler::on_start].
+#[derive(Clone, Debug)]
+pub struct Injector {
+    tx: Sender<Event>,
+    requests: Sender<(Origin, HandlerEvent)>,
+}
+
+impl Injector {
+    pub(crate) fn new(tx: Sender<Event>, requests: Sender<(Origin, HandlerEvent)>) -> Self {
+        Self { tx, requests }
+    }
+
+    /// Inject a raw event.
+    ///
+    /// Returns an error if the watch loop has stopped.
+    pub fn send(&self, event: Event) -> crate::error::Result<()> {
+        self.tx
+            .send(event)
+            .map_err(|_| crate::error::Error::Generic("watch loop has stopped".into()))
+    }
+
+    /// Inject an event for a `PathOp`.
+    pub fn pathop(&self, pathop: PathOp) -> crate::error::Result<()> {
+        self.send(Event {
+            path: Some(pathop.path),
+            op: pathop
+                .op
+                .ok_or_else(|| Error::Generic("injected event without op".into())),
+            cookie: pathop.cookie,
+        })
+    }
+
+    /// Inject a write event for a path, as if it had been modified.
+    pub fn path(&self, path: &Path) -> crate::error::Result<()> {
+        self.pathop(PathOp::new(path, Some(notify::op::WRITE), None))
+    }
+
+    /// Deliver an event to the handler, bypassing filtering and debouncing.
+    ///
+    /// The handler gets it on its own, after a `Source(origin)`. Any batch in
+    /// progress ends first. `Manual` and `Tick` events from the same origin
+    /// which pile up before the loop gets to them are only delivered once.
+    pub fn deliver(&self, origin: Origin, event: HandlerEvent) -> crate::error::Result<()> {
+        self.requests
+            .send((origin, event))
+            .map_err(|_| crate::error::Error::Generic("watch loop has stopped".into()))?;
+        self.wake()
+    }
+
+    /// Request a run, delivered as a `Manual` event.
+    pub fn run(&self, origin: Origin) -> crate::error::Result<()> {
+        self.deliver(origin, HandlerEvent::Manual)
+    }
+
+    /// Inject an event without a path, which is filtered out but still wakes
+    /// the watch loop up.
+    pub(crate) fn wake(&self) -> crate::error::Result<()> {
+        self.send(wake_event())
+    }
+}
+
+pub(crate) fn wake_event() -> Event {
+    Event {
+        path: None,
+        op: Ok(notify::op::Op::empty()),
+        cookie: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Injector;
+    use crate::events::{Event, Origin};
+    use std::{path::Path, sync::mpsc::channel};
+
+    #[test]
+    fn injected_path_is_a_write() {
+        let (tx, rx) = channel();
+        let (requests, _) = channel();
+        Injector::new(tx, requests)
+            .path(Path::new("config.toml"))
+            .expect("receiver is alive");
+
+        let event = rx.recv().expect("event was sent");
+        assert_eq!(event.path.as_deref(), Some(Path::new("config.toml")));
+        assert_eq!(event.op.ok(), Some(notify::op::WRITE));
+    }
+
+    #[test]
+    fn inject_after_stop_errors() {
+        let (tx, rx) = channel();
+        let (requests, _) = channel();
+        drop(rx);
+        assert!(Injector::new(tx, requests)
+            .path(Path::new("config.toml"))
+            .is_err());
+    }
+
+    #[test]
+    fn delivered_events_wake_up() {
+        let (tx, rx) = channel();
+        let (requests_tx, requests) = channel();
+        Injector::new(tx, requests_tx)
+            .run(Origin::Custom("ci".into()))
+            .expect("receiver is alive");
+
+        let (origin, event) = requests.try_recv().expect("event was delivered");
+        assert_eq!(origin, Origin::Custom("ci".into()));
+        assert!(matches!(event, Event::Manual));
+        assert_eq!(rx.try_recv().expect("woken up").path, None);
+    }
+}
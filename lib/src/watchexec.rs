@@ -0,0 +1,282 @@
+use std::{path::PathBuf, thread, time::Duration};
+
+use crate::config::{Config, ConfigBuilder};
+use crate::error::{Error, Result};
+use crate::events::Event;
+use crate::pathop::PathOp;
+use crate::run::{watch, ExecHandler, Handler, OnBusyUpdate};
+use crate::watcher::Injector;
+use crate::Shell;
+
+type UpdateFn = Box<dyn Fn(&[PathOp]) -> Result<bool> + Send>;
+
+/// High-level entry point to watchexec.
+///
+/// This owns the configuration and wires up the watcher, the filter, and the
+/// handler, for embedders who don't need to implement [`Handler`] themselves.
+/// If a command is given, it is run as with the CLI; if an update callback is
+/// given, it is called with every batch of changes (after the command is
+/// started, if there is one). At least one of the two is needed.
+///
+/// Anything not covered by the typed setters here can be set on the
+/// underlying [`ConfigBuilder`] with [`config`][Watchexec::config].
+///
+/// # Examples
+///
+/// ```no_run
+/// # use watchexec::Watchexec;
+/// Watchexec::new()
+///     .paths(vec!["src"])
+///     .on_update(|batch| {
+///         println!("{} paths changed", batch.len());
+///         Ok(true)
+///     })
+///     .run()
+///     .expect("watch failed");
+/// ```
+pub struct Watchexec {
+    config: ConfigBuilder,
+    on_update: Option<UpdateFn>,
+}
+
+impl Default for Watchexec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Watchexec {
+    /// Start with the default configuration.
+    pub fn new() -> Self {
+        Self {
+            config: ConfigBuilder::default(),
+            on_update: None,
+        }
+    }
+
+    /// Set the paths to watch.
+    pub fn paths<P>(mut self, paths: impl IntoIterator<Item = P>) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.config
+            .paths(paths.into_iter().map(Into::into).collect::<Vec<_>>());
+        self
+    }
+
+    /// Set the command to run on changes.
+    ///
+    /// See [`Config.cmd`][crate::config::Config] for how this is interpreted.
+    pub fn command<S>(mut self, cmd: impl IntoIterator<Item = S>) -> Self
+    where
+        S: Into<String>,
+    {
+        self.config
+            .cmd(cmd.into_iter().map(Into::into).collect::<Vec<_>>());
+        self
+    }
+
+    /// Set the shell to run the command with.
+    pub fn shell(mut self, shell: Shell) -> Self {
+        self.config.shell(shell);
+        self
+    }
+
+    /// Only trigger on changes matching these globs.
+    pub fn filters<S>(mut self, filters: impl IntoIterator<Item = S>) -> Self
+    where
+        S: Into<String>,
+    {
+        self.config
+            .filters(filters.into_iter().map(Into::into).collect::<Vec<_>>());
+        self
+    }
+
+    /// Do not trigger on changes matching these globs.
+    pub fn ignores<S>(mut self, ignores: impl IntoIterator<Item = S>) -> Self
+    where
+        S: Into<String>,
+    {
+        self.config
+            .ignores(ignores.into_iter().map(Into::into).collect::<Vec<_>>());
+        self
+    }
+
+    /// Set the debounce interval.
+    pub fn debounce(mut self, debounce: Duration) -> Self {
+        self.config.debounce(debounce);
+        self
+    }
+
+    /// Set what to do with the command on changes while it's running.
+    pub fn on_busy_update(mut self, on_busy_update: OnBusyUpdate) -> Self {
+        self.config.on_busy_update(on_busy_update);
+        self
+    }
+
+    /// Modify the underlying config builder directly.
+    pub fn config<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&mut ConfigBuilder),
+    {
+        f(&mut self.config);
+        self
+    }
+
+    /// Call a function with every batch of changes.
+    ///
+    /// The return value has the same meaning as for [`Handler::on_update`].
+    pub fn on_update<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&[PathOp]) -> Result<bool> + Send + 'static,
+    {
+        self.on_update = Some(Box::new(f));
+        self
+    }
+
+    /// Start watching, blocking until done.
+    pub fn run(self) -> Result<()> {
+        let mut builder = self.config;
+
+        // The callback can stand in for the command
+        if self.on_update.is_some() {
+            builder.no_command(true);
+        }
+
+        let config = builder
+            .build()
+            .map_err(|err| Error::Generic(err.to_string()))?;
+
+        let exec =
+            if config.cmd.is_empty() && config.container.is_none() && config.commands.is_empty() {
+                None
+            } else {
+                Some(ExecHandler::new(config.clone())?)
+            };
+
+        watch(&FacadeHandler {
+            config,
+            exec,
+            on_update: self.on_update,
+        })
+    }
+
+    /// Start watching in a new thread.
+    pub fn spawn(self) -> thread::JoinHandle<Result<()>> {
+        thread::spawn(move || self.run())
+    }
+}
+
+struct FacadeHandler {
+    config: Config,
+    exec: Option<ExecHandler>,
+    on_update: Option<UpdateFn>,
+}
+
+impl Handler for FacadeHandler {
+    fn args(&self) -> Config {
+        self.config.clone()
+    }
+
+    fn on_start(&self, injector: Injector) {
+        if let Some(ref exec) = self.exec {
+            exec.on_start(injector);
+        }
+    }
+
+    fn on_stop(&self) {
+        if let Some(ref exec) = self.exec {
+            exec.on_stop();
+        }
+    }
+
+    fn on_event(&self, events: &[Event]) -> Result<bool> {
+        let mut keep_going = match self.exec {
+            Some(ref exec) => exec.on_event(events)?,
+            None => true,
+        };
+
+        if let Some(ref f) = self.on_update {
+            let ops: Vec<PathOp> = events.iter().filter_map(Event::pathop).cloned().collect();
+            if !ops.is_empty() {
+                keep_going &= f(&ops)?;
+            }
+        }
+
+        Ok(keep_going)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Watchexec;
+    use std::{env, fs, process, sync::mpsc::channel, time::Duration};
+
+    #[test]
+    fn needs_command_or_callback() {
+        assert!(Watchexec::new().paths(vec!["."]).run().is_err());
+    }
+
+    #[test]
+    fn reports_config_errors_with_callback() {
+        let err = Watchexec::new()
+            .paths(vec!["."])
+            .config(|config| {
+                config.max_runs(0_u64);
+            })
+            .on_update(|_| Ok(false))
+            .run()
+            .expect_err("invalid config");
+        assert!(err.to_string().contains("max_runs"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runs_command() {
+        let dir = env::temp_dir().join(format!("watchexec-facade-cmd-{}", process::id()));
+        fs::create_dir_all(&dir).expect("create dir");
+        let out = dir.join("ran");
+
+        Watchexec::new()
+            .paths(vec![&dir])
+            .command(vec![format!("echo ran > {}", out.display())])
+            .config(|config| {
+                config.max_runs(1_u64);
+            })
+            .run()
+            .expect("watch");
+
+        let ran = fs::read_to_string(&out).expect("command ran");
+        fs::remove_dir_all(&dir).ok();
+        assert_eq!(ran.trim(), "ran");
+    }
+
+    #[test]
+    fn calls_on_update() {
+        let dir = env::temp_dir().join(format!("watchexec-facade-update-{}", process::id()));
+        fs::create_dir_all(&dir).expect("create dir");
+        let file = dir.join("changed");
+
+        let (tx, rx) = channel();
+        let session = Watchexec::new()
+            .paths(vec![&dir])
+            .debounce(Duration::from_millis(50))
+            .on_update(move |batch| {
+                tx.send(batch.to_vec()).ok();
+                Ok(false)
+            })
+            .spawn();
+
+        // Keep changing the file until the watcher is up and sees it
+        let batch = (0..50)
+            .find_map(|i| {
+                fs::write(&file, i.to_string()).expect("write file");
+                rx.recv_timeout(Duration::from_millis(100)).ok()
+            })
+            .expect("update callback called");
+        session.join().expect("session thread").expect("session");
+        fs::remove_dir_all(&dir).ok();
+
+        assert!(batch.iter().any(|op| op.path.ends_with("changed")));
+    }
+}
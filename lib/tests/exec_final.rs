@@ -0,0 +1,55 @@
+#![cfg(unix)]
+
+use std::{env, process::Command};
+
+use watchexec::{config::ConfigBuilder, run};
+
+/// Set to run [`helper`] for real, in a process of its own.
+const HELPER: &str = "WATCHEXEC_TEST_EXEC_FINAL";
+
+/// Print this process's id, then become a shell which prints its own and
+/// exits with 3.
+///
+/// The test harness may have started a line already.
+#[test]
+fn helper() {
+    if env::var_os(HELPER).is_none() {
+        return;
+    }
+
+    println!("\nwatchexec {}", std::process::id());
+    let config = ConfigBuilder::default()
+        .paths(vec![env::current_dir().expect("current dir")])
+        .cmd(vec!["echo command $$; exit 3".into()])
+        .max_runs(1_u64)
+        .exec_final(true)
+        .build()
+        .expect("valid config");
+
+    run(config).expect("exec failed");
+    unreachable!("watchexec returned instead of replacing itself");
+}
+
+#[test]
+fn final_run_replaces_watchexec() {
+    let output = Command::new(env::current_exe().expect("test binary"))
+        .arg("--exact")
+        .arg("helper")
+        .arg("--nocapture")
+        .arg("--test-threads=1")
+        .env(HELPER, "1")
+        .output()
+        .expect("run helper");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let pid = |prefix: &str| {
+        stdout
+            .lines()
+            .find(|line| line.starts_with(prefix))
+            .map(|line| line[prefix.len()..].to_owned())
+            .unwrap_or_else(|| panic!("no {:?} in output: {:?}", prefix, stdout))
+    };
+
+    assert_eq!(pid("watchexec "), pid("command "));
+    assert_eq!(output.status.code(), Some(3));
+}